@@ -0,0 +1,334 @@
+use std::{collections::HashMap, fmt, hash::{Hash, Hasher}, marker::PhantomData, path::{Path, PathBuf}, sync::{mpsc, Arc, Mutex}};
+
+use anyhow::Result;
+
+use crate::{model::{ImageData, Material, MaterialData, MaterialFactors, MaterialImages, Model, ModelData, SamplerSettings}, texture::Texture};
+
+// Size of the checkerboard shown on models that are still loading
+const CHECKERBOARD_SIZE: u32 = 64;
+const CHECKERBOARD_SQUARES: u32 = 8;
+// Threads decoding models in the background, loads past this many wait in a queue
+const LOAD_WORKERS: usize = 4;
+
+// Refers to an asset owned by the AssetServer. Cheap to copy, so ECS components store these instead of the assets.
+pub struct Handle<T> {
+    index: usize,
+    _marker: PhantomData<fn() -> T>,
+}
+
+impl<T> Handle<T> {
+    const fn new(index: usize) -> Self {
+        Self { index, _marker: PhantomData }
+    }
+}
+
+// Implemented by hand, deriving would require T to implement the traits as well
+impl<T> Clone for Handle<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> Copy for Handle<T> {}
+
+impl<T> PartialEq for Handle<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.index == other.index
+    }
+}
+
+impl<T> Eq for Handle<T> {}
+
+impl<T> Hash for Handle<T> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.index.hash(state);
+    }
+}
+
+impl<T> fmt::Debug for Handle<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Handle<{}>({})", std::any::type_name::<T>(), self.index)
+    }
+}
+
+// 1x1 textures standing in for the ones a material doesn't have, shared by every material
+pub struct DefaultTextures {
+    pub white: Arc<Texture>,
+    pub white_linear: Arc<Texture>,
+    // Points straight out of the surface
+    pub normal: Arc<Texture>,
+}
+
+impl DefaultTextures {
+    fn new(device: &wgpu::Device, queue: &wgpu::Queue) -> Self {
+        Self {
+            white: Arc::new(Texture::from_color(device, queue, [255, 255, 255, 255], "default_white", false)),
+            white_linear: Arc::new(Texture::from_color(device, queue, [255, 255, 255, 255], "default_white_linear", true)),
+            normal: Arc::new(Texture::from_color(device, queue, [128, 128, 255, 255], "default_normal", true)),
+        }
+    }
+}
+
+// A model waiting for a worker thread to decode it
+type LoadRequest = (Handle<Model>, PathBuf);
+// A model decoded on a worker thread, waiting to be uploaded on the main thread
+type LoadedModel = (Handle<Model>, PathBuf, Result<ModelData>);
+
+enum ModelSlot {
+    Loading,
+    // The decoded model is kept to upload it again on a new device
+    Loaded(Model, ModelData),
+    Failed(anyhow::Error),
+}
+
+// The same image can be loaded both as color and as linear data, and with the sampler settings of different glTF files
+type TextureKey = (PathBuf, bool, Option<SamplerSettings>);
+
+// A texture and the decoded image it was uploaded from, kept to upload it again on a new device
+struct TextureSlot {
+    texture: Arc<Texture>,
+    image: Arc<ImageData>,
+}
+
+// How far a model handed out by the AssetServer got
+#[derive(Debug)]
+pub enum LoadState<'a> {
+    // Still decoding or waiting to be uploaded, the placeholder is drawn in its place
+    Loading,
+    Loaded,
+    // The placeholder is drawn in its place for good
+    Failed(&'a anyhow::Error),
+}
+
+// Loads models and textures once per path and hands out handles to them
+pub struct AssetServer {
+    material_bind_group_layout: wgpu::BindGroupLayout,
+    default_textures: DefaultTextures,
+    models: Vec<ModelSlot>,
+    // Drawn in place of models that haven't finished loading or failed to
+    placeholder_model: Model,
+    // Shared by the worker threads, which stop once the server and with it this sender are dropped
+    request_sender: mpsc::Sender<LoadRequest>,
+    loaded_receiver: mpsc::Receiver<LoadedModel>,
+    pending_loads: usize,
+    model_paths: HashMap<PathBuf, Handle<Model>>,
+    textures: Vec<TextureSlot>,
+    texture_paths: HashMap<TextureKey, Handle<Texture>>,
+}
+
+impl AssetServer {
+    pub fn new(device: &wgpu::Device, queue: &wgpu::Queue) -> Self {
+        let (request_sender, request_receiver) = mpsc::channel::<LoadRequest>();
+        let (loaded_sender, loaded_receiver) = mpsc::channel();
+        let request_receiver = Arc::new(Mutex::new(request_receiver));
+        for _ in 0..LOAD_WORKERS {
+            let request_receiver = request_receiver.clone();
+            let loaded_sender = loaded_sender.clone();
+            std::thread::spawn(move || loop {
+                // Only locked while taking the next request, so the other workers can take theirs during the decode
+                let request = match request_receiver.lock() {
+                    Ok(receiver) => receiver.recv(),
+                    Err(_) => break,
+                };
+                let Ok((handle, path)) = request else {
+                    break;
+                };
+                let data = ModelData::load(&path);
+                // The server only goes away when the renderer does, nobody is waiting for the model then
+                if loaded_sender.send((handle, path, data)).is_err() {
+                    break;
+                }
+            });
+        }
+        let mut assets = Self {
+            material_bind_group_layout: Material::create_bind_group_layout(device),
+            default_textures: DefaultTextures::new(device, queue),
+            models: Vec::new(),
+            // Replaced right away, the placeholder needs the rest of the server to upload
+            placeholder_model: Model { meshes: Vec::new(), materials: Vec::new(), bounds: None },
+            request_sender,
+            loaded_receiver,
+            pending_loads: 0,
+            model_paths: HashMap::new(),
+            textures: Vec::new(),
+            texture_paths: HashMap::new(),
+        };
+        assets.placeholder_model = Model::upload(device, queue, &mut assets, &placeholder_model_data());
+        assets
+    }
+
+    // Loads an OBJ or glTF model, or returns the handle of the earlier load of the same file
+    pub fn load_model<P: AsRef<Path>>(&mut self, device: &wgpu::Device, queue: &wgpu::Queue, path: P) -> Result<Handle<Model>> {
+        let key = canonical_path(path.as_ref());
+        if let Some(handle) = self.model_paths.get(&key) {
+            return Ok(*handle);
+        }
+        let data = ModelData::load(path)?;
+        let model = Model::upload(device, queue, self, &data);
+        let handle = Handle::new(self.models.len());
+        self.models.push(ModelSlot::Loaded(model, data));
+        self.model_paths.insert(key, handle);
+        Ok(handle)
+    }
+
+    // Decodes the model on a worker thread and returns right away. The handle shows a checkerboard cube until
+    // poll has uploaded the model. Files that fail to load keep the placeholder, see load_state for the error.
+    pub fn load_model_async<P: AsRef<Path>>(&mut self, path: P) -> Handle<Model> {
+        let key = canonical_path(path.as_ref());
+        if let Some(handle) = self.model_paths.get(&key) {
+            return *handle;
+        }
+        let handle = Handle::new(self.models.len());
+        self.models.push(ModelSlot::Loading);
+        self.model_paths.insert(key, handle);
+        self.queue_load(handle, path.as_ref().to_path_buf());
+        handle
+    }
+
+    fn queue_load(&mut self, handle: Handle<Model>, path: PathBuf) {
+        self.pending_loads += 1;
+        // The workers hold the receiver for as long as the server exists
+        let _ = self.request_sender.send((handle, path));
+    }
+
+    pub fn load_state(&self, handle: Handle<Model>) -> LoadState<'_> {
+        match &self.models[handle.index] {
+            ModelSlot::Loading => LoadState::Loading,
+            ModelSlot::Loaded(..) => LoadState::Loaded,
+            ModelSlot::Failed(e) => LoadState::Failed(e),
+        }
+    }
+
+    // Recreates every GPU resource on a new device after the old one was lost, from the decoded textures and models
+    // kept in memory. Nothing is read from disk again. Handles stay valid.
+    pub fn recreate(&mut self, device: &wgpu::Device, queue: &wgpu::Queue) {
+        self.material_bind_group_layout = Material::create_bind_group_layout(device);
+        self.default_textures = DefaultTextures::new(device, queue);
+        // Before the models, which find their textures here when they are uploaded again
+        for slot in &mut self.textures {
+            slot.texture = Arc::new(slot.image.upload(device, queue));
+        }
+        self.placeholder_model = Model::upload(device, queue, self, &placeholder_model_data());
+
+        // Models that are still loading get uploaded by poll, failed ones have nothing to upload
+        for index in 0..self.models.len() {
+            let slot = std::mem::replace(&mut self.models[index], ModelSlot::Loading);
+            self.models[index] = match slot {
+                ModelSlot::Loaded(_, data) => ModelSlot::Loaded(Model::upload(device, queue, self, &data), data),
+                slot => slot,
+            };
+        }
+    }
+
+    // Uploads the models that finished loading in the background, call once per frame
+    pub fn poll(&mut self, device: &wgpu::Device, queue: &wgpu::Queue) {
+        while let Ok(loaded) = self.loaded_receiver.try_recv() {
+            self.finish_load(device, queue, loaded);
+        }
+    }
+
+    // Blocks until every background load has finished and been uploaded
+    pub fn wait_for_loads(&mut self, device: &wgpu::Device, queue: &wgpu::Queue) {
+        while self.pending_loads > 0 {
+            // Only fails once every worker thread is gone
+            let Ok(loaded) = self.loaded_receiver.recv() else {
+                break;
+            };
+            self.finish_load(device, queue, loaded);
+        }
+    }
+
+    fn finish_load(&mut self, device: &wgpu::Device, queue: &wgpu::Queue, (handle, path, data): LoadedModel) {
+        self.pending_loads -= 1;
+        self.models[handle.index] = match data {
+            Ok(data) => ModelSlot::Loaded(Model::upload(device, queue, self, &data), data),
+            Err(e) => {
+                eprintln!("Failed to load model {}: {e:?}", path.display());
+                ModelSlot::Failed(e)
+            }
+        };
+    }
+
+    // Uploads an image that was already decoded, unless the same file was uploaded before
+    pub fn insert_texture(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        path: &Path,
+        image: &Arc<ImageData>,
+    ) -> Handle<Texture> {
+        let key = (canonical_path(path), image.is_linear, image.sampler);
+        if let Some(handle) = self.texture_paths.get(&key) {
+            return *handle;
+        }
+        let texture = Arc::new(image.upload(device, queue));
+        let handle = Handle::new(self.textures.len());
+        self.textures.push(TextureSlot { texture, image: image.clone() });
+        self.texture_paths.insert(key, handle);
+        handle
+    }
+
+    // Uploads a model that was already decoded, unless one was inserted with the same path before
+    pub fn insert_model(&mut self, device: &wgpu::Device, queue: &wgpu::Queue, path: &Path, data: ModelData) -> Handle<Model> {
+        let key = canonical_path(path);
+        if let Some(handle) = self.model_paths.get(&key) {
+            return *handle;
+        }
+        let model = Model::upload(device, queue, self, &data);
+        let handle = Handle::new(self.models.len());
+        self.models.push(ModelSlot::Loaded(model, data));
+        self.model_paths.insert(key, handle);
+        handle
+    }
+
+    // Handles are only created by this server, so they always point at a model, or its placeholder while loading
+    pub fn model(&self, handle: Handle<Model>) -> &Model {
+        match &self.models[handle.index] {
+            ModelSlot::Loaded(model, _) => model,
+            ModelSlot::Loading | ModelSlot::Failed(_) => &self.placeholder_model,
+        }
+    }
+
+    pub fn texture(&self, handle: Handle<Texture>) -> &Arc<Texture> {
+        &self.textures[handle.index].texture
+    }
+
+    pub const fn default_textures(&self) -> &DefaultTextures {
+        &self.default_textures
+    }
+
+    pub const fn material_bind_group_layout(&self) -> &wgpu::BindGroupLayout {
+        &self.material_bind_group_layout
+    }
+}
+
+fn placeholder_model_data() -> ModelData {
+    let checkerboard = image::RgbaImage::from_fn(CHECKERBOARD_SIZE, CHECKERBOARD_SIZE, |x, y| {
+        let square_size = CHECKERBOARD_SIZE / CHECKERBOARD_SQUARES;
+        if (x / square_size + y / square_size).is_multiple_of(2) {
+            image::Rgba([255, 0, 255, 255])
+        } else {
+            image::Rgba([32, 32, 32, 255])
+        }
+    });
+    let material = MaterialData {
+        name: String::from("placeholder_material"),
+        images: MaterialImages {
+            base_color: Some(Arc::new(ImageData {
+                path: None,
+                image: image::DynamicImage::ImageRgba8(checkerboard),
+                is_linear: false,
+                sampler: None,
+            })),
+            ..MaterialImages::default()
+        },
+        factors: MaterialFactors { metallic: 0.0, ..MaterialFactors::default() },
+    };
+    ModelData::cube("placeholder_model", 1.0, material)
+}
+
+// Different spellings of the same path should share an asset. Paths that don't exist are kept as they are,
+// loading them will report the error.
+pub fn canonical_path(path: &Path) -> PathBuf {
+    std::fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf())
+}
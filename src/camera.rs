@@ -1,10 +1,10 @@
-use cgmath::{InnerSpace, Matrix4, Point3, Rad, Vector3, perspective};
+use cgmath::{InnerSpace, Matrix4, Point3, Rad, SquareMatrix, Vector3, perspective};
 use wgpu::{Device, util::DeviceExt};
 use winit::{event::{ElementState, MouseScrollDelta, VirtualKeyCode}, dpi::PhysicalPosition};
 use std::time::Duration;
 use std::f32::consts::FRAC_PI_2;
 
-use crate::renderer::Renderer;
+use crate::{culling::Frustum, renderer::Renderer};
 
 const SAFE_FRAC_PI_2: f32 = FRAC_PI_2 - 0.0001;
 #[derive(Debug)]
@@ -43,25 +43,7 @@ impl Camera {
         let pitch = pitch.into();
         let fovy = fovy.into();
         let raw = Raw::new(position, yaw, pitch, fovy, znear, zfar, aspect);
-        
-        let buffer = renderer.device.create_buffer_init(
-            &wgpu::util::BufferInitDescriptor {
-                label: Some("Uniform Buffer"),
-                contents: bytemuck::cast_slice(&[raw]),
-                usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
-            }
-        );
-
-        let bind_group = renderer.device.create_bind_group(&wgpu::BindGroupDescriptor {
-            layout: &Raw::create_bind_group_layout(&renderer.device),
-            entries: &[
-                wgpu::BindGroupEntry {
-                    binding: 0,
-                    resource: buffer.as_entire_binding(),
-                }
-            ],
-            label: Some("uniform_bind_group"),
-        });
+        let (buffer, bind_group) = create_buffers(&renderer.device, raw);
         
         Self {
             position,
@@ -76,6 +58,11 @@ impl Camera {
         }
     }
 
+    // Creates the uniform buffer again on the renderer's new device, after Renderer::recover
+    pub fn recreate_buffers(&mut self, renderer: &Renderer) {
+        (self.buffer, self.bind_group) = create_buffers(&renderer.device, self.to_raw());
+    }
+
     pub fn resize(&mut self, new_size: winit::dpi::PhysicalSize<u32>) {
         self.aspect = new_size.width as f32 / new_size.height as f32;
     }
@@ -84,6 +71,28 @@ impl Camera {
         Raw::new(self.position, self.yaw, self.pitch, self.fovy, self.znear, self.zfar, self.aspect)
     }
 
+    // World space to view space, where the camera looks down -z
+    pub fn view(&self) -> Matrix4<f32> {
+        view_matrix(self.position, self.yaw, self.pitch)
+    }
+
+    pub fn projection(&self) -> Matrix4<f32> {
+        projection_matrix(self.fovy, self.aspect, self.znear, self.zfar)
+    }
+
+    pub const fn znear(&self) -> f32 {
+        self.znear
+    }
+
+    pub const fn zfar(&self) -> f32 {
+        self.zfar
+    }
+
+    // What the camera can currently see, for skipping everything outside of it
+    pub fn frustum(&self) -> Frustum {
+        Frustum::from_view_projection(&self.to_raw().view_proj.into())
+    }
+
 }
 
 #[repr(C)]
@@ -94,6 +103,8 @@ pub struct Raw {
     // We can't use cgmath with bytemuck directly so we'll have
     // to convert the Matrix4 into a 4x4 f32 array
     view_proj: [[f32; 4]; 4],
+    // Turns a depth buffer position back into world space, used by the deferred lighting pass
+    inv_view_proj: [[f32; 4]; 4],
 }
 
 impl Raw {
@@ -106,20 +117,11 @@ impl Raw {
         zfar: f32,
         aspect: f32,
     ) -> Self {
-        let perspective = OPENGL_TO_WGPU_MATRIX * perspective(fovy, aspect, znear, zfar);
-        let orientation = Matrix4::look_to_rh(
-            position,
-            Vector3::new(
-                yaw.0.cos(),
-                pitch.0.sin(),
-                yaw.0.sin(),
-            ).normalize(),
-            Vector3::unit_y(),
-        );
-        let view_proj = (perspective * orientation).into();
+        let view_proj = projection_matrix(fovy, aspect, znear, zfar) * view_matrix(position, yaw, pitch);
         Self {
             view_position: position.to_homogeneous().into(),
-            view_proj,
+            view_proj: view_proj.into(),
+            inv_view_proj: view_proj.invert().unwrap_or_else(Matrix4::identity).into(),
         }
     }
 
@@ -271,4 +273,42 @@ pub const OPENGL_TO_WGPU_MATRIX: cgmath::Matrix4<f32> = cgmath::Matrix4::new(
     0.0, 1.0, 0.0, 0.0,
     0.0, 0.0, 0.5, 0.0,
     0.0, 0.0, 0.5, 1.0,
-);
\ No newline at end of file
+);
+
+fn view_matrix(position: Point3<f32>, yaw: Rad<f32>, pitch: Rad<f32>) -> Matrix4<f32> {
+    Matrix4::look_to_rh(
+        position,
+        Vector3::new(
+            yaw.0.cos(),
+            pitch.0.sin(),
+            yaw.0.sin(),
+        ).normalize(),
+        Vector3::unit_y(),
+    )
+}
+
+fn projection_matrix(fovy: Rad<f32>, aspect: f32, znear: f32, zfar: f32) -> Matrix4<f32> {
+    OPENGL_TO_WGPU_MATRIX * perspective(fovy, aspect, znear, zfar)
+}
+
+fn create_buffers(device: &Device, raw: Raw) -> (wgpu::Buffer, wgpu::BindGroup) {
+    let buffer = device.create_buffer_init(
+        &wgpu::util::BufferInitDescriptor {
+            label: Some("Uniform Buffer"),
+            contents: bytemuck::cast_slice(&[raw]),
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+        }
+    );
+
+    let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+        layout: &Raw::create_bind_group_layout(device),
+        entries: &[
+            wgpu::BindGroupEntry {
+                binding: 0,
+                resource: buffer.as_entire_binding(),
+            }
+        ],
+        label: Some("uniform_bind_group"),
+    });
+    (buffer, bind_group)
+}
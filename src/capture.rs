@@ -0,0 +1,86 @@
+use std::{cell::OnceCell, path::PathBuf, sync::mpsc, thread::JoinHandle};
+
+use anyhow::{bail, Result};
+
+// A pending request to write rendered frames to disk
+#[derive(Debug)]
+pub enum Capture {
+    Screenshot(PathBuf),
+    Sequence {
+        directory: PathBuf,
+        next_frame: u32,
+        remaining: u32,
+    },
+}
+
+impl Capture {
+    // Fails for a frame_count of 0, since every capture writes at least the frame it is taken on
+    pub fn sequence<P: Into<PathBuf>>(directory: P, frame_count: u32) -> Result<Self> {
+        if frame_count == 0 {
+            bail!("A frame capture needs at least one frame");
+        }
+        let directory = directory.into();
+        std::fs::create_dir_all(&directory)?;
+        Ok(Self::Sequence {
+            directory,
+            next_frame: 0,
+            remaining: frame_count,
+        })
+    }
+
+    // Returns the path the current frame should be written to, and what is left of the capture afterwards
+    pub fn advance(self) -> (PathBuf, Option<Self>) {
+        match self {
+            Self::Screenshot(path) => (path, None),
+            Self::Sequence { directory, next_frame, remaining } => {
+                let path = directory.join(format!("frame_{next_frame:05}.png"));
+                let rest = (remaining > 1).then_some(Self::Sequence {
+                    directory,
+                    next_frame: next_frame + 1,
+                    remaining: remaining - 1,
+                });
+                (path, rest)
+            }
+        }
+    }
+}
+
+// A frame waiting to be written, and where to
+type Frame = (image::RgbaImage, PathBuf);
+
+// Encoding PNGs is slow, so images are written on a separate thread to keep the frame rate up while capturing.
+// A single thread writes them in order, started with the first capture.
+#[derive(Debug, Default)]
+pub struct Writer {
+    thread: OnceCell<(mpsc::Sender<Frame>, JoinHandle<()>)>,
+}
+
+impl Writer {
+    pub fn save(&self, image: image::RgbaImage, path: PathBuf) {
+        let (sender, _) = self.thread.get_or_init(|| {
+            let (sender, receiver) = mpsc::channel::<Frame>();
+            let thread = std::thread::spawn(move || {
+                for (image, path) in receiver {
+                    if let Err(e) = image.save(&path) {
+                        eprintln!("Failed to save capture to {}: {e}", path.display());
+                    }
+                }
+            });
+            (sender, thread)
+        });
+        // Only fails if the writer thread panicked
+        if sender.send((image, path)).is_err() {
+            eprintln!("Failed to save capture, the writer thread stopped");
+        }
+    }
+}
+
+impl Drop for Writer {
+    // Finishes writing the frames that are still queued
+    fn drop(&mut self) {
+        if let Some((sender, thread)) = self.thread.take() {
+            drop(sender);
+            let _ = thread.join();
+        }
+    }
+}
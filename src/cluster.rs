@@ -0,0 +1,223 @@
+use crate::{camera::Camera, graph::{BufferId, RenderGraph}};
+
+// The view frustum is split into this many clusters along x, y and depth, must match clusters.wgsl
+const CLUSTERS_X: u32 = 16;
+const CLUSTERS_Y: u32 = 9;
+const CLUSTERS_Z: u32 = 24;
+const CLUSTER_COUNT: u32 = CLUSTERS_X * CLUSTERS_Y * CLUSTERS_Z;
+// Lights past this many are left out of a cluster and the cluster shows up magenta in the heat map, must match
+// clusters.wgsl. Directional lights are listed separately and don't count.
+const MAX_LIGHTS_PER_CLUSTER: u32 = 32;
+// A light count followed by the light indices
+#[allow(clippy::cast_possible_truncation)]
+const CLUSTER_SIZE: u32 = (1 + MAX_LIGHTS_PER_CLUSTER) * std::mem::size_of::<u32>() as u32;
+// Must match the workgroup size in light_culling.wgsl
+const WORKGROUP_SIZE: u32 = 64;
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+struct Raw {
+    view: [[f32; 4]; 4],
+    // The x and y scale of the projection, the culling shader builds the clusters in view space from them
+    projection_scale: [f32; 2],
+    z_near: f32,
+    z_far: f32,
+    screen_size: [f32; 2],
+    show_heatmap: u32,
+    _padding: u32,
+}
+
+// Clustered light culling for forward shading. A compute pass lists the lights reaching each cluster of the view
+// frustum, so a fragment only loops over the lights of its own cluster instead of every light.
+pub struct ClusteredLighting {
+    params_buffer: wgpu::Buffer,
+    cluster_buffer: wgpu::Buffer,
+    // What the forward shader reads the clusters through
+    pub bind_group: wgpu::BindGroup,
+    culling_bind_group_layout: wgpu::BindGroupLayout,
+    pipeline: wgpu::ComputePipeline,
+    show_heatmap: bool,
+}
+
+impl ClusteredLighting {
+    pub fn new(device: &wgpu::Device) -> Self {
+        let params_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Cluster Params Buffer"),
+            size: std::mem::size_of::<Raw>() as wgpu::BufferAddress,
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        let cluster_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Cluster Buffer"),
+            // The directional lights come first, laid out like a cluster
+            size: u64::from(CLUSTER_SIZE * (CLUSTER_COUNT + 1)),
+            usage: wgpu::BufferUsages::STORAGE,
+            mapped_at_creation: false,
+        });
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout: &Self::create_bind_group_layout(device),
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: params_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: cluster_buffer.as_entire_binding(),
+                },
+            ],
+            label: Some("cluster_bind_group"),
+        });
+        let culling_bind_group_layout = create_culling_bind_group_layout(device);
+        let pipeline = create_culling_pipeline(device, &culling_bind_group_layout);
+
+        Self {
+            params_buffer,
+            cluster_buffer,
+            bind_group,
+            culling_bind_group_layout,
+            pipeline,
+            show_heatmap: false,
+        }
+    }
+
+    pub fn create_bind_group_layout(device: &wgpu::Device) -> wgpu::BindGroupLayout {
+        device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            entries: &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Storage { read_only: true },
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+            ],
+            label: Some("cluster_bind_group_layout"),
+        })
+    }
+
+    // The clusters follow the camera, so this has to run whenever it moved or the screen was resized
+    #[allow(clippy::cast_precision_loss)]
+    pub fn update(&self, queue: &wgpu::Queue, camera: &Camera, width: u32, height: u32) {
+        let projection = camera.projection();
+        let raw = Raw {
+            view: camera.view().into(),
+            projection_scale: [projection.x.x, projection.y.y],
+            z_near: camera.znear(),
+            z_far: camera.zfar(),
+            screen_size: [width as f32, height as f32],
+            show_heatmap: u32::from(self.show_heatmap),
+            _padding: 0,
+        };
+        queue.write_buffer(&self.params_buffer, 0, bytemuck::cast_slice(&[raw]));
+    }
+
+    pub const fn show_heatmap(&self) -> bool {
+        self.show_heatmap
+    }
+
+    // Takes effect with the next update
+    pub const fn set_show_heatmap(&mut self, show_heatmap: bool) {
+        self.show_heatmap = show_heatmap;
+    }
+
+    // Adds the compute pass assigning the lights to the clusters, returns the clusters the forward pass has to read
+    pub fn add_pass<'a>(&'a self, graph: &mut RenderGraph<'a>, lights: BufferId, scene_lighting: &'a wgpu::Buffer) -> BufferId {
+        let clusters = graph.import_buffer(&self.cluster_buffer);
+        let mut pass = graph.add_pass("light_culling");
+        pass.read_buffer(lights);
+        let clusters = pass.write_buffer(clusters);
+        pass.execute(move |context| {
+            // The light buffer is replaced when it grows, so the bind group is made for the one of this frame
+            let bind_group = context.device.create_bind_group(&wgpu::BindGroupDescriptor {
+                layout: &self.culling_bind_group_layout,
+                entries: &[
+                    wgpu::BindGroupEntry {
+                        binding: 0,
+                        resource: self.params_buffer.as_entire_binding(),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 1,
+                        resource: context.buffer(lights).as_entire_binding(),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 2,
+                        resource: scene_lighting.as_entire_binding(),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 3,
+                        resource: context.buffer(clusters).as_entire_binding(),
+                    },
+                ],
+                label: Some("light_culling_bind_group"),
+            });
+            let mut compute_pass = context.encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+                label: Some("Light Culling Pass"),
+            });
+            compute_pass.set_pipeline(&self.pipeline);
+            compute_pass.set_bind_group(0, &bind_group, &[]);
+            compute_pass.dispatch_workgroups(CLUSTER_COUNT.div_ceil(WORKGROUP_SIZE), 1, 1);
+        });
+        clusters
+    }
+}
+
+fn create_culling_bind_group_layout(device: &wgpu::Device) -> wgpu::BindGroupLayout {
+    let buffer_entry = |binding, ty| wgpu::BindGroupLayoutEntry {
+        binding,
+        visibility: wgpu::ShaderStages::COMPUTE,
+        ty: wgpu::BindingType::Buffer {
+            ty,
+            has_dynamic_offset: false,
+            min_binding_size: None,
+        },
+        count: None,
+    };
+    device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+        entries: &[
+            // cluster params
+            buffer_entry(0, wgpu::BufferBindingType::Uniform),
+            // lights
+            buffer_entry(1, wgpu::BufferBindingType::Storage { read_only: true }),
+            // scene lighting, for the light count
+            buffer_entry(2, wgpu::BufferBindingType::Uniform),
+            // clusters
+            buffer_entry(3, wgpu::BufferBindingType::Storage { read_only: false }),
+        ],
+        label: Some("light_culling_bind_group_layout"),
+    })
+}
+
+fn create_culling_pipeline(device: &wgpu::Device, bind_group_layout: &wgpu::BindGroupLayout) -> wgpu::ComputePipeline {
+    let layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+        label: Some("Light Culling Pipeline Layout"),
+        bind_group_layouts: &[bind_group_layout],
+        push_constant_ranges: &[],
+    });
+    let shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+        label: Some("Light Culling Shader"),
+        source: wgpu::ShaderSource::Wgsl(concat!(
+            include_str!("shaders/wgpu_0.13/clusters.wgsl"),
+            include_str!("shaders/wgpu_0.13/light_culling.wgsl"),
+        ).into()),
+    });
+    device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+        label: Some("Light Culling Pipeline"),
+        layout: Some(&layout),
+        module: &shader,
+        entry_point: "main",
+    })
+}
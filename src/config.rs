@@ -0,0 +1,180 @@
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::post::PostPass;
+
+// Graphics APIs the renderer can pick an adapter from
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Backend {
+    // Whichever API works on this machine, preferring Vulkan, Metal and DX12 over the rest
+    Any,
+    Vulkan,
+    Metal,
+    Dx12,
+    Gl,
+}
+
+impl Backend {
+    pub const fn to_backends(self) -> wgpu::Backends {
+        match self {
+            Self::Any => wgpu::Backends::all(),
+            Self::Vulkan => wgpu::Backends::VULKAN,
+            Self::Metal => wgpu::Backends::METAL,
+            Self::Dx12 => wgpu::Backends::DX12,
+            Self::Gl => wgpu::Backends::GL,
+        }
+    }
+}
+
+// How finished frames are handed to the screen. The auto modes pick the best mode the surface supports.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PresentMode {
+    AutoVsync,
+    AutoNoVsync,
+    Fifo,
+    FifoRelaxed,
+    Immediate,
+    Mailbox,
+}
+
+impl PresentMode {
+    pub const ALL: [Self; 6] = [Self::AutoVsync, Self::AutoNoVsync, Self::Fifo, Self::FifoRelaxed, Self::Immediate, Self::Mailbox];
+
+    pub const fn to_wgpu(self) -> wgpu::PresentMode {
+        match self {
+            Self::AutoVsync => wgpu::PresentMode::AutoVsync,
+            Self::AutoNoVsync => wgpu::PresentMode::AutoNoVsync,
+            Self::Fifo => wgpu::PresentMode::Fifo,
+            Self::FifoRelaxed => wgpu::PresentMode::FifoRelaxed,
+            Self::Immediate => wgpu::PresentMode::Immediate,
+            Self::Mailbox => wgpu::PresentMode::Mailbox,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PowerPreference {
+    None,
+    LowPower,
+    HighPerformance,
+}
+
+impl PowerPreference {
+    pub const fn to_wgpu(self) -> wgpu::PowerPreference {
+        match self {
+            Self::None => wgpu::PowerPreference::None,
+            Self::LowPower => wgpu::PowerPreference::LowPower,
+            Self::HighPerformance => wgpu::PowerPreference::HighPerformance,
+        }
+    }
+}
+
+// The device limits that are requested. Lower limits let the renderer start on older hardware and GL drivers.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Limits {
+    Default,
+    Downlevel,
+    // Whatever the adapter supports
+    Adapter,
+}
+
+impl Limits {
+    pub fn to_wgpu(self, adapter: &wgpu::Adapter) -> wgpu::Limits {
+        match self {
+            Self::Default => wgpu::Limits::default(),
+            Self::Downlevel => wgpu::Limits::downlevel_defaults(),
+            Self::Adapter => adapter.limits(),
+        }
+    }
+}
+
+// How the scene is lit
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Shading {
+    // Every fragment loops over the lights reaching its cluster of the view frustum, which a compute pass works
+    // out every frame
+    Forward,
+    // Surfaces are written into a G-buffer, which is then shaded once per light within the light's range. Faster
+    // with many lights, but without MSAA.
+    Deferred,
+}
+
+// Settings the renderer is created with. The ones with a setter on Renderer can also be changed while running.
+// Config files only need the settings that differ from the defaults.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RendererConfig {
+    pub backend: Backend,
+    pub present_mode: PresentMode,
+    pub power_preference: PowerPreference,
+    pub limits: Limits,
+    // Linear RGBA the frame is cleared to before drawing
+    pub clear_color: [f64; 4],
+    // MSAA samples per pixel, 1 turns multisampling off. Must be one of Renderer::supported_sample_counts.
+    pub sample_count: u32,
+    // Run in order on the HDR image of the scene. An empty chain clips the colors to what the screen can show.
+    pub post_processing: Vec<PostPass>,
+    pub shading: Shading,
+}
+
+impl Default for RendererConfig {
+    fn default() -> Self {
+        Self {
+            backend: Backend::Any,
+            present_mode: PresentMode::AutoVsync,
+            power_preference: PowerPreference::None,
+            limits: Limits::Default,
+            clear_color: [0.1, 0.2, 0.3, 1.0],
+            // Every adapter supports 4 samples for the formats the renderer uses
+            sample_count: 4,
+            post_processing: PostPass::default_chain(),
+            shading: Shading::Forward,
+        }
+    }
+}
+
+impl RendererConfig {
+    // Reads a TOML file with the same fields as the struct
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let text = std::fs::read_to_string(path).with_context(|| format!("Could not read {}", path.display()))?;
+        toml::from_str(&text).with_context(|| format!("Invalid renderer config {}", path.display()))
+    }
+
+    pub const fn with_backend(mut self, backend: Backend) -> Self {
+        self.backend = backend;
+        self
+    }
+
+    pub const fn with_present_mode(mut self, present_mode: PresentMode) -> Self {
+        self.present_mode = present_mode;
+        self
+    }
+
+    pub const fn with_power_preference(mut self, power_preference: PowerPreference) -> Self {
+        self.power_preference = power_preference;
+        self
+    }
+
+    pub const fn with_limits(mut self, limits: Limits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    pub const fn with_clear_color(mut self, clear_color: [f64; 4]) -> Self {
+        self.clear_color = clear_color;
+        self
+    }
+
+    pub const fn with_sample_count(mut self, sample_count: u32) -> Self {
+        self.sample_count = sample_count;
+        self
+    }
+}
@@ -0,0 +1,239 @@
+use cgmath::{EuclideanSpace, InnerSpace, Matrix, Matrix3, Matrix4, MetricSpace, Point3, Transform, Vector3, Vector4};
+
+// Box around a mesh whose sides are parallel to the axes of the space it is in
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Aabb {
+    pub min: Point3<f32>,
+    pub max: Point3<f32>,
+}
+
+impl Aabb {
+    // None if there are no points
+    pub fn from_points<I: IntoIterator<Item = Point3<f32>>>(points: I) -> Option<Self> {
+        points.into_iter().fold(None, |aabb: Option<Self>, point| {
+            Some(aabb.map_or(Self { min: point, max: point }, |aabb| Self {
+                min: Point3::new(aabb.min.x.min(point.x), aabb.min.y.min(point.y), aabb.min.z.min(point.z)),
+                max: Point3::new(aabb.max.x.max(point.x), aabb.max.y.max(point.y), aabb.max.z.max(point.z)),
+            }))
+        })
+    }
+
+    pub const fn union(self, other: Self) -> Self {
+        Self {
+            min: Point3::new(self.min.x.min(other.min.x), self.min.y.min(other.min.y), self.min.z.min(other.min.z)),
+            max: Point3::new(self.max.x.max(other.max.x), self.max.y.max(other.max.y), self.max.z.max(other.max.z)),
+        }
+    }
+
+    pub fn center(self) -> Point3<f32> {
+        self.min.midpoint(self.max)
+    }
+
+    pub fn half_extents(self) -> Vector3<f32> {
+        (self.max - self.min) / 2.0
+    }
+
+    // The smallest box around this one once it is transformed, which is larger than the box itself if it is rotated
+    pub fn transformed(self, matrix: &Matrix4<f32>) -> Self {
+        let center = matrix.transform_point(self.center());
+        let half = self.half_extents();
+        // Every axis of the new box gets the absolute contribution of each of the old box's axes
+        let absolute = Matrix3::from_cols(abs(matrix.x.truncate()), abs(matrix.y.truncate()), abs(matrix.z.truncate()));
+        let half = absolute * half;
+        Self { min: center - half, max: center + half }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct BoundingSphere {
+    pub center: Point3<f32>,
+    pub radius: f32,
+}
+
+impl BoundingSphere {
+    pub fn transformed(self, matrix: &Matrix4<f32>) -> Self {
+        // Non-uniform scale turns the sphere into an ellipsoid, so grow it by the largest scale to keep it around it
+        let scale = matrix.x.truncate().magnitude()
+            .max(matrix.y.truncate().magnitude())
+            .max(matrix.z.truncate().magnitude());
+        Self {
+            center: matrix.transform_point(self.center),
+            radius: self.radius * scale,
+        }
+    }
+}
+
+// What is tested against the view frustum. The sphere is the quicker test, the box is tighter around long meshes.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Bounds {
+    pub aabb: Aabb,
+    pub sphere: BoundingSphere,
+}
+
+impl Bounds {
+    // None if there are no points
+    pub fn from_points<I: IntoIterator<Item = Point3<f32>> + Clone>(points: I) -> Option<Self> {
+        let aabb = Aabb::from_points(points.clone())?;
+        let center = aabb.center();
+        // Centered on the box, but only as large as the points need rather than reaching the box's corners
+        let radius = points.into_iter().map(|point| point.distance(center)).fold(0.0, f32::max);
+        Some(Self { aabb, sphere: BoundingSphere { center, radius } })
+    }
+
+    pub fn union(self, other: Self) -> Self {
+        let aabb = self.aabb.union(other.aabb);
+        let center = aabb.center();
+        let radius = (self.sphere.center.distance(center) + self.sphere.radius)
+            .max(other.sphere.center.distance(center) + other.sphere.radius);
+        Self { aabb, sphere: BoundingSphere { center, radius } }
+    }
+
+    pub fn transformed(self, matrix: &Matrix4<f32>) -> Self {
+        Self {
+            aabb: self.aabb.transformed(matrix),
+            sphere: self.sphere.transformed(matrix),
+        }
+    }
+}
+
+// The six planes around what a camera can see, pointing inwards
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Frustum {
+    // Normal in xyz and distance from the origin in w, with normalized normals
+    planes: [Vector4<f32>; 6],
+}
+
+impl Frustum {
+    // Extracts the planes from the rows of a view projection matrix (Gribb and Hartmann). wgpu clip space has
+    // x and y in -w..w but z in 0..w, so the near plane is the third row on its own.
+    pub fn from_view_projection(view_projection: &Matrix4<f32>) -> Self {
+        let rows = [view_projection.row(0), view_projection.row(1), view_projection.row(2), view_projection.row(3)];
+        let planes = [
+            rows[3] + rows[0],
+            rows[3] - rows[0],
+            rows[3] + rows[1],
+            rows[3] - rows[1],
+            rows[2],
+            rows[3] - rows[2],
+        ];
+        Self { planes: planes.map(|plane| plane / plane.truncate().magnitude()) }
+    }
+
+    pub fn intersects_sphere(&self, sphere: &BoundingSphere) -> bool {
+        self.planes.iter().all(|plane| plane.truncate().dot(sphere.center.to_vec()) + plane.w >= -sphere.radius)
+    }
+
+    // Conservative, boxes near the frustum's corners can pass without actually being inside
+    pub fn intersects_aabb(&self, aabb: &Aabb) -> bool {
+        self.planes.iter().all(|plane| {
+            // The corner furthest along the plane's normal is the last one to leave the frustum
+            let corner = Vector3::new(
+                if plane.x >= 0.0 { aabb.max.x } else { aabb.min.x },
+                if plane.y >= 0.0 { aabb.max.y } else { aabb.min.y },
+                if plane.z >= 0.0 { aabb.max.z } else { aabb.min.z },
+            );
+            plane.truncate().dot(corner) + plane.w >= 0.0
+        })
+    }
+
+    pub fn intersects(&self, bounds: &Bounds) -> bool {
+        self.intersects_sphere(&bounds.sphere) && self.intersects_aabb(&bounds.aabb)
+    }
+}
+
+const fn abs(vector: Vector3<f32>) -> Vector3<f32> {
+    Vector3::new(vector.x.abs(), vector.y.abs(), vector.z.abs())
+}
+
+// How many instances the last frame drew and how many it skipped for being out of view
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct CullingStats {
+    pub drawn: u32,
+    pub culled: u32,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cgmath::{Deg, Rad};
+
+    // Looks down -Z with a 90 degree field of view, so the side planes are at 45 degrees, from 1 to 10 units away
+    fn frustum() -> Frustum {
+        let projection = crate::camera::OPENGL_TO_WGPU_MATRIX * cgmath::perspective(Deg(90.0), 1.0, 1.0, 10.0);
+        let view = Matrix4::look_to_rh(Point3::new(0.0, 0.0, 0.0), -Vector3::unit_z(), Vector3::unit_y());
+        Frustum::from_view_projection(&(projection * view))
+    }
+
+    fn point(x: f32, y: f32, z: f32) -> BoundingSphere {
+        BoundingSphere { center: Point3::new(x, y, z), radius: 0.0 }
+    }
+
+    fn cube(x: f32, y: f32, z: f32, half_extent: f32) -> Aabb {
+        let center = Point3::new(x, y, z);
+        let half = Vector3::new(half_extent, half_extent, half_extent);
+        Aabb { min: center - half, max: center + half }
+    }
+
+    #[test]
+    fn planes_are_normalized_and_point_inwards() {
+        let frustum = frustum();
+        for plane in frustum.planes {
+            assert!((plane.truncate().magnitude() - 1.0).abs() < 1e-5);
+            // The middle of the frustum is on the inner side of every plane
+            assert!(plane.truncate().dot(Vector3::new(0.0, 0.0, -5.0)) + plane.w > 0.0);
+        }
+    }
+
+    #[test]
+    fn planes_lie_on_the_edges_of_the_view() {
+        let frustum = frustum();
+        assert!(frustum.intersects_sphere(&point(0.0, 0.0, -1.01)));
+        assert!(!frustum.intersects_sphere(&point(0.0, 0.0, -0.99)));
+        assert!(frustum.intersects_sphere(&point(0.0, 0.0, -9.99)));
+        assert!(!frustum.intersects_sphere(&point(0.0, 0.0, -10.01)));
+        assert!(frustum.intersects_sphere(&point(4.9, 0.0, -5.0)));
+        assert!(!frustum.intersects_sphere(&point(5.1, 0.0, -5.0)));
+        assert!(frustum.intersects_sphere(&point(0.0, -4.9, -5.0)));
+        assert!(!frustum.intersects_sphere(&point(0.0, -5.1, -5.0)));
+    }
+
+    #[test]
+    fn spheres_count_as_visible_until_they_are_fully_outside() {
+        let frustum = frustum();
+        assert!(frustum.intersects_sphere(&BoundingSphere { center: Point3::new(0.0, 0.0, 5.0), radius: 6.5 }));
+        assert!(!frustum.intersects_sphere(&BoundingSphere { center: Point3::new(0.0, 0.0, 5.0), radius: 5.5 }));
+    }
+
+    #[test]
+    fn boxes_inside_outside_and_straddling_a_plane() {
+        let frustum = frustum();
+        assert!(frustum.intersects_aabb(&cube(0.0, 0.0, -5.0, 1.0)));
+        assert!(!frustum.intersects_aabb(&cube(0.0, 0.0, 5.0, 1.0)));
+        assert!(!frustum.intersects_aabb(&cube(8.0, 0.0, -5.0, 1.0)));
+        // Half in front of the far plane, half behind it
+        assert!(frustum.intersects_aabb(&cube(0.0, 0.0, -10.0, 1.0)));
+        // Across the right plane
+        assert!(frustum.intersects_aabb(&cube(5.0, 0.0, -5.0, 0.5)));
+
+        let bounds = Bounds::from_points([Point3::new(4.5, -0.5, -5.5), Point3::new(5.5, 0.5, -4.5)]).unwrap();
+        assert!(frustum.intersects(&bounds));
+        assert!(!frustum.intersects(&bounds.transformed(&Matrix4::from_translation(Vector3::new(3.0, 0.0, 0.0)))));
+    }
+
+    #[test]
+    fn transformed_boxes_stay_around_the_rotated_box() {
+        let rotation = Matrix4::from_angle_y(Rad(std::f32::consts::FRAC_PI_4));
+        let moved = cube(0.0, 0.0, 0.0, 1.0).transformed(&(Matrix4::from_translation(Vector3::new(1.0, 2.0, 3.0)) * rotation));
+        let reach = std::f32::consts::SQRT_2;
+        let expected = Aabb { min: Point3::new(1.0 - reach, 1.0, 3.0 - reach), max: Point3::new(1.0 + reach, 3.0, 3.0 + reach) };
+        assert!((moved.min - expected.min).magnitude() < 1e-5 && (moved.max - expected.max).magnitude() < 1e-5);
+    }
+
+    #[test]
+    fn transformed_spheres_grow_by_the_largest_scale() {
+        let sphere = BoundingSphere { center: Point3::new(1.0, 0.0, 0.0), radius: 1.0 };
+        let moved = sphere.transformed(&Matrix4::from_nonuniform_scale(2.0, 3.0, 0.5));
+        assert_eq!(moved.center, Point3::new(2.0, 0.0, 0.0));
+        assert!((moved.radius - 3.0).abs() < 1e-5);
+    }
+}
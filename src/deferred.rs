@@ -0,0 +1,199 @@
+use crate::{model::{self, Vertex}, post::HDR_FORMAT, texture::Texture, transform};
+
+// Must match GBufferOutput in gbuffer.wgsl. The scene target holds the ambient and emissive light.
+pub const ALBEDO_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rgba8UnormSrgb;
+pub const NORMAL_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rgba16Float;
+pub const MATERIAL_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rgba8Unorm;
+// Every light is drawn as a box of this many vertices, see deferred_lighting.wgsl
+pub const LIGHT_VOLUME_VERTICES: u32 = 36;
+
+// The pipelines of deferred shading, which writes the surfaces into a G-buffer first and then shades it once per
+// light. The cost grows with the pixels each light covers, instead of with lights × pixels.
+pub struct DeferredShading {
+    pub geometry_pipeline: wgpu::RenderPipeline,
+    pub lighting_pipeline: wgpu::RenderPipeline,
+    bind_group_layout: wgpu::BindGroupLayout,
+}
+
+impl DeferredShading {
+    pub fn new(
+        device: &wgpu::Device,
+        material_bind_group_layout: &wgpu::BindGroupLayout,
+        camera_bind_group_layout: &wgpu::BindGroupLayout,
+        light_bind_group_layout: &wgpu::BindGroupLayout,
+    ) -> Self {
+        let bind_group_layout = create_bind_group_layout(device);
+        let geometry_pipeline = create_geometry_pipeline(device, material_bind_group_layout, camera_bind_group_layout, light_bind_group_layout);
+        let lighting_pipeline = create_lighting_pipeline(device, &bind_group_layout, camera_bind_group_layout, light_bind_group_layout);
+        Self { geometry_pipeline, lighting_pipeline, bind_group_layout }
+    }
+
+    // The G-buffer as the lighting pass reads it
+    pub fn bind_group(
+        &self,
+        device: &wgpu::Device,
+        albedo: &wgpu::TextureView,
+        normal: &wgpu::TextureView,
+        material: &wgpu::TextureView,
+        depth: &wgpu::TextureView,
+    ) -> wgpu::BindGroup {
+        device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout: &self.bind_group_layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: wgpu::BindingResource::TextureView(albedo),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::TextureView(normal),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: wgpu::BindingResource::TextureView(material),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 3,
+                    resource: wgpu::BindingResource::TextureView(depth),
+                },
+            ],
+            label: Some("gbuffer_bind_group"),
+        })
+    }
+}
+
+// The lighting pass only loads single texels, so nothing needs to be filterable. The depth is bound as a float
+// texture too, the GL backend can't load texels from depth textures.
+fn create_bind_group_layout(device: &wgpu::Device) -> wgpu::BindGroupLayout {
+    let texture_entry = |binding| wgpu::BindGroupLayoutEntry {
+        binding,
+        visibility: wgpu::ShaderStages::FRAGMENT,
+        ty: wgpu::BindingType::Texture {
+            multisampled: false,
+            view_dimension: wgpu::TextureViewDimension::D2,
+            sample_type: wgpu::TextureSampleType::Float { filterable: false },
+        },
+        count: None,
+    };
+    device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+        entries: &[texture_entry(0), texture_entry(1), texture_entry(2), texture_entry(3)],
+        label: Some("gbuffer_bind_group_layout"),
+    })
+}
+
+fn create_geometry_pipeline(
+    device: &wgpu::Device,
+    material_bind_group_layout: &wgpu::BindGroupLayout,
+    camera_bind_group_layout: &wgpu::BindGroupLayout,
+    light_bind_group_layout: &wgpu::BindGroupLayout,
+) -> wgpu::RenderPipeline {
+    let layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+        label: Some("GBuffer Pipeline Layout"),
+        bind_group_layouts: &[material_bind_group_layout, camera_bind_group_layout, light_bind_group_layout],
+        push_constant_ranges: &[],
+    });
+    let vertex_shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+        label: Some("GBuffer Shader Vertex"),
+        source: wgpu::ShaderSource::Wgsl(include_str!("shaders/wgpu_0.13/vertex_shader.wgsl").into()),
+    });
+    let fragment_shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+        label: Some("GBuffer Shader Fragment"),
+        source: wgpu::ShaderSource::Wgsl(concat!(
+            include_str!("shaders/wgpu_0.13/material.wgsl"),
+            include_str!("shaders/wgpu_0.13/lighting.wgsl"),
+            include_str!("shaders/wgpu_0.13/gbuffer.wgsl"),
+        ).into()),
+    });
+    let target = |format| Some(wgpu::ColorTargetState {
+        format,
+        blend: None,
+        write_mask: wgpu::ColorWrites::ALL,
+    });
+
+    device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+        label: Some("GBuffer Pipeline"),
+        layout: Some(&layout),
+        vertex: wgpu::VertexState {
+            module: &vertex_shader,
+            entry_point: "main",
+            buffers: &[model::ModelVertex::desc(), transform::Raw::desc()],
+        },
+        fragment: Some(wgpu::FragmentState {
+            module: &fragment_shader,
+            entry_point: "main",
+            targets: &[target(HDR_FORMAT), target(ALBEDO_FORMAT), target(NORMAL_FORMAT), target(MATERIAL_FORMAT)],
+        }),
+        primitive: wgpu::PrimitiveState {
+            cull_mode: Some(wgpu::Face::Back),
+            ..wgpu::PrimitiveState::default()
+        },
+        depth_stencil: Some(wgpu::DepthStencilState {
+            format: Texture::DEPTH_FORMAT,
+            depth_write_enabled: true,
+            depth_compare: wgpu::CompareFunction::Less,
+            stencil: wgpu::StencilState::default(),
+            bias: wgpu::DepthBiasState::default(),
+        }),
+        multisample: wgpu::MultisampleState::default(),
+        multiview: None,
+    })
+}
+
+fn create_lighting_pipeline(
+    device: &wgpu::Device,
+    gbuffer_bind_group_layout: &wgpu::BindGroupLayout,
+    camera_bind_group_layout: &wgpu::BindGroupLayout,
+    light_bind_group_layout: &wgpu::BindGroupLayout,
+) -> wgpu::RenderPipeline {
+    let layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+        label: Some("Deferred Lighting Pipeline Layout"),
+        bind_group_layouts: &[gbuffer_bind_group_layout, camera_bind_group_layout, light_bind_group_layout],
+        push_constant_ranges: &[],
+    });
+    let shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+        label: Some("Deferred Lighting Shader"),
+        source: wgpu::ShaderSource::Wgsl(concat!(
+            include_str!("shaders/wgpu_0.13/lighting.wgsl"),
+            include_str!("shaders/wgpu_0.13/deferred_lighting.wgsl"),
+        ).into()),
+    });
+
+    device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+        label: Some("Deferred Lighting Pipeline"),
+        layout: Some(&layout),
+        vertex: wgpu::VertexState {
+            module: &shader,
+            entry_point: "vs_main",
+            buffers: &[],
+        },
+        fragment: Some(wgpu::FragmentState {
+            module: &shader,
+            entry_point: "fs_main",
+            targets: &[Some(wgpu::ColorTargetState {
+                format: HDR_FORMAT,
+                // Every light adds its light to what is already there
+                blend: Some(wgpu::BlendState {
+                    color: wgpu::BlendComponent {
+                        src_factor: wgpu::BlendFactor::One,
+                        dst_factor: wgpu::BlendFactor::One,
+                        operation: wgpu::BlendOperation::Add,
+                    },
+                    alpha: wgpu::BlendComponent {
+                        src_factor: wgpu::BlendFactor::Zero,
+                        dst_factor: wgpu::BlendFactor::One,
+                        operation: wgpu::BlendOperation::Add,
+                    },
+                }),
+                write_mask: wgpu::ColorWrites::ALL,
+            })],
+        }),
+        // The back faces of the light volumes, so each pixel inside a volume is shaded once by it
+        primitive: wgpu::PrimitiveState {
+            cull_mode: Some(wgpu::Face::Front),
+            ..wgpu::PrimitiveState::default()
+        },
+        depth_stencil: None,
+        multisample: wgpu::MultisampleState::default(),
+        multiview: None,
+    })
+}
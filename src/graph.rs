@@ -0,0 +1,467 @@
+use std::{cmp::Reverse, collections::BinaryHeap};
+
+// What a transient texture is created with. Textures with the same description share memory when no pass in
+// the frame needs both at once.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct TextureDesc {
+    pub width: u32,
+    pub height: u32,
+    pub format: wgpu::TextureFormat,
+    pub sample_count: u32,
+    pub usage: wgpu::TextureUsages,
+}
+
+// A resource as a pass left it. Writing a resource gives a new version, so the passes reading the old version
+// run before the write and the passes reading the new one after it.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+struct Version {
+    resource: usize,
+    version: usize,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct TextureId(Version);
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct BufferId(Version);
+
+enum Resource<'a> {
+    // Created by the graph, and only valid between the first and the last pass using it
+    Transient { label: &'static str, desc: TextureDesc },
+    Imported(Bound<'a>),
+}
+
+// Who wrote a version of a resource and who read it
+#[derive(Default)]
+struct VersionUse {
+    // None for imported resources that already hold their contents when the frame starts
+    writer: Option<usize>,
+    readers: Vec<usize>,
+}
+
+struct ResourceNode<'a> {
+    resource: Resource<'a>,
+    versions: Vec<VersionUse>,
+}
+
+type Execute<'a> = Box<dyn FnOnce(&mut PassContext) + 'a>;
+
+struct PassNode<'a> {
+    name: &'static str,
+    reads: Vec<Version>,
+    writes: Vec<Version>,
+    execute: Option<Execute<'a>>,
+}
+
+// The passes of a frame and the resources they use. Passes can be added in any order, they run in the order
+// their reads and writes require, and passes nothing imported depends on are skipped.
+#[derive(Default)]
+pub struct RenderGraph<'a> {
+    resources: Vec<ResourceNode<'a>>,
+    passes: Vec<PassNode<'a>>,
+}
+
+impl<'a> RenderGraph<'a> {
+    // A texture that lives outside of the graph, e.g. the frame or a shadow map that is sampled by a bind group
+    pub fn import_texture(&mut self, view: &'a wgpu::TextureView) -> TextureId {
+        TextureId(self.add_resource(Resource::Imported(Bound::Texture(view)), None))
+    }
+
+    pub fn import_buffer(&mut self, buffer: &'a wgpu::Buffer) -> BufferId {
+        BufferId(self.add_resource(Resource::Imported(Bound::Buffer(buffer)), None))
+    }
+
+    pub fn add_pass(&mut self, name: &'static str) -> PassBuilder<'_, 'a> {
+        self.passes.push(PassNode {
+            name,
+            reads: Vec::new(),
+            writes: Vec::new(),
+            execute: None,
+        });
+        let pass = self.passes.len() - 1;
+        PassBuilder { graph: self, pass }
+    }
+
+    // Records every pass that contributes to an imported resource. Transient textures come from the pool, which
+    // keeps them between frames and drops the ones a frame didn't use.
+    pub fn execute(self, device: &wgpu::Device, encoder: &mut wgpu::CommandEncoder, pool: &mut TexturePool) {
+        let order = self.schedule();
+
+        pool.begin_frame();
+        let mut allocations = vec![None; self.resources.len()];
+        for (resource, lifetime) in self.transient_lifetimes(&order) {
+            if let Resource::Transient { label, desc } = self.resources[resource].resource {
+                allocations[resource] = Some(pool.allocate(device, label, desc, lifetime));
+            }
+        }
+
+        let bound: Vec<Bound> = self
+            .resources
+            .iter()
+            .zip(&allocations)
+            .map(|(node, allocation)| match node.resource {
+                Resource::Transient { .. } => allocation.and_then(|index| pool.view(index)).map_or(Bound::Unused, Bound::Texture),
+                Resource::Imported(bound) => bound,
+            })
+            .collect();
+
+        let mut passes: Vec<Option<Execute>> = self.passes.into_iter().map(|pass| pass.execute).collect();
+        let mut context = PassContext { device, encoder, bound: &bound };
+        for pass in order {
+            if let Some(execute) = passes[pass].take() {
+                execute(&mut context);
+            }
+        }
+    }
+
+    // Where in the order each transient texture that is used is first and last used, sorted by the first use
+    fn transient_lifetimes(&self, order: &[usize]) -> Vec<(usize, (usize, usize))> {
+        let mut lifetimes: Vec<Option<(usize, usize)>> = vec![None; self.resources.len()];
+        for (position, &pass) in order.iter().enumerate() {
+            let node = &self.passes[pass];
+            for version in node.reads.iter().chain(&node.writes) {
+                let lifetime = &mut lifetimes[version.resource];
+                *lifetime = Some(lifetime.map_or((position, position), |(first, _)| (first, position)));
+            }
+        }
+        let mut transients: Vec<(usize, (usize, usize))> = lifetimes
+            .iter()
+            .enumerate()
+            .filter(|(resource, _)| matches!(self.resources[*resource].resource, Resource::Transient { .. }))
+            .filter_map(|(resource, lifetime)| lifetime.map(|lifetime| (resource, lifetime)))
+            .collect();
+        transients.sort_by_key(|(_, (first, _))| *first);
+        transients
+    }
+
+    fn add_resource(&mut self, resource: Resource<'a>, writer: Option<usize>) -> Version {
+        self.resources.push(ResourceNode {
+            resource,
+            versions: vec![VersionUse { writer, readers: Vec::new() }],
+        });
+        Version { resource: self.resources.len() - 1, version: 0 }
+    }
+
+    // The passes that have to run, sorted so every pass comes after the ones it depends on. Passes that don't
+    // depend on each other keep the order they were added in.
+    fn schedule(&self) -> Vec<usize> {
+        // The passes whose results a pass uses, and the passes that merely have to run before it
+        let mut dependencies = vec![Vec::new(); self.passes.len()];
+        let mut after = vec![Vec::new(); self.passes.len()];
+        for (pass, node) in self.passes.iter().enumerate() {
+            for read in &node.reads {
+                dependencies[pass].extend(self.resources[read.resource].versions[read.version].writer);
+            }
+            for write in node.writes.iter().filter(|write| write.version > 0) {
+                // Overwriting a resource has to wait until the previous version has been written and read
+                let previous = &self.resources[write.resource].versions[write.version - 1];
+                dependencies[pass].extend(previous.writer);
+                after[pass].extend(previous.readers.iter().copied().filter(|&reader| reader != pass));
+            }
+        }
+
+        // Everything an imported resource ends up depending on
+        let mut needed = vec![false; self.passes.len()];
+        let mut stack: Vec<usize> = (0..self.passes.len())
+            .filter(|&pass| {
+                self.passes[pass].writes.iter().any(|write| !matches!(self.resources[write.resource].resource, Resource::Transient { .. }))
+            })
+            .collect();
+        while let Some(pass) = stack.pop() {
+            if !std::mem::replace(&mut needed[pass], true) {
+                stack.extend(&dependencies[pass]);
+            }
+        }
+
+        let mut waiting_on = vec![0; self.passes.len()];
+        let mut dependents = vec![Vec::new(); self.passes.len()];
+        for pass in (0..self.passes.len()).filter(|&pass| needed[pass]) {
+            for &dependency in dependencies[pass].iter().chain(&after[pass]).filter(|&&dependency| needed[dependency]) {
+                waiting_on[pass] += 1;
+                dependents[dependency].push(pass);
+            }
+        }
+        let mut ready: BinaryHeap<Reverse<usize>> = (0..self.passes.len())
+            .filter(|&pass| needed[pass] && waiting_on[pass] == 0)
+            .map(Reverse)
+            .collect();
+        let mut order = Vec::new();
+        while let Some(Reverse(pass)) = ready.pop() {
+            order.push(pass);
+            for &dependent in &dependents[pass] {
+                waiting_on[dependent] -= 1;
+                if waiting_on[dependent] == 0 {
+                    ready.push(Reverse(dependent));
+                }
+            }
+        }
+        if let Some(pass) = (0..self.passes.len()).find(|&pass| needed[pass] && waiting_on[pass] > 0) {
+            panic!("Render graph pass {} is part of a dependency cycle", self.passes[pass].name);
+        }
+        order
+    }
+}
+
+// Declares what a pass reads and writes, and what it records once the graph runs
+pub struct PassBuilder<'g, 'a> {
+    graph: &'g mut RenderGraph<'a>,
+    pass: usize,
+}
+
+impl<'a> PassBuilder<'_, 'a> {
+    // A texture this pass writes first, so it has to clear it
+    pub fn create_texture(&mut self, label: &'static str, desc: TextureDesc) -> TextureId {
+        let version = self.graph.add_resource(Resource::Transient { label, desc }, Some(self.pass));
+        self.graph.passes[self.pass].writes.push(version);
+        TextureId(version)
+    }
+
+    pub fn read_texture(&mut self, texture: TextureId) -> TextureId {
+        self.read(texture.0);
+        texture
+    }
+
+    // Returns the version the following passes have to read to see what this one wrote
+    pub fn write_texture(&mut self, texture: TextureId) -> TextureId {
+        TextureId(self.write(texture.0))
+    }
+
+    pub fn read_buffer(&mut self, buffer: BufferId) -> BufferId {
+        self.read(buffer.0);
+        buffer
+    }
+
+    pub fn write_buffer(&mut self, buffer: BufferId) -> BufferId {
+        BufferId(self.write(buffer.0))
+    }
+
+    // Called with the command encoder once the pass' turn comes
+    pub fn execute(self, execute: impl FnOnce(&mut PassContext) + 'a) {
+        self.graph.passes[self.pass].execute = Some(Box::new(execute));
+    }
+
+    fn read(&mut self, version: Version) {
+        self.graph.resources[version.resource].versions[version.version].readers.push(self.pass);
+        self.graph.passes[self.pass].reads.push(version);
+    }
+
+    fn write(&mut self, version: Version) -> Version {
+        let versions = &mut self.graph.resources[version.resource].versions;
+        assert!(
+            version.version + 1 == versions.len(),
+            "Render graph pass {} writes an old version of a resource",
+            self.graph.passes[self.pass].name,
+        );
+        versions.push(VersionUse { writer: Some(self.pass), readers: Vec::new() });
+        let written = Version { resource: version.resource, version: version.version + 1 };
+        self.graph.passes[self.pass].writes.push(written);
+        written
+    }
+}
+
+#[derive(Copy, Clone)]
+enum Bound<'r> {
+    Texture(&'r wgpu::TextureView),
+    Buffer(&'r wgpu::Buffer),
+    // Transient textures of passes that were skipped
+    Unused,
+}
+
+// What a pass records its commands with
+pub struct PassContext<'r> {
+    pub device: &'r wgpu::Device,
+    pub encoder: &'r mut wgpu::CommandEncoder,
+    bound: &'r [Bound<'r>],
+}
+
+impl<'r> PassContext<'r> {
+    pub fn texture(&self, texture: TextureId) -> &'r wgpu::TextureView {
+        match self.bound[texture.0.resource] {
+            Bound::Texture(view) => view,
+            _ => unreachable!("texture ids only refer to textures of passes that run"),
+        }
+    }
+
+    pub fn buffer(&self, buffer: BufferId) -> &'r wgpu::Buffer {
+        match self.bound[buffer.0.resource] {
+            Bound::Buffer(buffer) => buffer,
+            _ => unreachable!("buffer ids only refer to buffers"),
+        }
+    }
+}
+
+struct PooledTexture {
+    desc: TextureDesc,
+    // The texture, kept alive for the view, and the view. None until allocate has created them.
+    created: Option<(wgpu::Texture, wgpu::TextureView)>,
+    // The position of the last pass using it this frame, None while it is unused
+    busy_until: Option<usize>,
+}
+
+// The transient textures of the render graph, kept between frames so they aren't created every frame
+#[derive(Default)]
+pub struct TexturePool {
+    textures: Vec<PooledTexture>,
+}
+
+impl TexturePool {
+    // Textures the last frame didn't use are left over from a different size or sample count
+    fn begin_frame(&mut self) {
+        self.textures.retain(|texture| texture.busy_until.is_some());
+        for texture in &mut self.textures {
+            texture.busy_until = None;
+        }
+    }
+
+    // Hands out a texture no other transient needs during the given passes
+    fn allocate(&mut self, device: &wgpu::Device, label: &str, desc: TextureDesc, lifetime: (usize, usize)) -> usize {
+        let index = self.reserve(desc, lifetime);
+        let pooled = &mut self.textures[index];
+        if pooled.created.is_none() {
+            let texture = device.create_texture(&wgpu::TextureDescriptor {
+                label: Some(label),
+                size: wgpu::Extent3d {
+                    width: desc.width,
+                    height: desc.height,
+                    depth_or_array_layers: 1,
+                },
+                mip_level_count: 1,
+                sample_count: desc.sample_count,
+                dimension: wgpu::TextureDimension::D2,
+                format: desc.format,
+                view_formats: &[],
+                usage: desc.usage,
+            });
+            let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+            pooled.created = Some((texture, view));
+        }
+        index
+    }
+
+    // The bookkeeping of allocate, without creating the texture. Reuses a texture with the same description whose
+    // last use is before the first of the given passes, or adds one.
+    fn reserve(&mut self, desc: TextureDesc, (first, last): (usize, usize)) -> usize {
+        let free = self.textures.iter().position(|texture| {
+            texture.desc == desc && texture.busy_until.is_none_or(|busy_until| busy_until < first)
+        });
+        let index = free.unwrap_or_else(|| {
+            self.textures.push(PooledTexture { desc, created: None, busy_until: None });
+            self.textures.len() - 1
+        });
+        self.textures[index].busy_until = Some(last);
+        index
+    }
+
+    fn view(&self, index: usize) -> Option<&wgpu::TextureView> {
+        self.textures[index].created.as_ref().map(|(_, view)| view)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Stands in for the frame or a buffer, which need a device to create
+    fn import(graph: &mut RenderGraph) -> TextureId {
+        TextureId(graph.add_resource(Resource::Imported(Bound::Unused), None))
+    }
+
+    fn desc(width: u32) -> TextureDesc {
+        TextureDesc {
+            width,
+            height: 4,
+            format: wgpu::TextureFormat::Rgba8Unorm,
+            sample_count: 1,
+            usage: wgpu::TextureUsages::RENDER_ATTACHMENT,
+        }
+    }
+
+    #[test]
+    fn reads_run_after_the_write_they_read() {
+        let mut graph = RenderGraph::default();
+        let frame = import(&mut graph);
+        let output = import(&mut graph);
+        let frame = graph.add_pass("clear").write_texture(frame);
+        let frame = graph.add_pass("draw").write_texture(frame);
+        let mut pass = graph.add_pass("post");
+        pass.read_texture(frame);
+        pass.write_texture(output);
+        assert_eq!(graph.schedule(), vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn writes_wait_for_the_reads_of_the_previous_version() {
+        let mut graph = RenderGraph::default();
+        let shadow_map = import(&mut graph);
+        let frame = import(&mut graph);
+        graph.add_pass("overwrite").write_texture(shadow_map);
+        // Added later but reads what was there before the overwrite
+        let mut pass = graph.add_pass("read_old");
+        pass.read_texture(shadow_map);
+        pass.write_texture(frame);
+        assert_eq!(graph.schedule(), vec![1, 0]);
+    }
+
+    #[test]
+    fn passes_nothing_imported_depends_on_are_skipped() {
+        let mut graph = RenderGraph::default();
+        let frame = import(&mut graph);
+        let mut pass = graph.add_pass("unused");
+        pass.create_texture("unused", desc(4));
+        let mut pass = graph.add_pass("scene");
+        let scene = pass.create_texture("scene", desc(4));
+        let mut pass = graph.add_pass("present");
+        pass.read_texture(scene);
+        pass.write_texture(frame);
+        assert_eq!(graph.schedule(), vec![1, 2]);
+    }
+
+    #[test]
+    #[should_panic(expected = "dependency cycle")]
+    fn cycles_are_detected() {
+        let mut graph = RenderGraph::default();
+        let texture = import(&mut graph);
+        let frame = import(&mut graph);
+        let new = graph.add_pass("overwrite").write_texture(texture);
+        // Has to run after the overwrite to read the new version, and before it to read the old one
+        let mut pass = graph.add_pass("read_both");
+        pass.read_texture(texture);
+        pass.read_texture(new);
+        pass.write_texture(frame);
+        graph.schedule();
+    }
+
+    #[test]
+    fn transients_live_from_their_first_to_their_last_use() {
+        let mut graph = RenderGraph::default();
+        let frame = import(&mut graph);
+        let first = graph.add_pass("first").create_texture("first", desc(4));
+        let mut pass = graph.add_pass("second");
+        pass.read_texture(first);
+        let second = pass.create_texture("second", desc(4));
+        let mut pass = graph.add_pass("third");
+        pass.read_texture(second);
+        pass.write_texture(frame);
+
+        let order = graph.schedule();
+        assert_eq!(graph.transient_lifetimes(&order), vec![(first.0.resource, (0, 1)), (second.0.resource, (1, 2))]);
+    }
+
+    #[test]
+    fn identical_textures_are_shared_when_their_lifetimes_dont_overlap() {
+        let mut pool = TexturePool::default();
+        pool.begin_frame();
+        let first = pool.reserve(desc(4), (0, 1));
+        // Both are used by pass 1
+        let overlapping = pool.reserve(desc(4), (1, 2));
+        assert_ne!(first, overlapping);
+        assert_eq!(pool.reserve(desc(4), (2, 3)), first);
+        // Only textures with the same description are shared
+        assert_eq!(pool.reserve(desc(8), (4, 5)), 2);
+
+        // Kept for the next frame
+        pool.begin_frame();
+        assert_eq!(pool.reserve(desc(4), (0, 0)), first);
+        assert_eq!(pool.textures.len(), 3);
+    }
+}
@@ -0,0 +1,190 @@
+use anyhow::{bail, Context, Result};
+use legion::{component, Entity, EntityStore, IntoQuery, World};
+
+use crate::transform::{GlobalTransform, LocalTransform};
+
+// The entity this one is attached to. Its LocalTransform is then relative to the parent's GlobalTransform.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Parent(pub Entity);
+
+// The entities attached to this one, kept in sync with their Parent components by set_parent and remove_parent
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Children(pub Vec<Entity>);
+
+// Attaches child to parent, detaching it from its previous parent first. Fails if either entity doesn't exist
+// or if parent is child itself or one of its descendants, which would make the hierarchy a cycle.
+pub fn set_parent(world: &mut World, child: Entity, parent: Entity) -> Result<()> {
+    let mut ancestor = Some(parent);
+    while let Some(entity) = ancestor {
+        if entity == child {
+            bail!("Can't attach {:?} to {:?}, it is one of its ancestors", child, parent);
+        }
+        let entry = world.entry_ref(entity);
+        if entry.is_err() && entity == parent {
+            bail!("Parent {:?} doesn't exist", parent);
+        }
+        // The chain ends at an ancestor that was despawned, like it does for roots
+        ancestor = entry.ok().and_then(|entry| entry.get_component::<Parent>().ok().map(|parent| parent.0));
+    }
+
+    remove_parent(world, child)?;
+    world.entry(child).context("Child doesn't exist")?.add_component(Parent(parent));
+    let mut parent_entry = world.entry(parent).context("Parent doesn't exist")?;
+    if let Ok(children) = parent_entry.get_component_mut::<Children>() {
+        children.0.push(child);
+    } else {
+        parent_entry.add_component(Children(vec![child]));
+    }
+    Ok(())
+}
+
+// Detaches child from its parent, if it has one, leaving it placed relative to the world
+pub fn remove_parent(world: &mut World, child: Entity) -> Result<()> {
+    let mut child_entry = world.entry(child).context("Child doesn't exist")?;
+    let Ok(&Parent(parent)) = child_entry.get_component::<Parent>() else {
+        return Ok(());
+    };
+    child_entry.remove_component::<Parent>();
+    if let Some(mut parent_entry) = world.entry(parent) {
+        if let Ok(children) = parent_entry.get_component_mut::<Children>() {
+            children.0.retain(|&entity| entity != child);
+        }
+    }
+    Ok(())
+}
+
+// Computes the GlobalTransform of every entity with a LocalTransform, parents before their children.
+// Entities whose parent was despawned or has no LocalTransform are treated as roots.
+// Call after moving things and before Renderer::update. Entities that don't have a GlobalTransform yet get one.
+pub fn propagate_transforms(world: &mut World) {
+    let missing: Vec<Entity> = <Entity>::query()
+        .filter(component::<LocalTransform>() & !component::<GlobalTransform>())
+        .iter(world)
+        .copied()
+        .collect();
+    for entity in missing {
+        if let Some(mut entry) = world.entry(entity) {
+            entry.add_component(GlobalTransform::default());
+        }
+    }
+
+    // A parent that was removed from the world, or has no transform to pass on, leaves its children placed relative
+    // to the world
+    let mut pending: Vec<(Entity, cgmath::Matrix4<f32>)> = <(Entity, &LocalTransform, Option<&Parent>)>::query()
+        .iter(world)
+        .filter(|(_, _, parent)| parent.is_none_or(|parent| {
+            world.entry_ref(parent.0).map_or(true, |parent| parent.get_component::<LocalTransform>().is_err())
+        }))
+        .map(|(entity, local, _)| (*entity, local.matrix()))
+        .collect();
+    while let Some((entity, matrix)) = pending.pop() {
+        let Some(mut entry) = world.entry(entity) else {
+            continue;
+        };
+        // Writing marks the transform as changed for the renderer, so leave the ones that didn't move alone
+        if entry.get_component::<GlobalTransform>().is_ok_and(|global| global.0 != matrix) {
+            if let Ok(global) = entry.get_component_mut::<GlobalTransform>() {
+                global.0 = matrix;
+            }
+        }
+        let Ok(Children(children)) = entry.get_component::<Children>().cloned() else {
+            continue;
+        };
+        for child in children {
+            // Children without a transform of their own are skipped, their children are roots
+            let local = world.entry_ref(child).ok().and_then(|child| child.get_component::<LocalTransform>().ok().copied());
+            if let Some(local) = local {
+                pending.push((child, matrix * local.matrix()));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cgmath::{One, Quaternion, Vector3};
+
+    fn spawn(world: &mut World, x: f32) -> Entity {
+        world.push((LocalTransform::new(Vector3::new(x, 0.0, 0.0), Quaternion::one()),))
+    }
+
+    fn global_x(world: &World, entity: Entity) -> f32 {
+        world.entry_ref(entity).unwrap().get_component::<GlobalTransform>().unwrap().0.w.x
+    }
+
+    fn children(world: &World, entity: Entity) -> Vec<Entity> {
+        world.entry_ref(entity).unwrap().get_component::<Children>().map(|children| children.0.clone()).unwrap_or_default()
+    }
+
+    #[test]
+    fn children_are_placed_relative_to_their_parents() {
+        let mut world = World::default();
+        let parent = spawn(&mut world, 1.0);
+        let child = spawn(&mut world, 2.0);
+        let grandchild = spawn(&mut world, 4.0);
+        set_parent(&mut world, child, parent).unwrap();
+        set_parent(&mut world, grandchild, child).unwrap();
+        propagate_transforms(&mut world);
+        assert!((global_x(&world, grandchild) - 7.0).abs() < 1e-5);
+    }
+
+    #[test]
+    fn reparenting_moves_the_child_to_the_new_parent() {
+        let mut world = World::default();
+        let first = spawn(&mut world, 1.0);
+        let second = spawn(&mut world, 10.0);
+        let child = spawn(&mut world, 2.0);
+        set_parent(&mut world, child, first).unwrap();
+        set_parent(&mut world, child, second).unwrap();
+        propagate_transforms(&mut world);
+
+        assert!(children(&world, first).is_empty());
+        assert_eq!(children(&world, second), vec![child]);
+        assert_eq!(world.entry_ref(child).unwrap().get_component::<Parent>().ok(), Some(&Parent(second)));
+        assert!((global_x(&world, child) - 12.0).abs() < 1e-5);
+    }
+
+    #[test]
+    fn cycles_are_rejected() {
+        let mut world = World::default();
+        let parent = spawn(&mut world, 0.0);
+        let child = spawn(&mut world, 0.0);
+        let grandchild = spawn(&mut world, 0.0);
+        set_parent(&mut world, child, parent).unwrap();
+        set_parent(&mut world, grandchild, child).unwrap();
+
+        assert!(set_parent(&mut world, parent, grandchild).is_err());
+        assert!(set_parent(&mut world, parent, parent).is_err());
+        // The hierarchy is left as it was
+        assert!(world.entry_ref(parent).unwrap().get_component::<Parent>().is_err());
+        assert_eq!(children(&world, grandchild), Vec::new());
+    }
+
+    #[test]
+    fn children_of_a_despawned_parent_become_roots() {
+        let mut world = World::default();
+        let parent = spawn(&mut world, 1.0);
+        let child = spawn(&mut world, 2.0);
+        set_parent(&mut world, child, parent).unwrap();
+        world.remove(parent);
+        propagate_transforms(&mut world);
+        assert!((global_x(&world, child) - 2.0).abs() < 1e-5);
+
+        // Attaching it elsewhere still works
+        let other = spawn(&mut world, 5.0);
+        set_parent(&mut world, child, other).unwrap();
+        propagate_transforms(&mut world);
+        assert!((global_x(&world, child) - 7.0).abs() < 1e-5);
+    }
+
+    #[test]
+    fn children_of_a_parent_without_a_transform_become_roots() {
+        let mut world = World::default();
+        let parent = world.push(((),));
+        let child = spawn(&mut world, 2.0);
+        set_parent(&mut world, child, parent).unwrap();
+        propagate_transforms(&mut world);
+        assert!((global_x(&world, child) - 2.0).abs() < 1e-5);
+    }
+}
@@ -0,0 +1,307 @@
+use std::{collections::{HashMap, HashSet}, ops::Range};
+
+use legion::{
+    maybe_changed,
+    query::{And, Any, ComponentChangedFilter, ComponentFilter, EntityFilterTuple, Passthrough, Query, TryComponentFilter},
+    Entity, IntoQuery, World,
+};
+
+use crate::{asset::{AssetServer, Handle}, culling::{CullingStats, Frustum}, model::{Draw, Model}, transform::{self, GlobalTransform}};
+
+// Capacity of a batch's first buffer, so small batches don't reallocate while entities are being added
+const MIN_BATCH_CAPACITY: usize = 16;
+#[allow(clippy::cast_possible_truncation)]
+const INSTANCE_SIZE: u64 = std::mem::size_of::<transform::Raw>() as u64;
+
+// What <(Entity, &GlobalTransform)>::query().filter(maybe_changed::<GlobalTransform>()) returns
+type ChangedTransforms = Query<
+    (Entity, &'static GlobalTransform),
+    EntityFilterTuple<
+        And<(Any, ComponentFilter<GlobalTransform>, TryComponentFilter<GlobalTransform>)>,
+        And<(Passthrough, Passthrough, ComponentChangedFilter<GlobalTransform>)>,
+    >,
+>;
+
+// The instance data of every entity drawn with one model, so the model can be drawn with a single call
+pub struct Batch {
+    pub buffer: wgpu::Buffer,
+    // Number of instances the buffer has room for
+    capacity: usize,
+    pub count: u32,
+    // What the buffer holds, and which entity each instance belongs to
+    instances: Vec<transform::Raw>,
+    entities: Vec<Entity>,
+    // Instances that changed since the last upload
+    dirty: Option<Range<usize>>,
+    // The instances inside the view frustum packed together, once for every mesh of the model, set by cull
+    visible_buffer: wgpu::Buffer,
+    visible_capacity: usize,
+    visible_instances: Vec<transform::Raw>,
+    // What was last written to the visible buffer, so only the instances that changed since are written again
+    uploaded_visible_instances: Vec<transform::Raw>,
+    // Where each mesh's visible instances are in the visible buffer
+    visible: Vec<Range<u32>>,
+}
+
+impl Batch {
+    fn new(device: &wgpu::Device) -> Self {
+        Self {
+            buffer: create_instance_buffer(device, MIN_BATCH_CAPACITY),
+            capacity: MIN_BATCH_CAPACITY,
+            count: 0,
+            instances: Vec::new(),
+            entities: Vec::new(),
+            dirty: None,
+            visible_buffer: create_instance_buffer(device, MIN_BATCH_CAPACITY),
+            visible_capacity: MIN_BATCH_CAPACITY,
+            visible_instances: Vec::new(),
+            uploaded_visible_instances: Vec::new(),
+            visible: Vec::new(),
+        }
+    }
+
+    fn mark_dirty(&mut self, index: usize) {
+        mark_dirty(&mut self.dirty, index);
+    }
+
+    // Writes the changed instances to the buffer, growing it geometrically when it is full
+    #[allow(clippy::cast_possible_truncation)]
+    fn upload(&mut self, device: &wgpu::Device, queue: &wgpu::Queue) {
+        if self.instances.len() > self.capacity {
+            self.capacity = self.instances.len().next_power_of_two();
+            self.buffer = create_instance_buffer(device, self.capacity);
+            self.dirty = Some(0..self.instances.len());
+        }
+        if let Some(dirty) = take_dirty(&mut self.dirty, self.instances.len()) {
+            queue.write_buffer(&self.buffer, INSTANCE_SIZE * dirty.start as u64, bytemuck::cast_slice(&self.instances[dirty]));
+        }
+        self.count = self.instances.len() as u32;
+    }
+
+    // Packs the instances each mesh of the model has inside the frustum into the visible buffer, so every mesh is
+    // drawn with a single call. Instances whose whole model is out of view aren't tested mesh by mesh.
+    #[allow(clippy::cast_possible_truncation)]
+    fn cull(&mut self, device: &wgpu::Device, queue: &wgpu::Queue, frustum: &Frustum, model: &Model, stats: &mut CullingStats) {
+        let in_view: Vec<&transform::Raw> = self.instances.iter()
+            .filter(|instance| model.bounds.is_none_or(|bounds| {
+                frustum.intersects(&bounds.transformed(&cgmath::Matrix4::from(instance.model)))
+            }))
+            .collect();
+        stats.drawn += in_view.len() as u32;
+        stats.culled += (self.instances.len() - in_view.len()) as u32;
+
+        self.visible_instances.clear();
+        self.visible.clear();
+        for mesh in &model.meshes {
+            let start = self.visible_instances.len() as u32;
+            // The bounds of a model's only mesh are the model's, which the instances already passed
+            let mesh_bounds = mesh.bounds.filter(|_| model.meshes.len() > 1);
+            self.visible_instances.extend(in_view.iter().copied().filter(|instance| mesh_bounds.is_none_or(|bounds| {
+                frustum.intersects(&bounds.transformed(&cgmath::Matrix4::from(instance.model)))
+            })));
+            self.visible.push(start..self.visible_instances.len() as u32);
+        }
+
+        let grown = self.visible_instances.len() > self.visible_capacity;
+        if grown {
+            self.visible_capacity = self.visible_instances.len().next_power_of_two();
+            self.visible_buffer = create_instance_buffer(device, self.visible_capacity);
+        }
+        // The packing order is stable, so while the same instances stay in view only the ones that moved are written
+        let changed = if grown || self.visible_instances.len() != self.uploaded_visible_instances.len() {
+            Some(0..self.visible_instances.len())
+        } else {
+            changed_range(&self.visible_instances, &self.uploaded_visible_instances)
+        };
+        if let Some(changed) = changed.filter(|changed| !changed.is_empty()) {
+            queue.write_buffer(
+                &self.visible_buffer,
+                INSTANCE_SIZE * changed.start as u64,
+                bytemuck::cast_slice(&self.visible_instances[changed]),
+            );
+        }
+        std::mem::swap(&mut self.visible_instances, &mut self.uploaded_visible_instances);
+    }
+
+    // Draws the instances cull found inside the frustum, with one draw call per mesh of the model
+    pub fn draw_visible<'a>(
+        &'a self,
+        render_pass: &mut wgpu::RenderPass<'a>,
+        model: &'a Model,
+        camera: &'a wgpu::BindGroup,
+        light: &'a wgpu::BindGroup,
+    ) {
+        // The model may have finished loading after the last cull, its meshes are drawn from the next one on
+        if self.visible.len() != model.meshes.len() {
+            return;
+        }
+        for (mesh, instances) in model.meshes.iter().zip(&self.visible) {
+            if instances.is_empty() {
+                continue;
+            }
+            // Each mesh's instances start at the front of its slice, so the draw doesn't need a first instance
+            render_pass.set_vertex_buffer(1, self.visible_buffer.slice(INSTANCE_SIZE * u64::from(instances.start)..INSTANCE_SIZE * u64::from(instances.end)));
+            render_pass.draw_mesh_instanced(mesh, &model.materials[mesh.material], 0..instances.end - instances.start, camera, light);
+        }
+    }
+}
+
+// Packs the transforms of all renderables into one instance buffer per model. Every entity keeps its place in
+// the buffer, so only the instances of entities that were added or moved need to be uploaded.
+pub struct InstanceBatches {
+    batches: HashMap<Handle<Model>, Batch>,
+    // The batch and index of every renderable's instance
+    slots: HashMap<Entity, (Handle<Model>, usize)>,
+    // Reused every update to find the renderables that were removed from the world
+    seen: HashSet<Entity>,
+    // Kept between updates, the change filter remembers what it has already seen
+    changed_transforms: ChangedTransforms,
+}
+
+impl Default for InstanceBatches {
+    fn default() -> Self {
+        Self {
+            batches: HashMap::new(),
+            slots: HashMap::new(),
+            seen: HashSet::new(),
+            changed_transforms: <(Entity, &GlobalTransform)>::query().filter(maybe_changed::<GlobalTransform>()),
+        }
+    }
+}
+
+impl InstanceBatches {
+    // Brings the instance buffers up to date with the renderables in the world
+    pub fn update(&mut self, device: &wgpu::Device, queue: &wgpu::Queue, world: &World) {
+        self.seen.clear();
+        let mut renderables = <(Entity, &GlobalTransform, &Handle<Model>)>::query();
+        for (entity, transform, model) in renderables.iter(world) {
+            self.seen.insert(*entity);
+            match self.slots.get(entity) {
+                Some((current, _)) if current == model => {}
+                Some(_) => {
+                    self.remove(*entity);
+                    self.insert(device, *entity, *model, transform.to_raw());
+                }
+                None => self.insert(device, *entity, *model, transform.to_raw()),
+            }
+        }
+        let removed: Vec<Entity> = self.slots.keys().filter(|entity| !self.seen.contains(entity)).copied().collect();
+        for entity in removed {
+            self.remove(entity);
+        }
+
+        // Legion tracks writes per archetype, so this skips every archetype whose transforms weren't touched.
+        // The instances of the ones that were are compared, so entities that didn't actually move aren't uploaded.
+        for (entity, transform) in self.changed_transforms.iter(world) {
+            let Some((model, index)) = self.slots.get(entity) else {
+                continue;
+            };
+            let Some(batch) = self.batches.get_mut(model) else {
+                continue;
+            };
+            let raw = transform.to_raw();
+            if bytemuck::bytes_of(&raw) != bytemuck::bytes_of(&batch.instances[*index]) {
+                batch.instances[*index] = raw;
+                batch.mark_dirty(*index);
+            }
+        }
+
+        for batch in self.batches.values_mut() {
+            batch.upload(device, queue);
+        }
+    }
+
+    // Finds the instances whose meshes are at least partly inside the frustum, see Batch::draw_visible.
+    // An instance counts as drawn if any of its meshes is.
+    pub fn cull(&mut self, device: &wgpu::Device, queue: &wgpu::Queue, frustum: &Frustum, assets: &AssetServer) -> CullingStats {
+        let mut stats = CullingStats::default();
+        for (model, batch) in &mut self.batches {
+            batch.cull(device, queue, frustum, assets.model(*model), &mut stats);
+        }
+        stats
+    }
+
+    // The batches with at least one instance, in no particular order
+    pub fn iter(&self) -> impl Iterator<Item = (Handle<Model>, &Batch)> {
+        self.batches.iter().filter(|(_, batch)| batch.count > 0).map(|(model, batch)| (*model, batch))
+    }
+
+    fn insert(&mut self, device: &wgpu::Device, entity: Entity, model: Handle<Model>, raw: transform::Raw) {
+        let batch = self.batches.entry(model).or_insert_with(|| Batch::new(device));
+        let index = batch.instances.len();
+        batch.instances.push(raw);
+        batch.entities.push(entity);
+        batch.mark_dirty(index);
+        self.slots.insert(entity, (model, index));
+    }
+
+    // Moves the batch's last instance into the removed one's place to keep the instances packed
+    fn remove(&mut self, entity: Entity) {
+        let Some((model, index)) = self.slots.remove(&entity) else {
+            return;
+        };
+        let Some(batch) = self.batches.get_mut(&model) else {
+            return;
+        };
+        batch.instances.swap_remove(index);
+        batch.entities.swap_remove(index);
+        if let Some(moved) = batch.entities.get(index) {
+            self.slots.insert(*moved, (model, index));
+            batch.mark_dirty(index);
+        }
+    }
+}
+
+fn create_instance_buffer(device: &wgpu::Device, capacity: usize) -> wgpu::Buffer {
+    device.create_buffer(&wgpu::BufferDescriptor {
+        label: Some("Instance Buffer"),
+        size: INSTANCE_SIZE * capacity as u64,
+        usage: wgpu::BufferUsages::VERTEX | wgpu::BufferUsages::COPY_DST,
+        mapped_at_creation: false,
+    })
+}
+
+// The range from the first to the last instance that differs between two arrays of the same length
+fn changed_range(new: &[transform::Raw], old: &[transform::Raw]) -> Option<Range<usize>> {
+    let differs = |(new, old): (&transform::Raw, &transform::Raw)| bytemuck::bytes_of(new) != bytemuck::bytes_of(old);
+    let start = new.iter().zip(old).position(differs)?;
+    let end = new.len() - new.iter().zip(old).rev().position(differs)?;
+    Some(start..end)
+}
+
+// Grows the range of slots that need uploading to include index. Shared with the light list, which keeps its
+// slots the same way.
+pub fn mark_dirty(dirty: &mut Option<Range<usize>>, index: usize) {
+    *dirty = Some(dirty.as_ref().map_or(index..index + 1, |dirty| dirty.start.min(index)..dirty.end.max(index + 1)));
+}
+
+// Takes the slots that need uploading. Slots at the end may have been removed after they were marked, so the range
+// is cut to len.
+pub fn take_dirty(dirty: &mut Option<Range<usize>>, len: usize) -> Option<Range<usize>> {
+    let dirty = dirty.take()?;
+    let dirty = dirty.start..dirty.end.min(len);
+    (!dirty.is_empty()).then_some(dirty)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn instance(x: f32) -> transform::Raw {
+        GlobalTransform(cgmath::Matrix4::from_translation(cgmath::Vector3::new(x, 0.0, 0.0))).to_raw()
+    }
+
+    #[test]
+    fn changed_range_spans_the_first_to_the_last_difference() {
+        let old: Vec<_> = (0..6u8).map(|x| instance(f32::from(x))).collect();
+        assert_eq!(changed_range(&old, &old), None);
+
+        let mut new = old.clone();
+        new[3] = instance(10.0);
+        assert_eq!(changed_range(&new, &old), Some(3..4));
+
+        new[1] = instance(11.0);
+        new[4] = instance(12.0);
+        assert_eq!(changed_range(&new, &old), Some(1..5));
+    }
+}
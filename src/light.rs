@@ -1,45 +1,219 @@
+use std::{collections::{HashMap, HashSet}, ops::Range};
+
+use cgmath::{EuclideanSpace, InnerSpace, Point3, Rad, Transform, Vector3};
+use legion::{
+    maybe_changed,
+    query::{And, Any, ComponentChangedFilter, ComponentFilter, EntityFilterTuple, Or, Passthrough, Query, TryComponentFilter},
+    Entity, IntoQuery, World,
+};
 use wgpu::{Device};
 
+use crate::{instance::{mark_dirty, take_dirty}, transform::GlobalTransform};
+
+// Values of Raw::kind, must match the LIGHT_* constants in the shaders
+pub const KIND_DIRECTIONAL: u32 = 0;
+pub const KIND_POINT: u32 = 1;
+pub const KIND_SPOT: u32 = 2;
+
+// Lights fade out to nothing at their range, so it has to be above zero
+const MIN_RANGE: f32 = 0.01;
+// A spot light's shadow map is a perspective projection covering its cone, which can't open up to 180°
+const MAX_SPOT_ANGLE: Rad<f32> = Rad(89.0_f32.to_radians());
+// Directions shorter than this can't be normalized, the light points straight down instead
+const MIN_DIRECTION_LENGTH: f32 = 1e-6;
+
+// What <(Entity, &Light, Option<&GlobalTransform>)>::query() filtered to changed lights or transforms returns
+type ChangedLights = Query<
+    (Entity, &'static Light, Option<&'static GlobalTransform>),
+    EntityFilterTuple<
+        And<(Any, ComponentFilter<Light>, TryComponentFilter<GlobalTransform>, Or<(TryComponentFilter<Light>, TryComponentFilter<GlobalTransform>)>)>,
+        And<(Passthrough, Passthrough, Passthrough, Or<(ComponentChangedFilter<Light>, ComponentChangedFilter<GlobalTransform>)>)>,
+    >,
+>;
 
 #[repr(C)]
 #[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
 pub struct Raw {
     pub position: [f32; 3],
-    pub _padding: u32,
+    pub kind: u32,
     pub color: [f32; 3],
-    pub _padding2: u32,
+    pub intensity: f32,
+    pub direction: [f32; 3],
+    pub range: f32,
+    // Cosines of the spot cone angles, so the shader can compare them with a dot product directly
+    pub inner_cos: f32,
+    pub outer_cos: f32,
+    pub _padding: [u32; 2],
+}
+
+#[derive(Debug, Copy, Clone)]
+pub enum LightKind {
+    // Infinitely far away, like the sun, so only the direction the light travels in matters
+    Directional {
+        direction: Vector3<f32>,
+    },
+    // Shines in all directions, fading out to nothing at range
+    Point {
+        range: f32,
+    },
+    // Shines in a cone, full strength inside the inner angle and fading out towards the outer angle
+    Spot {
+        direction: Vector3<f32>,
+        range: f32,
+        inner_angle: Rad<f32>,
+        outer_angle: Rad<f32>,
+    },
+}
+
+// Lighting that applies to the whole scene rather than coming from a single light
+#[derive(Debug, Copy, Clone)]
+pub struct SceneLighting {
+    // Light reaching every surface regardless of the lights, so unlit areas aren't pitch black
+    pub ambient_color: [f32; 3],
+    pub ambient_strength: f32,
+}
+
+impl Default for SceneLighting {
+    fn default() -> Self {
+        Self {
+            ambient_color: [1.0, 1.0, 1.0],
+            ambient_strength: 0.1,
+        }
+    }
+}
+
+impl SceneLighting {
+    // The light buffer can be larger than the number of lights in it, so the shader gets the count from here
+    pub fn to_raw(self, light_count: u32) -> SceneLightingRaw {
+        SceneLightingRaw {
+            ambient: self.ambient_color.map(|channel| channel * self.ambient_strength),
+            light_count,
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct SceneLightingRaw {
+    pub ambient: [f32; 3],
+    pub light_count: u32,
 }
 
 pub struct Light {
+    // Ignored when lighting with a directional light, but still used to place its debug model
     pub position: cgmath::Vector3<f32>,
     color: [f32; 3],
+    pub kind: LightKind,
+    pub intensity: f32,
+}
+
+fn normalize_direction(direction: Vector3<f32>) -> Vector3<f32> {
+    if direction.magnitude() < MIN_DIRECTION_LENGTH {
+        -Vector3::unit_y()
+    } else {
+        direction.normalize()
+    }
 }
 
 impl Light {
-    pub fn new(position: cgmath::Vector3<f32>, color: [f32; 3]) -> Self {
+    // The range is kept above zero
+    pub const fn point(position: cgmath::Vector3<f32>, color: [f32; 3], range: f32) -> Self {
+        Self {
+            position,
+            color,
+            kind: LightKind::Point { range: range.max(MIN_RANGE) },
+            intensity: 1.0,
+        }
+    }
+
+    // A zero direction points the light straight down
+    pub fn directional(direction: cgmath::Vector3<f32>, color: [f32; 3]) -> Self {
+        let direction = normalize_direction(direction);
+        Self {
+            position: -direction * 10.0,
+            color,
+            kind: LightKind::Directional { direction },
+            intensity: 1.0,
+        }
+    }
 
+    // The angles are measured from the direction to the edge of the cone. The outer angle is clamped to below 90°,
+    // the inner angle to at most the outer one, and the range is kept above zero. A zero direction points it straight down.
+    pub fn spot<A: Into<Rad<f32>>>(
+        position: cgmath::Vector3<f32>,
+        direction: cgmath::Vector3<f32>,
+        color: [f32; 3],
+        range: f32,
+        inner_angle: A,
+        outer_angle: A,
+    ) -> Self {
+        let outer_angle = Rad(outer_angle.into().0.clamp(0.0, MAX_SPOT_ANGLE.0));
+        let inner_angle = Rad(inner_angle.into().0.clamp(0.0, outer_angle.0));
         Self {
             position,
-            color, 
+            color,
+            kind: LightKind::Spot {
+                direction: normalize_direction(direction),
+                range: range.max(MIN_RANGE),
+                inner_angle,
+                outer_angle,
+            },
+            intensity: 1.0,
         }
     }
 
+    pub const fn with_intensity(mut self, intensity: f32) -> Self {
+        self.intensity = intensity;
+        self
+    }
+
     pub fn to_raw(&self) -> Raw {
-        Raw::new(self.position, self.color)
+        Raw::new(self.position, self.color, self.kind, self.intensity)
+    }
+
+    // Lights on entities with a GlobalTransform move along with them, their position and direction are relative to it
+    pub fn to_raw_transformed(&self, transform: &GlobalTransform) -> Raw {
+        let matrix = transform.0;
+        let position = matrix.transform_point(Point3::from_vec(self.position)).to_vec();
+        let kind = match self.kind {
+            LightKind::Directional { direction } => LightKind::Directional {
+                direction: matrix.transform_vector(direction).normalize(),
+            },
+            LightKind::Spot { direction, range, inner_angle, outer_angle } => LightKind::Spot {
+                direction: matrix.transform_vector(direction).normalize(),
+                range,
+                inner_angle,
+                outer_angle,
+            },
+            point @ LightKind::Point { .. } => point,
+        };
+        Raw::new(position, self.color, kind, self.intensity)
     }
 
-    
 
-    
+
+
 }
 
 impl Raw {
-    fn new(position: cgmath::Vector3<f32>, color: [f32; 3]) -> Self {
+    fn new(position: cgmath::Vector3<f32>, color: [f32; 3], kind: LightKind, intensity: f32) -> Self {
+        let (kind, direction, range, inner_cos, outer_cos) = match kind {
+            LightKind::Directional { direction } => (KIND_DIRECTIONAL, direction, 0.0, 0.0, 0.0),
+            LightKind::Point { range } => (KIND_POINT, Vector3::unit_y(), range, 0.0, 0.0),
+            LightKind::Spot { direction, range, inner_angle, outer_angle } => {
+                (KIND_SPOT, direction, range, inner_angle.0.cos(), outer_angle.0.cos())
+            }
+        };
         Self {
             position: position.into(),
-            _padding: 0,
+            kind,
             color,
-            _padding2: 0,
+            intensity,
+            direction: direction.into(),
+            range,
+            inner_cos,
+            outer_cos,
+            _padding: [0; 2],
         }
     }
 
@@ -56,11 +230,162 @@ impl Raw {
                     },
                     count: None,
                 },
+                // shadow map view projections
+                wgpu::BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Storage { read_only: true },
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                // shadow maps
+                wgpu::BindGroupLayoutEntry {
+                    binding: 2,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Texture {
+                        multisampled: false,
+                        sample_type: wgpu::TextureSampleType::Depth,
+                        view_dimension: wgpu::TextureViewDimension::D2Array,
+                    },
+                    count: None,
+                },
+                // shadow map sampler
+                wgpu::BindGroupLayoutEntry {
+                    binding: 3,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Comparison),
+                    count: None,
+                },
+                // scene lighting
+                wgpu::BindGroupLayoutEntry {
+                    binding: 4,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
             ],
             label: None,
-        
+
         })
     }
 
 }
 
+// The GPU data of every light in the world, in the order of the light buffer. Every light keeps its slot while it
+// exists, so only the slots of lights that were added, changed or moved need to be written to the buffer.
+pub struct LightList {
+    lights: Vec<Raw>,
+    // Which entity each slot belongs to
+    entities: Vec<Entity>,
+    slots: HashMap<Entity, usize>,
+    // Reused every update to find the lights that were removed from the world
+    seen: HashSet<Entity>,
+    // Slots that changed since take_dirty was last called
+    dirty: Option<Range<usize>>,
+    // Kept between updates, the change filter remembers what it has already seen
+    changed_lights: ChangedLights,
+}
+
+impl Default for LightList {
+    fn default() -> Self {
+        Self {
+            lights: Vec::new(),
+            entities: Vec::new(),
+            slots: HashMap::new(),
+            seen: HashSet::new(),
+            dirty: None,
+            changed_lights: <(Entity, &Light, Option<&GlobalTransform>)>::query()
+                .filter(maybe_changed::<Light>() | maybe_changed::<GlobalTransform>()),
+        }
+    }
+}
+
+impl LightList {
+    // Brings the list up to date with the lights in the world, returning false if nothing changed
+    pub fn update(&mut self, world: &World) -> bool {
+        let mut changed = false;
+        self.seen.clear();
+        for (entity, light, transform) in <(Entity, &Light, Option<&GlobalTransform>)>::query().iter(world) {
+            self.seen.insert(*entity);
+            if !self.slots.contains_key(entity) {
+                self.slots.insert(*entity, self.lights.len());
+                self.lights.push(to_raw(light, transform));
+                self.entities.push(*entity);
+                mark_dirty(&mut self.dirty, self.lights.len() - 1);
+                changed = true;
+            }
+        }
+        let removed: Vec<Entity> = self.slots.keys().filter(|entity| !self.seen.contains(entity)).copied().collect();
+        for entity in removed {
+            self.remove(entity);
+            changed = true;
+        }
+
+        // Legion tracks writes per archetype, so this skips every archetype whose lights and transforms weren't
+        // touched. The lights of the ones that were are compared, so lights that didn't actually change aren't written.
+        for (entity, light, transform) in self.changed_lights.iter(world) {
+            let Some(&index) = self.slots.get(entity) else {
+                continue;
+            };
+            let raw = to_raw(light, transform);
+            if bytemuck::bytes_of(&raw) != bytemuck::bytes_of(&self.lights[index]) {
+                self.lights[index] = raw;
+                mark_dirty(&mut self.dirty, index);
+                changed = true;
+            }
+        }
+        changed
+    }
+
+    // Moves the last light into the removed one's slot
+    fn remove(&mut self, entity: Entity) {
+        let Some(index) = self.slots.remove(&entity) else {
+            return;
+        };
+        self.lights.swap_remove(index);
+        self.entities.swap_remove(index);
+        if let Some(&moved) = self.entities.get(index) {
+            self.slots.insert(moved, index);
+            mark_dirty(&mut self.dirty, index);
+        }
+    }
+
+    // The slots that changed since the last call, the lights at the end may have been removed since they were marked
+    pub fn take_dirty(&mut self) -> Option<Range<usize>> {
+        take_dirty(&mut self.dirty, self.lights.len())
+    }
+
+    pub fn lights(&self) -> &[Raw] {
+        &self.lights
+    }
+
+    pub const fn len(&self) -> usize {
+        self.lights.len()
+    }
+}
+
+fn to_raw(light: &Light, transform: Option<&GlobalTransform>) -> Raw {
+    transform.map_or_else(|| light.to_raw(), |transform| light.to_raw_transformed(transform))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn zero_directions_point_down() {
+        let directional = Light::directional(Vector3::new(0.0, 0.0, 0.0), [1.0; 3]);
+        assert!(matches!(directional.kind, LightKind::Directional { direction } if direction == -Vector3::unit_y()));
+        assert!(directional.position.y > 0.0);
+
+        let spot = Light::spot(Vector3::new(0.0, 0.0, 0.0), Vector3::new(0.0, 0.0, 0.0), [1.0; 3], 5.0, Rad(0.2), Rad(0.4));
+        assert!(matches!(spot.kind, LightKind::Spot { direction, .. } if direction == -Vector3::unit_y()));
+    }
+}
@@ -10,15 +10,24 @@ mod model;
 mod transform;
 mod light;
 mod renderer;
+mod capture;
+mod shadow;
+mod asset;
+mod instance;
+mod hierarchy;
+mod culling;
+mod config;
+mod post;
+mod graph;
+mod deferred;
+mod cluster;
+mod skybox;
 
 
-use std::sync::Arc;
-
-use cgmath::{Deg, InnerSpace, Quaternion, Rotation3, Zero};
+use cgmath::{Deg, InnerSpace, One, Quaternion, Rotation3, Zero};
 use legion::World;
 use legion::IntoQuery;
 use light::Light;
-use texture::Texture;
 use winit::{
     event::{DeviceEvent, ElementState, Event, KeyboardInput, VirtualKeyCode, WindowEvent},
     event_loop::{ControlFlow, EventLoop},
@@ -26,52 +35,61 @@ use winit::{
     window::Window,
 };
 use camera::Camera;
-use transform::Transform;
+use transform::LocalTransform;
 use model::Model;
+use asset::{Handle, LoadState};
+use config::{Backend, Limits, PowerPreference, PresentMode, RendererConfig};
+use post::PostPass;
+use renderer::RendererError;
+use skybox::SkyboxSource;
 
 const NUM_INSTANCES_PER_ROW: u16 = 10;
 const SPACE_BETWEEN: f32 = 3.0;
+const LIGHT_RANGE: f32 = 20.0;
+const LIGHT_INTENSITY: f32 = 15.0;
+// Factor the exposure pass changes by when pressing = or -
+const EXPOSURE_STEP: f32 = 1.25;
+// Factor the ambient light changes by when pressing ] or [
+const AMBIENT_STEP: f32 = 1.25;
+// Number of frames dumped to disk when pressing F11
+const CAPTURE_FRAME_COUNT: u32 = 120;
+// Read from the working directory if it exists, the default settings are used otherwise
+const CONFIG_PATH: &str = "renderer.toml";
 
 struct State {
     mouse_pressed: bool,
     camera: Camera,
     camera_controller: camera::Controller,
-    models: Vec<Arc<Model>>,
+    models: Vec<Handle<Model>>,
     world: World,
     renderer: renderer::Renderer,
 }
 
 impl State {
-    async fn new(window: &Window) -> Self {
-        let world = World::default();
+    async fn new(window: &Window) -> anyhow::Result<Self> {
         let position = cgmath::Vector3 { x: 5.0, y: 5.0, z: 5.0 };
         let color = [1.0, 1.0, 1.0];
-        let light = Light::new(position, color); 
+        let light = Light::point(position, color, LIGHT_RANGE).with_intensity(LIGHT_INTENSITY); 
         
         
-        let renderer = renderer::Renderer::new(window, &light).await;
+        let renderer = renderer::Renderer::new(window, &light, &load_config()?).await?;
+        Ok(Self::with_renderer(renderer))
+    }
+
+    fn with_renderer(mut renderer: renderer::Renderer) -> Self {
+        let world = World::default();
         let camera = camera::Camera::new((0.0, 5.0, 10.0), cgmath::Deg(-90.0), cgmath::Deg(-20.0), cgmath::Deg(45.0), 0.1, 100.0, &renderer);
         let camera_controller = camera::Controller::new(4.0, 0.4);
 
 
         let res_dir = std::path::Path::new(env!("OUT_DIR")).join("resources");
         
-        let floor_model = model::Model::load(
-            &renderer.device,
-            &renderer.queue,
-            &Texture::create_bind_group_layout(&renderer.device),
-            res_dir.join("floor.obj"),
-        ).unwrap();
-        let floor_model = Arc::new(floor_model);
-
-        let cube_model = model::Model::load(
-            &renderer.device,
-            &renderer.queue,
-            &Texture::create_bind_group_layout(&renderer.device),
-            res_dir.join("cube.obj"),
-        ).unwrap();
-        let cube_model = Arc::new(cube_model);
-        
+        let floor_model = renderer.load_model_async(res_dir.join("floor.obj"));
+        let cube_model = renderer.load_model_async(res_dir.join("cube.obj"));
+        // The scene still renders against the clear color without it
+        if let Err(e) = renderer.set_skybox(Some(skybox_source(&res_dir))) {
+            eprintln!("Could not load the skybox: {e:?}");
+        }
 
         Self {
             mouse_pressed: false,
@@ -83,25 +101,38 @@ impl State {
         }
     }
 
-    fn populate_world(&mut self) {
+    fn populate_world(&mut self) -> anyhow::Result<()> {
 
         let position = cgmath::Vector3 { x: -5.0, y: 5.0, z: 5.0 };
         let color = [1.0, 0.0, 0.0];
-        let light = Light::new(position, color); 
+        let light = Light::point(position, color, LIGHT_RANGE).with_intensity(LIGHT_INTENSITY); 
         self.world.push((light, ()));
 
         let position = cgmath::Vector3 { x: 2.0, y: 2.0, z: 2.0 };
         let color = [0.0, 0.0, 1.0];
-        let light = Light::new(position, color); 
+        let light = Light::point(position, color, LIGHT_RANGE).with_intensity(LIGHT_INTENSITY); 
         self.world.push((light, ()));
 
-        let position = cgmath::Vector3 { x: 0.0, y: 0.0, z: 0.0 };
-        let rotation = cgmath::Quaternion::new(1.0, 0.0, 0.0, 0.0);
-        let transform = Transform::new(position, rotation, &self.renderer);
-        let floor_model = self.models[0].clone();
+        let direction = cgmath::Vector3 { x: -1.0, y: -1.0, z: -0.3 };
+        let color = [1.0, 1.0, 0.8];
+        let sun = Light::directional(direction, color).with_intensity(0.3);
+        self.world.push((sun, ()));
+
+        let position = cgmath::Vector3 { x: 0.0, y: 6.0, z: 3.0 };
+        let direction = cgmath::Vector3 { x: 0.0, y: -1.0, z: 0.0 };
+        let color = [0.0, 1.0, 0.0];
+        let spot = Light::spot(position, direction, color, LIGHT_RANGE, cgmath::Deg(15.0), cgmath::Deg(25.0))
+            .with_intensity(LIGHT_INTENSITY * 2.0);
+        self.world.push((spot, ()));
+
+        // The floor mesh sits off to one side of the origin, stretch it until it covers the whole grid of cubes
+        let position = cgmath::Vector3 { x: -18.5, y: 0.0, z: -18.0 };
+        let transform = LocalTransform::new(position, Quaternion::one()).with_scale(cgmath::Vector3 { x: 1.5, y: 1.0, z: 2.0 });
+        let floor_model = self.models[0];
         self.world.push((transform, floor_model));
 
         
+        let mut center_cube = None;
         (0..NUM_INSTANCES_PER_ROW).for_each(|z| {
             (0..NUM_INSTANCES_PER_ROW).for_each(|x| {
                 let x = SPACE_BETWEEN * (f32::from(x) - f32::from(NUM_INSTANCES_PER_ROW) / 2.0);
@@ -115,13 +146,41 @@ impl State {
                     cgmath::Quaternion::from_axis_angle(position.normalize(), cgmath::Deg(45.0))
                 };
 
-                let cube_model = self.models[1].clone();
-                let transform = Transform::new(position, rotation, &self.renderer);
-                self.world.push((transform, cube_model));
+                let cube_model = self.models[1];
+                let transform = LocalTransform::new(position, rotation);
+                let cube = self.world.push((transform, cube_model));
+                if position.is_zero() {
+                    center_cube = Some(cube);
+                }
                 
             });
         });
+
+        // A smaller cube riding on top of the center one, carried along as the center cube rotates
+        if let Some(center_cube) = center_cube {
+            let position = cgmath::Vector3 { x: 0.0, y: 1.5, z: 0.0 };
+            let mut transform = LocalTransform::new(position, Quaternion::one());
+            transform.set_scale(cgmath::Vector3 { x: 0.4, y: 0.4, z: 0.4 });
+            let rider = self.world.push((transform, self.models[1]));
+            hierarchy::set_parent(&mut self.world, rider, center_cube).expect("both cubes were just created");
+        }
+
+        // A post with a shade attached as a child node, placed as a whole through the root node and turned to face
+        // the middle of the grid
+        let res_dir = std::path::Path::new(env!("OUT_DIR")).join("resources");
+        for root in self.renderer.load_gltf(&mut self.world, res_dir.join("lamp.gltf"))? {
+            if let Some(mut entry) = self.world.entry(root) {
+                if let Ok(transform) = entry.get_component_mut::<LocalTransform>() {
+                    transform.translate_by(cgmath::Vector3 { x: 1.5, y: 0.0, z: 4.5 });
+                    let target = cgmath::Point3::new(0.0, transform.position.y, 0.0);
+                    transform.look_at(target, cgmath::Vector3::unit_y());
+                }
+            }
+        }
+
+        hierarchy::propagate_transforms(&mut self.world);
         dbg!("done populating world");
+        Ok(())
     }
 
     // Handle events, return true if want to capture that event so it does not get handled further
@@ -155,13 +214,94 @@ impl State {
         }
     }
 
+    // Gets a new GPU device after the old one was lost, and recreates the camera's buffers on it
+    fn recover(&mut self) -> Result<(), RendererError> {
+        eprintln!("Recreating the GPU device");
+        pollster::block_on(self.renderer.recover())?;
+        self.camera.recreate_buffers(&self.renderer);
+        Ok(())
+    }
+
+    // Hotkeys that act on the window rather than steering the camera
+    fn handle_key(&mut self, key: VirtualKeyCode) {
+        match key {
+            VirtualKeyCode::F12 => {
+                let path = format!("screenshots/screenshot_{}.png", unix_timestamp());
+                if let Err(e) = std::fs::create_dir_all("screenshots") {
+                    eprintln!("Could not create screenshot directory: {e}");
+                }
+                self.renderer.screenshot(path);
+            }
+            VirtualKeyCode::F11 => {
+                let directory = format!("captures/capture_{}", unix_timestamp());
+                if let Err(e) = self.renderer.capture_frames(directory, CAPTURE_FRAME_COUNT) {
+                    eprintln!("Could not start frame capture: {e:?}");
+                }
+            }
+            VirtualKeyCode::F10 => {
+                let stats = self.renderer.culling_stats();
+                println!("Drew {} instances, culled {}", stats.drawn, stats.culled);
+            }
+            VirtualKeyCode::F7 => {
+                let heatmap = !self.renderer.light_heatmap();
+                self.renderer.set_light_heatmap(heatmap);
+                println!("Light heat map: {}", if heatmap { "on" } else { "off" });
+            }
+            VirtualKeyCode::F8 => {
+                let vsync = !self.renderer.vsync();
+                self.renderer.set_vsync(vsync);
+                println!("Vsync: {}", if vsync { "on" } else { "off" });
+            }
+            VirtualKeyCode::F6 => {
+                // Steps through the present modes, skipping the ones the surface doesn't support
+                let modes = PresentMode::ALL;
+                let current = modes.iter().position(|mode| mode.to_wgpu() == self.renderer.present_mode()).unwrap_or(0);
+                for step in 1..=modes.len() {
+                    let mode = modes[(current + step) % modes.len()];
+                    if self.renderer.set_present_mode(mode).is_ok() {
+                        println!("Present mode: {mode:?}");
+                        break;
+                    }
+                }
+            }
+            VirtualKeyCode::F9 => {
+                // Cycles through the supported MSAA sample counts, back to 1 after the highest
+                let supported = self.renderer.supported_sample_counts();
+                let current = self.renderer.sample_count();
+                let next = supported.iter().copied().find(|&count| count > current).unwrap_or(1);
+                match self.renderer.set_sample_count(next) {
+                    Ok(()) => println!("MSAA: {next}x"),
+                    Err(e) => eprintln!("Could not change MSAA: {e:?}"),
+                }
+            }
+            VirtualKeyCode::Equals | VirtualKeyCode::Minus => {
+                let factor = if key == VirtualKeyCode::Equals { EXPOSURE_STEP } else { 1.0 / EXPOSURE_STEP };
+                let mut passes = self.renderer.post_passes().to_vec();
+                for pass in &mut passes {
+                    if let PostPass::Exposure { exposure } = pass {
+                        *exposure *= factor;
+                    }
+                }
+                self.renderer.set_post_passes(passes);
+            }
+            VirtualKeyCode::RBracket | VirtualKeyCode::LBracket => {
+                let factor = if key == VirtualKeyCode::RBracket { AMBIENT_STEP } else { 1.0 / AMBIENT_STEP };
+                let mut scene_lighting = *self.renderer.scene_lighting();
+                scene_lighting.ambient_strength *= factor;
+                self.renderer.set_scene_lighting(scene_lighting);
+                println!("Ambient strength: {:.3}", scene_lighting.ambient_strength);
+            }
+            _ => {}
+        }
+    }
+
     fn update(&mut self, dt: std::time::Duration) {
         // Camera
         self.camera_controller.update_camera(&mut self.camera, dt);
         // Models
         let rotate_by = Quaternion::from_angle_z(Deg(1.0));
 
-        let mut transforms = <&mut Transform>::query();
+        let mut transforms = <&mut LocalTransform>::query();
         for transform in transforms.iter_mut(&mut self.world) {
             transform.rotate_by(rotate_by);
         }
@@ -171,6 +311,7 @@ impl State {
             light.position = cgmath::Quaternion::from_axis_angle((0.0, 1.0, 0.0).into(), cgmath::Deg(1.0)) * light.position;
         }
         
+        hierarchy::propagate_transforms(&mut self.world);
 
         self.renderer.update(&self.camera, &self.world);
 
@@ -180,13 +321,96 @@ impl State {
 
 
 
+// Six face images in a skybox directory take the place of the HDR sky, named after the face they show
+fn skybox_source(res_dir: &std::path::Path) -> SkyboxSource {
+    let faces_dir = res_dir.join("skybox");
+    if faces_dir.is_dir() {
+        SkyboxSource::Faces(["px", "nx", "py", "ny", "pz", "nz"].map(|face| faces_dir.join(format!("{face}.png"))))
+    } else {
+        SkyboxSource::Equirectangular(res_dir.join("sky.hdr"))
+    }
+}
+
+fn load_config() -> anyhow::Result<RendererConfig> {
+    if std::path::Path::new(CONFIG_PATH).exists() {
+        RendererConfig::load(CONFIG_PATH)
+    } else {
+        // What the demo runs with, a config file starts from RendererConfig::default instead
+        Ok(RendererConfig::default()
+            .with_backend(Backend::Any)
+            .with_present_mode(PresentMode::AutoVsync)
+            .with_power_preference(PowerPreference::HighPerformance)
+            .with_limits(Limits::Default)
+            .with_clear_color([0.1, 0.2, 0.3, 1.0])
+            .with_sample_count(4))
+    }
+}
+
+// Prints the error with its causes, and where to change the settings the renderer couldn't start with
+fn report_startup_error(message: &str, error: &anyhow::Error) {
+    eprintln!("{message}: {error:?}");
+    let setting = match error.downcast_ref::<RendererError>() {
+        Some(RendererError::NoAdapter { .. } | RendererError::IncompatibleSurface { .. } | RendererError::RequestDevice { .. }) => {
+            "another backend or lower limits"
+        }
+        Some(RendererError::UnsupportedSampleCount { .. }) => "a supported sample_count",
+        Some(RendererError::UnsupportedPresentMode { .. }) => "a supported present_mode",
+        _ => return,
+    };
+    eprintln!("Setting {setting} in {CONFIG_PATH} may fix this");
+}
+
+fn unix_timestamp() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_secs())
+}
+
+// Renders a single frame of the scene without opening a window and saves it to the given path
+fn render_headless(output_path: &str) -> anyhow::Result<()> {
+    let renderer = pollster::block_on(renderer::Renderer::new_headless(800, 600, true, &load_config()?))?;
+    let mut state = State::with_renderer(renderer);
+    // A single frame would only show the placeholders otherwise
+    state.renderer.wait_for_loads();
+    for &model in &state.models {
+        if let LoadState::Failed(e) = state.renderer.model_load_state(model) {
+            anyhow::bail!("Failed to load a model of the scene: {:?}", e);
+        }
+    }
+    state.populate_world()?;
+    state.renderer.update(&state.camera, &state.world);
+    let image = state.renderer.render_to_image(&state.camera)?;
+    image.save(output_path)?;
+    Ok(())
+}
+
 fn main() {
     env_logger::init();
+    let args: Vec<String> = std::env::args().collect();
+    if let [_, flag, output_path] = args.as_slice() {
+        if flag == "--headless" {
+            if let Err(e) = render_headless(output_path) {
+                report_startup_error("Headless rendering failed", &e);
+                std::process::exit(1);
+            }
+            return;
+        }
+    }
+
     let event_loop = EventLoop::new();
     let window = WindowBuilder::new().build(&event_loop).unwrap();
 
-    let mut state = pollster::block_on(State::new(&window));
-    state.populate_world();
+    let mut state = match pollster::block_on(State::new(&window)) {
+        Ok(state) => state,
+        Err(e) => {
+            report_startup_error("Failed to start", &e);
+            std::process::exit(1);
+        }
+    };
+    if let Err(e) = state.populate_world() {
+        report_startup_error("Failed to build the scene", &e);
+        std::process::exit(1);
+    }
     let mut last_render_time = std::time::Instant::now();
 
     event_loop.run(move |event, _, control_flow| match event {
@@ -211,6 +435,15 @@ fn main() {
                         },
                     ..
                 } => *control_flow = ControlFlow::Exit,
+                WindowEvent::KeyboardInput {
+                    input:
+                        KeyboardInput {
+                            state: ElementState::Pressed,
+                            virtual_keycode: Some(key),
+                            ..
+                        },
+                    ..
+                } => state.handle_key(*key),
                 WindowEvent::Resized(physical_size) => {
                     state.renderer.resize(*physical_size);
                     state.camera.resize(*physical_size);
@@ -222,17 +455,31 @@ fn main() {
             }
         }
         Event::RedrawRequested(_) => {
+            // E.g. after a driver reset, everything on the GPU is gone and has to be recreated
+            if state.renderer.is_device_lost() {
+                if let Err(e) = state.recover() {
+                    eprintln!("Could not recover from losing the GPU device: {e:?}");
+                    *control_flow = ControlFlow::Exit;
+                    return;
+                }
+            }
             let now = std::time::Instant::now();
                 let dt = now - last_render_time;
                 last_render_time = now;
                 state.update(dt);
-            match state.renderer.render(&state.world, &state.camera) {
+            match state.renderer.render(&state.camera) {
                 Ok(_) => {}
-                // Recreate the swap_chain if lost
-                Err(wgpu::SurfaceError::Lost) => state.renderer.resize(state.renderer.size),
-                // The system is out of memory, we should probably quit
-                Err(wgpu::SurfaceError::OutOfMemory) => *control_flow = ControlFlow::Exit,
-                // All other errors (Outdated, Timeout) should be resolved by the next frame
+                // Recreate the swap_chain if lost or no longer matching the window
+                Err(wgpu::SurfaceError::Lost | wgpu::SurfaceError::Outdated) => state.renderer.resize(state.renderer.size),
+                // A lost device is recreated at the start of the next frame, a device that is still there really
+                // is out of memory
+                Err(wgpu::SurfaceError::OutOfMemory) => {
+                    if !state.renderer.is_device_lost() {
+                        eprintln!("Out of GPU memory");
+                        *control_flow = ControlFlow::Exit;
+                    }
+                }
+                // Timeouts should be resolved by the next frame
                 Err(e) => eprintln!("{:?}", e),
             }
         }
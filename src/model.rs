@@ -1,11 +1,13 @@
-use anyhow::{Context, Result};
-use cgmath::InnerSpace;
-use std::{ops::Range, path::Path};
+use anyhow::{anyhow, bail, Context, Result};
+use cgmath::{Deg, InnerSpace, Matrix, Matrix3, Matrix4, Rad, SquareMatrix, Transform};
+use std::{collections::HashMap, ops::Range, path::{Path, PathBuf}, sync::Arc};
 use wgpu::util::DeviceExt;
 
 use tobj::LoadOptions;
 use wgpu::BindGroup;
-use crate::texture::Texture;
+use legion::{Entity, World};
+
+use crate::{asset::{canonical_path, AssetServer}, culling::Bounds, hierarchy, texture::Texture, transform::LocalTransform};
 
 
 pub trait Vertex {
@@ -15,21 +17,96 @@ pub trait Vertex {
 pub struct Model {
     pub meshes: Vec<Mesh>,
     pub materials: Vec<Material>,
+    // Around all of the meshes, None if they have no vertices
+    pub bounds: Option<Bounds>,
 }
 
+// Metallic-roughness material, every texture is multiplied with its factor in the shader
 #[derive(Debug)]
 pub struct Material {
     pub name: String,
-    pub diffuse_texture: Texture,
-    pub normal_texture: Texture,
+    pub base_color_texture: Arc<Texture>,
+    // Roughness in the green channel and metallic in the blue channel, as in glTF
+    pub metallic_roughness_texture: Arc<Texture>,
+    // Ambient occlusion in the red channel
+    pub occlusion_texture: Arc<Texture>,
+    pub emissive_texture: Arc<Texture>,
+    pub normal_texture: Arc<Texture>,
+    pub factors: MaterialFactors,
+    pub factor_buffer: wgpu::Buffer,
     pub bind_group: BindGroup,
-}#[derive(Debug)]
+}
+
+// Textures of a material, the missing ones are replaced by the asset server's 1x1 textures that leave the factors unchanged
+#[derive(Debug, Default)]
+pub struct MaterialTextures {
+    pub base_color: Option<Arc<Texture>>,
+    pub metallic_roughness: Option<Arc<Texture>>,
+    pub occlusion: Option<Arc<Texture>>,
+    pub emissive: Option<Arc<Texture>>,
+    pub normal: Option<Arc<Texture>>,
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct MaterialFactors {
+    pub base_color: [f32; 4],
+    pub metallic: f32,
+    pub roughness: f32,
+    // How much the occlusion texture darkens the ambient light, zero ignores it
+    pub occlusion_strength: f32,
+    pub emissive: [f32; 3],
+    pub normal_scale: f32,
+}
+
+// The glTF defaults
+impl Default for MaterialFactors {
+    fn default() -> Self {
+        Self {
+            base_color: [1.0; 4],
+            metallic: 1.0,
+            roughness: 1.0,
+            occlusion_strength: 1.0,
+            emissive: [0.0; 3],
+            normal_scale: 1.0,
+        }
+    }
+}
+
+impl MaterialFactors {
+    pub const fn to_raw(self) -> MaterialRaw {
+        MaterialRaw {
+            base_color: self.base_color,
+            emissive: self.emissive,
+            metallic: self.metallic,
+            roughness: self.roughness,
+            occlusion_strength: self.occlusion_strength,
+            normal_scale: self.normal_scale,
+            _padding: 0.0,
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct MaterialRaw {
+    pub base_color: [f32; 4],
+    pub emissive: [f32; 3],
+    pub metallic: f32,
+    pub roughness: f32,
+    pub occlusion_strength: f32,
+    pub normal_scale: f32,
+    pub _padding: f32,
+}
+
+#[derive(Debug)]
 pub struct Mesh {
     pub name: String,
     pub vertex_buffer: wgpu::Buffer,
     pub index_buffer: wgpu::Buffer,
     pub num_elements: u32,
     pub material: usize,
+    // In the model's space, None if the mesh has no vertices
+    pub bounds: Option<Bounds>,
 }
 
 #[repr(C)]
@@ -80,173 +157,737 @@ impl Vertex for ModelVertex {
 }
 
 
-impl Model {
-    #[allow(clippy::too_many_lines)]
-    pub fn load<P: AsRef<Path>>(
-        device: &wgpu::Device,
-        queue: &wgpu::Queue,
-        layout: &wgpu::BindGroupLayout,
-        path: P,
-    ) -> Result<Self> {
+// A decoded image waiting to be uploaded
+#[derive(Debug)]
+pub struct ImageData {
+    // Images loaded from their own file are shared through the asset server by path. Images embedded in a glTF
+    // file use the file's path followed by their index.
+    pub path: Option<PathBuf>,
+    pub image: image::DynamicImage,
+    pub is_linear: bool,
+    // glTF textures bring their own sampler settings
+    pub sampler: Option<SamplerSettings>,
+}
+
+impl ImageData {
+    pub fn upload(&self, device: &wgpu::Device, queue: &wgpu::Queue) -> Texture {
+        let label = self.path.as_deref().and_then(Path::to_str);
+        let mut texture = Texture::from_image(device, queue, &self.image, label, self.is_linear);
+        if let Some(settings) = self.sampler {
+            texture.sampler = create_sampler(device, settings);
+        }
+        texture
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct SamplerSettings {
+    pub address_mode_u: wgpu::AddressMode,
+    pub address_mode_v: wgpu::AddressMode,
+    pub mag_filter: wgpu::FilterMode,
+    pub min_filter: wgpu::FilterMode,
+}
+
+// The images of a material, shared between materials of the same file that use the same image
+#[derive(Debug, Default, Clone)]
+pub struct MaterialImages {
+    pub base_color: Option<Arc<ImageData>>,
+    pub metallic_roughness: Option<Arc<ImageData>>,
+    pub occlusion: Option<Arc<ImageData>>,
+    pub emissive: Option<Arc<ImageData>>,
+    pub normal: Option<Arc<ImageData>>,
+}
+
+#[derive(Debug, Clone)]
+pub struct MaterialData {
+    pub name: String,
+    pub images: MaterialImages,
+    pub factors: MaterialFactors,
+}
+
+#[derive(Debug)]
+pub struct MeshData {
+    pub name: String,
+    pub vertices: Vec<ModelVertex>,
+    pub indices: Vec<u32>,
+    pub material: usize,
+}
+
+// Everything in a model file, decoded on the CPU. Loading this doesn't touch the GPU, so it can happen on a worker thread
+// and be turned into a Model with Model::upload afterwards.
+#[derive(Debug)]
+pub struct ModelData {
+    pub label: String,
+    pub meshes: Vec<MeshData>,
+    pub materials: Vec<MaterialData>,
+}
+
+impl ModelData {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        // Anything that isn't glTF is treated as OBJ
+        let extension = path.as_ref().extension().and_then(std::ffi::OsStr::to_str).map(str::to_ascii_lowercase);
+        if matches!(extension.as_deref(), Some("gltf" | "glb")) {
+            Self::load_gltf(path)
+        } else {
+            Self::load_obj(path)
+        }
+    }
+
+    pub fn load_obj<P: AsRef<Path>>(path: P) -> Result<Self> {
         let (obj_models, obj_materials) = tobj::load_obj(path.as_ref(), &LoadOptions {
                 triangulate: true,
                 single_index: true,
                 ..tobj::LoadOptions::default()
             },
-        )?;
-        let obj_materials = obj_materials?;
+        ).with_context(|| format!("Failed to load OBJ file {}", path.as_ref().display()))?;
+        // Without a usable MTL file every mesh gets the default material
+        let obj_materials = obj_materials.unwrap_or_else(|e| {
+            eprintln!("Failed to load the materials of {}: {e}", path.as_ref().display());
+            Vec::new()
+        });
         // We're assuming that the texture files are stored with the obj file
         let containing_folder = path.as_ref().parent()
             .context("Directory has no parent")?;
 
-            let mut materials = Vec::new();
-            for mat in obj_materials {
-                dbg!(&mat);
-                let diffuse_path = mat.diffuse_texture;
-                let diffuse_texture = crate::texture::Texture::load(device, queue, containing_folder.join(diffuse_path), false)?;
-                
-                let normal_path = mat.normal_texture;
-                let normal_texture = crate::texture::Texture::load(device, queue, containing_folder.join(normal_path), true)?;
-            
-                materials.push(Material::new(
-                    device,
-                    &mat.name,
-                    diffuse_texture,
-                    normal_texture,
-                    layout,
-                ));
-            }
+        // Decoded once per file, materials using the same file share it
+        let mut loaded_images: HashMap<(String, bool), Arc<ImageData>> = HashMap::new();
+        let mut materials = Vec::new();
+        for mat in obj_materials {
+            materials.push(obj_material_data(mat, containing_folder, path.as_ref(), &mut loaded_images)?);
+        }
+        // Meshes without a (valid) material use a plain white one, stored after the others
+        let default_material = materials.len();
+        materials.push(MaterialData {
+            name: String::from("obj_default_material"),
+            images: MaterialImages::default(),
+            factors: MaterialFactors { metallic: 0.0, ..MaterialFactors::default() },
+        });
 
         let mut meshes = Vec::new();
         for m in obj_models {
+            let vertex_count = m.mesh.positions.len() / 3;
+            // tobj leaves the attributes a file doesn't have empty
+            let has_tex_coords = !m.mesh.texcoords.is_empty();
+            let has_normals = !m.mesh.normals.is_empty();
+            if has_tex_coords && m.mesh.texcoords.len() != vertex_count * 2 {
+                bail!(
+                    "Mesh {} in {} has {} texture coordinates for {vertex_count} vertices",
+                    m.name,
+                    path.as_ref().display(),
+                    m.mesh.texcoords.len() / 2,
+                );
+            }
+            if has_normals && m.mesh.normals.len() != vertex_count * 3 {
+                bail!(
+                    "Mesh {} in {} has {} normals for {vertex_count} vertices",
+                    m.name,
+                    path.as_ref().display(),
+                    m.mesh.normals.len() / 3,
+                );
+            }
+
             let mut vertices = Vec::new();
-            for i in 0..m.mesh.positions.len() / 3 {
+            for i in 0..vertex_count {
                 vertices.push(ModelVertex {
                     position: [
                         m.mesh.positions[i * 3],
                         m.mesh.positions[i * 3 + 1],
                         m.mesh.positions[i * 3 + 2],
                     ],
-                    tex_coords: [m.mesh.texcoords[i * 2], m.mesh.texcoords[i * 2 + 1]],
-                    normal: [
-                        m.mesh.normals[i * 3],
-                        m.mesh.normals[i * 3 + 1],
-                        m.mesh.normals[i * 3 + 2],
-                    ],
+                    tex_coords: if has_tex_coords {
+                        [m.mesh.texcoords[i * 2], m.mesh.texcoords[i * 2 + 1]]
+                    } else {
+                        [0.0; 2]
+                    },
+                    normal: if has_normals {
+                        [
+                            m.mesh.normals[i * 3],
+                            m.mesh.normals[i * 3 + 1],
+                            m.mesh.normals[i * 3 + 2],
+                        ]
+                    } else {
+                        [0.0; 3]
+                    },
                     tangent: [0.0; 3],
                     bitangent: [0.0; 3],
                 });
             }
+            let mut indices = m.mesh.indices;
+            if !has_normals {
+                compute_normals(&mut vertices, &mut indices, OBJ_CREASE_ANGLE.into());
+            }
+            compute_tangents(&mut vertices, &indices);
+
+            let material = m.mesh.material_id
+                .filter(|&id| id < default_material)
+                .unwrap_or(default_material);
+            meshes.push(MeshData { name: m.name, vertices, indices, material });
+        }
 
-            let indices = &m.mesh.indices;
-            let mut triangles_included = (0..vertices.len()).collect::<Vec<_>>();
-
-            // Calculate tangents and bitangets. We're going to
-            // use the triangles, so we need to loop through the
-            // indices in chunks of 3
-            for c in indices.chunks(3) {
-                let v0 = vertices[c[0] as usize];
-                let v1 = vertices[c[1] as usize];
-                let v2 = vertices[c[2] as usize];
-
-                let pos0: cgmath::Vector3<_> = v0.position.into();
-                let pos1: cgmath::Vector3<_> = v1.position.into();
-                let pos2: cgmath::Vector3<_> = v2.position.into();
-
-                let uv0: cgmath::Vector2<_> = v0.tex_coords.into();
-                let uv1: cgmath::Vector2<_> = v1.tex_coords.into();
-                let uv2: cgmath::Vector2<_> = v2.tex_coords.into();
-
-                // Calculate the edges of the triangle
-                let delta_pos1 = pos1 - pos0;
-                let delta_pos2 = pos2 - pos0;
-
-                // This will give us a direction to calculate the
-                // tangent and bitangent
-                let delta_uv1 = uv1 - uv0;
-                let delta_uv2 = uv2 - uv0;
-
-                // Solving the following system of equations will
-                // give us the tangent and bitangent.
-                //     delta_pos1 = delta_uv1.x * T + delta_u.y * B
-                //     delta_pos2 = delta_uv2.x * T + delta_uv2.y * B
-                // Luckily, the place I found this equation provided
-                // the solution!
-                let r = 1.0 / (delta_uv1.x * delta_uv2.y - delta_uv1.y * delta_uv2.x);
-                let tangent = (delta_pos1 * delta_uv2.y - delta_pos2 * delta_uv1.y) * r;
-                let bitangent = (delta_pos2 * delta_uv1.x - delta_pos1 * delta_uv2.x) * r;
-
-                // We'll use the same tangent/bitangent for each vertex in the triangle
-                vertices[c[0] as usize].tangent =
-                    (tangent + cgmath::Vector3::from(vertices[c[0] as usize].tangent)).into();
-                vertices[c[1] as usize].tangent =
-                    (tangent + cgmath::Vector3::from(vertices[c[1] as usize].tangent)).into();
-                vertices[c[2] as usize].tangent =
-                    (tangent + cgmath::Vector3::from(vertices[c[2] as usize].tangent)).into();
-                vertices[c[0] as usize].bitangent =
-                    (bitangent + cgmath::Vector3::from(vertices[c[0] as usize].bitangent)).into();
-                vertices[c[1] as usize].bitangent =
-                    (bitangent + cgmath::Vector3::from(vertices[c[1] as usize].bitangent)).into();
-                vertices[c[2] as usize].bitangent =
-                    (bitangent + cgmath::Vector3::from(vertices[c[2] as usize].bitangent)).into();
-
-                // Used to average the tangents/bitangents
-                triangles_included[c[0] as usize] += 1;
-                triangles_included[c[1] as usize] += 1;
-                triangles_included[c[2] as usize] += 1;
+        Ok(Self { label: path.as_ref().display().to_string(), meshes, materials })
+    }
+
+    // The whole default scene as one model, with every node's transform baked into the vertices of its meshes. Its
+    // nodes can't be moved on their own, Model::load_gltf spawns them as separate entities instead.
+    pub fn load_gltf<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let GltfScene { label, materials, nodes } = GltfScene::load(path)?;
+        // Parents come first, so their scene transforms are known by the time their children need them
+        let mut scene_transforms: Vec<Matrix4<f32>> = Vec::with_capacity(nodes.len());
+        let mut meshes = Vec::new();
+        for node in nodes {
+            let parent_transform = node.parent.map_or_else(Matrix4::identity, |parent| scene_transforms[parent]);
+            let transform = parent_transform * node.transform.matrix();
+            for mut mesh in node.meshes {
+                transform_vertices(&mut mesh.vertices, transform);
+                meshes.push(mesh);
             }
+            scene_transforms.push(transform);
+        }
+        Ok(Self { label, meshes, materials })
+    }
 
-            // Average the tangents/bitangents
-            for (i, n) in triangles_included.into_iter().enumerate() {
-                let denom = 1.0 / n as f32;
-                let v = &mut vertices[i];
-                v.tangent = (cgmath::Vector3::from(v.tangent) * denom)
-                    .normalize()
-                    .into();
-                v.bitangent = (cgmath::Vector3::from(v.bitangent) * denom)
-                    .normalize()
-                    .into();
+    // An axis aligned cube with the given half extent and a single material
+    pub fn cube(label: &str, half_extent: f32, material: MaterialData) -> Self {
+        // Normal, and the directions the texture's u and v axes run along, of every face
+        let faces: [([f32; 3], [f32; 3], [f32; 3]); 6] = [
+            ([1.0, 0.0, 0.0], [0.0, 0.0, -1.0], [0.0, -1.0, 0.0]),
+            ([-1.0, 0.0, 0.0], [0.0, 0.0, 1.0], [0.0, -1.0, 0.0]),
+            ([0.0, 1.0, 0.0], [1.0, 0.0, 0.0], [0.0, 0.0, 1.0]),
+            ([0.0, -1.0, 0.0], [1.0, 0.0, 0.0], [0.0, 0.0, -1.0]),
+            ([0.0, 0.0, 1.0], [1.0, 0.0, 0.0], [0.0, -1.0, 0.0]),
+            ([0.0, 0.0, -1.0], [-1.0, 0.0, 0.0], [0.0, -1.0, 0.0]),
+        ];
+        let mut vertices = Vec::new();
+        let mut indices = Vec::new();
+        for (face, (normal, u, v)) in (0u32..).zip(faces) {
+            let (normal, u, v) = (cgmath::Vector3::from(normal), cgmath::Vector3::from(u), cgmath::Vector3::from(v));
+            for (s, t) in [(0.0f32, 0.0f32), (1.0, 0.0), (1.0, 1.0), (0.0, 1.0)] {
+                let position = (normal + u * s.mul_add(2.0, -1.0) + v * t.mul_add(2.0, -1.0)) * half_extent;
+                vertices.push(ModelVertex {
+                    position: position.into(),
+                    tex_coords: [s, t],
+                    normal: normal.into(),
+                    tangent: u.into(),
+                    bitangent: v.into(),
+                });
             }
+            let base = face * 4;
+            indices.extend_from_slice(&[base, base + 2, base + 1, base, base + 3, base + 2]);
+        }
+        Self {
+            label: label.to_string(),
+            meshes: vec![MeshData { name: label.to_string(), vertices, indices, material: 0 }],
+            materials: vec![material],
+        }
+    }
+}
 
-            let vertex_buffer = device.create_buffer_init(
-                &wgpu::util::BufferInitDescriptor {
-                    label: Some(&format!("{:?} Vertex Buffer", path.as_ref())),
-                    contents: bytemuck::cast_slice(&vertices),
-                    usage: wgpu::BufferUsages::VERTEX,
+// A node of a glTF scene, with its meshes in its own space
+#[derive(Debug)]
+pub struct GltfNode {
+    pub name: String,
+    pub transform: LocalTransform,
+    // Index of the parent in GltfScene::nodes, None for the roots of the scene
+    pub parent: Option<usize>,
+    // Empty for nodes that only place their children
+    pub meshes: Vec<MeshData>,
+}
+
+// The node tree of a glTF file's default scene, decoded on the CPU. Parents come before their children.
+#[derive(Debug)]
+pub struct GltfScene {
+    pub label: String,
+    pub materials: Vec<MaterialData>,
+    pub nodes: Vec<GltfNode>,
+}
+
+impl GltfScene {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let (document, buffers, images) = gltf::import(path.as_ref())
+            .with_context(|| format!("Failed to import glTF file {}", path.as_ref().display()))?;
+        let containing_folder = path.as_ref().parent().unwrap_or_else(|| Path::new(""));
+
+        // Materials referring to the same texture share it
+        let mut loaded_images: HashMap<(usize, bool), Arc<ImageData>> = HashMap::new();
+        let mut load_image = |info: Option<gltf::Texture>, is_linear: bool| -> Result<Option<Arc<ImageData>>> {
+            let Some(texture) = info else {
+                return Ok(None);
+            };
+            if let Some(loaded) = loaded_images.get(&(texture.index(), is_linear)) {
+                return Ok(Some(loaded.clone()));
+            }
+            let source = texture.source();
+            let image = gltf_image_to_rgba(&images[source.index()])?;
+            // External images are shared with every other model using the same file, embedded ones are keyed by the
+            // glTF file and their index in it
+            let image_path = match source.source() {
+                gltf::image::Source::Uri { uri, .. } if !uri.starts_with("data:") => containing_folder.join(uri),
+                _ => canonical_path(path.as_ref()).join(format!("#image{}", source.index())),
+            };
+            let loaded = Arc::new(ImageData {
+                path: Some(image_path),
+                image: image::DynamicImage::ImageRgba8(image),
+                is_linear,
+                sampler: Some(gltf_sampler_settings(&texture.sampler())),
+            });
+            loaded_images.insert((texture.index(), is_linear), loaded.clone());
+            Ok(Some(loaded))
+        };
+        let mut materials = Vec::new();
+        for material in document.materials() {
+            let pbr = material.pbr_metallic_roughness();
+            let images = MaterialImages {
+                base_color: load_image(pbr.base_color_texture().map(|info| info.texture()), false)?,
+                metallic_roughness: load_image(pbr.metallic_roughness_texture().map(|info| info.texture()), true)?,
+                occlusion: load_image(material.occlusion_texture().map(|info| info.texture()), true)?,
+                emissive: load_image(material.emissive_texture().map(|info| info.texture()), false)?,
+                normal: load_image(material.normal_texture().map(|info| info.texture()), true)?,
+            };
+            let factors = MaterialFactors {
+                base_color: pbr.base_color_factor(),
+                metallic: pbr.metallic_factor(),
+                roughness: pbr.roughness_factor(),
+                occlusion_strength: material.occlusion_texture().map_or(1.0, |info| info.strength()),
+                emissive: material.emissive_factor(),
+                normal_scale: material.normal_texture().map_or(1.0, |info| info.scale()),
+            };
+            let name = material.name().map_or_else(|| format!("gltf_material_{}", materials.len()), String::from);
+            materials.push(MaterialData { name, images, factors });
+        }
+        // Primitives without a material use the glTF default material, stored after the others
+        let default_material = materials.len();
+        materials.push(MaterialData {
+            name: String::from("gltf_default_material"),
+            images: MaterialImages::default(),
+            factors: MaterialFactors::default(),
+        });
+
+        let scene = document.default_scene()
+            .or_else(|| document.scenes().next())
+            .context("glTF file contains no scenes")?;
+        let label = path.as_ref().display().to_string();
+        let mut nodes: Vec<GltfNode> = Vec::new();
+        // The transform from each node's space into the scene's, to tell which nodes are mirrored
+        let mut scene_transforms: Vec<Matrix4<f32>> = Vec::new();
+        let mut pending: Vec<(gltf::Node, Option<usize>)> = scene.nodes().map(|node| (node, None)).collect();
+        while let Some((node, parent)) = pending.pop() {
+            let index = nodes.len();
+            pending.extend(node.children().map(|child| (child, Some(index))));
+
+            let (translation, [x, y, z, w], scale) = node.transform().decomposed();
+            let transform = LocalTransform::new(translation.into(), cgmath::Quaternion::new(w, x, y, z))
+                .with_scale(scale.into());
+            let parent_transform = parent.map_or_else(Matrix4::identity, |parent| scene_transforms[parent]);
+            let scene_transform = parent_transform * transform.matrix();
+            // A mirroring transform turns front faces into back faces, which would be culled
+            let mirrored = scene_transform.determinant() < 0.0;
+
+            let mut meshes = Vec::new();
+            for primitive in node.mesh().iter().flat_map(gltf::Mesh::primitives) {
+                if primitive.mode() != gltf::mesh::Mode::Triangles {
+                    eprintln!("Skipping {:?} primitive in {label}, only triangles are supported", primitive.mode());
+                    continue;
                 }
-            );
-            let index_buffer = device.create_buffer_init(
-                &wgpu::util::BufferInitDescriptor {
-                    label: Some(&format!("{:?} Index Buffer", path.as_ref())),
-                    contents: bytemuck::cast_slice(&m.mesh.indices),
-                    usage: wgpu::BufferUsages::INDEX,
+                let (vertices, mut indices) = gltf_primitive_geometry(&primitive, &buffers)?;
+                if mirrored {
+                    for triangle in indices.chunks_exact_mut(3) {
+                        triangle.swap(1, 2);
+                    }
                 }
-            );
+                let name = node.mesh().and_then(|mesh| mesh.name()).or_else(|| node.name()).unwrap_or("gltf_mesh").to_string();
+                let material = primitive.material().index().unwrap_or(default_material);
+                meshes.push(MeshData { name, vertices, indices, material });
+            }
 
-            meshes.push(Mesh {
-                name: m.name,
-                vertex_buffer,
-                index_buffer,
-                num_elements: m.mesh.indices.len() as u32,
-                material: m.mesh.material_id.unwrap_or(0),
+            let name = node.name().map_or_else(|| format!("gltf_node_{}", node.index()), String::from);
+            nodes.push(GltfNode { name, transform, parent, meshes });
+            scene_transforms.push(scene_transform);
+        }
+
+        Ok(Self { label, materials, nodes })
+    }
+
+    // The meshes of a node as a model of their own, with only the materials they use
+    fn node_model(&self, node: GltfNode) -> ModelData {
+        let mut used: Vec<usize> = Vec::new();
+        let mut meshes = node.meshes;
+        for mesh in &mut meshes {
+            mesh.material = used.iter().position(|&material| material == mesh.material).unwrap_or_else(|| {
+                used.push(mesh.material);
+                used.len() - 1
             });
         }
+        ModelData {
+            label: format!("{}#{}", self.label, node.name),
+            meshes,
+            materials: used.into_iter().map(|material| self.materials[material].clone()).collect(),
+        }
+    }
+}
+
+impl Model {
+    // Spawns an entity for every node of the glTF file's default scene, with the node's LocalTransform and, for nodes
+    // with meshes, a handle to a model of them. Child nodes are attached to their parents with hierarchy::set_parent.
+    // Returns the entities of the scene's root nodes, which place the whole tree.
+    pub fn load_gltf<P: AsRef<Path>>(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        assets: &mut AssetServer,
+        world: &mut World,
+        path: P,
+    ) -> Result<Vec<Entity>> {
+        let mut scene = GltfScene::load(path.as_ref())?;
+        let nodes = std::mem::take(&mut scene.nodes);
+        let mut entities: Vec<Entity> = Vec::with_capacity(nodes.len());
+        let mut roots = Vec::new();
+        for (index, node) in nodes.into_iter().enumerate() {
+            let (transform, parent) = (node.transform, node.parent);
+            let entity = if node.meshes.is_empty() {
+                world.push((transform,))
+            } else {
+                // Loading the same file again reuses the models of its nodes
+                let key = canonical_path(path.as_ref()).join(format!("#node{index}"));
+                let model = assets.insert_model(device, queue, &key, scene.node_model(node));
+                world.push((transform, model))
+            };
+            match parent {
+                Some(parent) => hierarchy::set_parent(world, entity, entities[parent])?,
+                None => roots.push(entity),
+            }
+            entities.push(entity);
+        }
+        Ok(roots)
+    }
+
+    // Creates the GPU resources of a decoded model. Images loaded from files are shared with the rest of the asset server.
+    // The data is left alone, so the model can be uploaded again after the device was lost.
+    pub fn upload(device: &wgpu::Device, queue: &wgpu::Queue, assets: &mut AssetServer, data: &ModelData) -> Self {
+        let mut uploaded: HashMap<*const ImageData, Arc<Texture>> = HashMap::new();
+        let mut upload_image = |image: &Option<Arc<ImageData>>| -> Option<Arc<Texture>> {
+            let image = image.as_ref()?;
+            if let Some(texture) = uploaded.get(&Arc::as_ptr(image)) {
+                return Some(texture.clone());
+            }
+            let texture = image.path.as_ref().map_or_else(
+                || Arc::new(image.upload(device, queue)),
+                |path| {
+                    let handle = assets.insert_texture(device, queue, path, image);
+                    assets.texture(handle).clone()
+                },
+            );
+            uploaded.insert(Arc::as_ptr(image), texture.clone());
+            Some(texture)
+        };
+
+        let material_textures: Vec<MaterialTextures> = data.materials.iter()
+            .map(|material| MaterialTextures {
+                base_color: upload_image(&material.images.base_color),
+                metallic_roughness: upload_image(&material.images.metallic_roughness),
+                occlusion: upload_image(&material.images.occlusion),
+                emissive: upload_image(&material.images.emissive),
+                normal: upload_image(&material.images.normal),
+            })
+            .collect();
+        let materials = data.materials.iter().zip(material_textures)
+            .map(|(material, textures)| Material::new(device, assets, &material.name, textures, material.factors))
+            .collect();
+
+        let meshes: Vec<Mesh> = data.meshes.iter()
+            .map(|mesh| Mesh::new(device, mesh.name.clone(), &data.label, &mesh.vertices, &mesh.indices, mesh.material))
+            .collect();
+        let bounds = meshes.iter().filter_map(|mesh| mesh.bounds).reduce(Bounds::union);
 
-        Ok(Self { meshes, materials })
+        Self { meshes, materials, bounds }
     }
 }
 
+impl Mesh {
+    pub fn new(
+        device: &wgpu::Device,
+        name: String,
+        label: &str,
+        vertices: &[ModelVertex],
+        indices: &[u32],
+        material: usize,
+    ) -> Self {
+        let vertex_buffer = device.create_buffer_init(
+            &wgpu::util::BufferInitDescriptor {
+                label: Some(&format!("{label} Vertex Buffer")),
+                contents: bytemuck::cast_slice(vertices),
+                usage: wgpu::BufferUsages::VERTEX,
+            }
+        );
+        let index_buffer = device.create_buffer_init(
+            &wgpu::util::BufferInitDescriptor {
+                label: Some(&format!("{label} Index Buffer")),
+                contents: bytemuck::cast_slice(indices),
+                usage: wgpu::BufferUsages::INDEX,
+            }
+        );
+
+        Self {
+            name,
+            vertex_buffer,
+            index_buffer,
+            num_elements: indices.len() as u32,
+            material,
+            bounds: Bounds::from_points(vertices.iter().map(|vertex| cgmath::Point3::from(vertex.position))),
+        }
+    }
+}
 
+// Calculate tangents and bitangents from the texture coordinates, averaging them over the triangles sharing each vertex
+fn compute_tangents(vertices: &mut [ModelVertex], indices: &[u32]) {
+    // We're going to use the triangles, so we need to loop through the
+    // indices in chunks of 3
+    for c in indices.chunks(3) {
+        let v0 = vertices[c[0] as usize];
+        let v1 = vertices[c[1] as usize];
+        let v2 = vertices[c[2] as usize];
 
+        let pos0: cgmath::Vector3<_> = v0.position.into();
+        let pos1: cgmath::Vector3<_> = v1.position.into();
+        let pos2: cgmath::Vector3<_> = v2.position.into();
 
-pub trait Draw<'a> {
-    fn draw_mesh(
-        &mut self,
-        mesh: &'a Mesh,
-        material: &'a Material,
-        camera: &'a wgpu::BindGroup,
-        light: &'a wgpu::BindGroup,
+        let uv0: cgmath::Vector2<_> = v0.tex_coords.into();
+        let uv1: cgmath::Vector2<_> = v1.tex_coords.into();
+        let uv2: cgmath::Vector2<_> = v2.tex_coords.into();
+
+        // Calculate the edges of the triangle
+        let delta_pos1 = pos1 - pos0;
+        let delta_pos2 = pos2 - pos0;
+
+        // This will give us a direction to calculate the
+        // tangent and bitangent
+        let delta_uv1 = uv1 - uv0;
+        let delta_uv2 = uv2 - uv0;
+
+        // Solving the following system of equations will
+        // give us the tangent and bitangent.
+        //     delta_pos1 = delta_uv1.x * T + delta_u.y * B
+        //     delta_pos2 = delta_uv2.x * T + delta_uv2.y * B
+        // Luckily, the place I found this equation provided
+        // the solution!
+        let determinant = delta_uv1.x * delta_uv2.y - delta_uv1.y * delta_uv2.x;
+        // Triangles without a texture mapping say nothing about the tangents
+        if determinant.abs() < f32::EPSILON {
+            continue;
+        }
+        let r = 1.0 / determinant;
+        let tangent = (delta_pos1 * delta_uv2.y - delta_pos2 * delta_uv1.y) * r;
+        let bitangent = (delta_pos2 * delta_uv1.x - delta_pos1 * delta_uv2.x) * r;
+
+        // We'll use the same tangent/bitangent for each vertex in the triangle
+        for &i in c {
+            let v = &mut vertices[i as usize];
+            v.tangent = (tangent + cgmath::Vector3::from(v.tangent)).into();
+            v.bitangent = (bitangent + cgmath::Vector3::from(v.bitangent)).into();
+        }
+    }
+
+    // Average the tangents/bitangents
+    for v in vertices {
+        let normal = cgmath::Vector3::from(v.normal);
+        let tangent = cgmath::Vector3::from(v.tangent);
+        if tangent.magnitude2() > 0.0 {
+            v.tangent = tangent.normalize().into();
+            v.bitangent = cgmath::Vector3::from(v.bitangent).normalize().into();
+        } else {
+            // Any frame around the normal will do when there is no texture mapping to follow
+            let (tangent, bitangent) = orthonormal_basis(normal);
+            v.tangent = tangent.into();
+            v.bitangent = bitangent.into();
+        }
+    }
+}
+
+// Two unit vectors perpendicular to the given normal and to each other
+fn orthonormal_basis(normal: cgmath::Vector3<f32>) -> (cgmath::Vector3<f32>, cgmath::Vector3<f32>) {
+    let helper = if normal.x.abs() > 0.9 { cgmath::Vector3::unit_y() } else { cgmath::Vector3::unit_x() };
+    let tangent = helper.cross(normal).normalize();
+    (tangent, normal.cross(tangent))
+}
+
+// Generated OBJ normals are smoothed across edges up to this angle, sharper edges stay hard
+const OBJ_CREASE_ANGLE: Deg<f32> = Deg(60.0);
+
+// Normals from the triangle faces, averaged over the triangles sharing each vertex. Where a triangle meets that average
+// at more than the crease angle it gets its own copy of the vertex with its flat face normal, so zero gives flat shading.
+#[allow(clippy::cast_possible_truncation)]
+fn compute_normals(vertices: &mut Vec<ModelVertex>, indices: &mut [u32], crease_angle: Rad<f32>) {
+    let face_normals: Vec<cgmath::Vector3<f32>> = indices.chunks(3)
+        .map(|c| {
+            let pos0 = cgmath::Vector3::from(vertices[c[0] as usize].position);
+            let pos1 = cgmath::Vector3::from(vertices[c[1] as usize].position);
+            let pos2 = cgmath::Vector3::from(vertices[c[2] as usize].position);
+            // Not normalized, so larger triangles weigh more
+            (pos1 - pos0).cross(pos2 - pos0)
+        })
+        .collect();
+    for (c, face_normal) in indices.chunks(3).zip(&face_normals) {
+        for &i in c {
+            let v = &mut vertices[i as usize];
+            v.normal = (face_normal + cgmath::Vector3::from(v.normal)).into();
+        }
+    }
+    for v in vertices.iter_mut() {
+        let normal = cgmath::Vector3::from(v.normal);
+        v.normal = if normal.magnitude2() > 0.0 { normal.normalize().into() } else { [0.0, 1.0, 0.0] };
+    }
+
+    // A little slack, so a crease angle of zero doesn't split vertices over rounding errors
+    let min_cos = crease_angle.0.cos() - 1e-4;
+    for (c, face_normal) in indices.chunks_mut(3).zip(face_normals) {
+        // Degenerate triangles have no direction to keep
+        if face_normal.magnitude2() == 0.0 {
+            continue;
+        }
+        let face_normal = face_normal.normalize();
+        for i in c {
+            let vertex = vertices[*i as usize];
+            if cgmath::Vector3::from(vertex.normal).dot(face_normal) < min_cos {
+                *i = vertices.len() as u32;
+                vertices.push(ModelVertex { normal: face_normal.into(), ..vertex });
+            }
+        }
+    }
+}
+
+// Reads the vertices and indices of a triangle primitive, in the space of its node
+fn gltf_primitive_geometry(
+    primitive: &gltf::Primitive,
+    buffers: &[gltf::buffer::Data],
+) -> Result<(Vec<ModelVertex>, Vec<u32>)> {
+    let reader = primitive.reader(|buffer| Some(&buffers[buffer.index()]));
+    let positions = reader.read_positions().context("glTF primitive has no positions")?;
+    let mut tex_coords = reader.read_tex_coords(0).map(gltf::mesh::util::ReadTexCoords::into_f32);
+    let mut normals = reader.read_normals();
+    let mut tangents = reader.read_tangents();
+    let has_normals = normals.is_some();
+    let has_tangents = tangents.is_some() && has_normals;
+
+    let mut vertices: Vec<ModelVertex> = positions
+        .map(|position| {
+            let normal = normals.as_mut().and_then(Iterator::next).unwrap_or([0.0; 3]);
+            let [tx, ty, tz, handedness] = tangents.as_mut().and_then(Iterator::next).unwrap_or([0.0; 4]);
+            // glTF stores the bitangent as a sign, it is the cross product of the normal and tangent
+            let bitangent = cgmath::Vector3::from(normal).cross(cgmath::Vector3::new(tx, ty, tz)) * handedness;
+            ModelVertex {
+                position,
+                tex_coords: tex_coords.as_mut().and_then(Iterator::next).unwrap_or([0.0; 2]),
+                normal,
+                tangent: [tx, ty, tz],
+                bitangent: bitangent.into(),
+            }
+        })
+        .collect();
+    // Unindexed primitives list every triangle's vertices in order
+    let mut indices: Vec<u32> = reader.read_indices().map_or_else(
+        || (0..).take(vertices.len()).collect(),
+        |indices| indices.into_u32().collect(),
     );
+
+    // The specification asks for flat normals when there are none
+    if !has_normals {
+        compute_normals(&mut vertices, &mut indices, Rad(0.0));
+    }
+    if !has_tangents {
+        compute_tangents(&mut vertices, &indices);
+    }
+
+    Ok((vertices, indices))
+}
+
+// Moves vertices into another space, such as a node's mesh into the space of its scene
+fn transform_vertices(vertices: &mut [ModelVertex], transform: Matrix4<f32>) {
+    // Normals need the inverse transpose to stay perpendicular to surfaces under non-uniform scale
+    let linear = Matrix3::from_cols(transform.x.truncate(), transform.y.truncate(), transform.z.truncate());
+    let normal_matrix = linear.invert().unwrap_or(linear).transpose();
+    for v in vertices {
+        v.position = transform.transform_point(cgmath::Point3::from(v.position)).into();
+        v.normal = (normal_matrix * cgmath::Vector3::from(v.normal)).normalize().into();
+        v.tangent = (linear * cgmath::Vector3::from(v.tangent)).normalize().into();
+        v.bitangent = (linear * cgmath::Vector3::from(v.bitangent)).normalize().into();
+    }
+}
+
+fn gltf_sampler_settings(sampler: &gltf::texture::Sampler) -> SamplerSettings {
+    use gltf::texture::{MagFilter, MinFilter, WrappingMode};
+    let address_mode = |mode| match mode {
+        WrappingMode::ClampToEdge => wgpu::AddressMode::ClampToEdge,
+        WrappingMode::MirroredRepeat => wgpu::AddressMode::MirrorRepeat,
+        WrappingMode::Repeat => wgpu::AddressMode::Repeat,
+    };
+    let mag_filter = match sampler.mag_filter() {
+        Some(MagFilter::Nearest) => wgpu::FilterMode::Nearest,
+        _ => wgpu::FilterMode::Linear,
+    };
+    // Textures have no mipmaps, so only the filtering within a level matters
+    let min_filter = match sampler.min_filter() {
+        Some(MinFilter::Nearest | MinFilter::NearestMipmapNearest | MinFilter::NearestMipmapLinear) => wgpu::FilterMode::Nearest,
+        _ => wgpu::FilterMode::Linear,
+    };
+    SamplerSettings {
+        address_mode_u: address_mode(sampler.wrap_s()),
+        address_mode_v: address_mode(sampler.wrap_t()),
+        mag_filter,
+        min_filter,
+    }
+}
+
+fn create_sampler(device: &wgpu::Device, settings: SamplerSettings) -> wgpu::Sampler {
+    device.create_sampler(&wgpu::SamplerDescriptor {
+        address_mode_u: settings.address_mode_u,
+        address_mode_v: settings.address_mode_v,
+        mag_filter: settings.mag_filter,
+        min_filter: settings.min_filter,
+        ..wgpu::SamplerDescriptor::default()
+    })
+}
+
+// glTF decodes images into whatever layout the file uses, the renderer wants 8 bit RGBA
+#[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+fn gltf_image_to_rgba(data: &gltf::image::Data) -> Result<image::RgbaImage> {
+    use gltf::image::Format;
+    let (channels, bytes_per_channel) = match data.format {
+        Format::R8 => (1, 1),
+        Format::R8G8 => (2, 1),
+        Format::R8G8B8 => (3, 1),
+        Format::R8G8B8A8 => (4, 1),
+        Format::R16 => (1, 2),
+        Format::R16G16 => (2, 2),
+        Format::R16G16B16 => (3, 2),
+        Format::R16G16B16A16 => (4, 2),
+        Format::R32G32B32FLOAT => (3, 4),
+        Format::R32G32B32A32FLOAT => (4, 4),
+    };
+    let channel = |bytes: &[u8]| match bytes {
+        [value] => *value,
+        // Little endian, so the high byte comes last
+        [_, high] => *high,
+        [a, b, c, d] => (f32::from_le_bytes([*a, *b, *c, *d]).clamp(0.0, 1.0) * 255.0).round() as u8,
+        _ => 0,
+    };
+    let pixels = data.pixels
+        .chunks_exact(channels * bytes_per_channel)
+        .flat_map(|pixel| {
+            let mut rgba = [0, 0, 0, 255];
+            for (value, bytes) in rgba.iter_mut().zip(pixel.chunks_exact(bytes_per_channel)) {
+                *value = channel(bytes);
+            }
+            // Single channel images are grayscale
+            if channels == 1 {
+                rgba[1] = rgba[0];
+                rgba[2] = rgba[0];
+            }
+            rgba
+        })
+        .collect();
+    image::RgbaImage::from_raw(data.width, data.height, pixels)
+        .ok_or_else(|| anyhow!("glTF image of {}x{} pixels has too little data", data.width, data.height))
+}
+
+pub trait Draw<'a> {
     fn draw_mesh_instanced(
         &mut self,
         mesh: &'a Mesh,
@@ -255,44 +896,12 @@ pub trait Draw<'a> {
         camera: &'a wgpu::BindGroup,
         light: &'a wgpu::BindGroup,
     );
-
-    fn draw_model(
-        &mut self,
-        model: &'a Model,
-        camera: &'a wgpu::BindGroup,
-        light: &'a wgpu::BindGroup,
-    );
-    fn draw_model_instanced(
-        &mut self,
-        model: &'a Model,
-        instances: Range<u32>,
-        camera: &'a wgpu::BindGroup,
-        light: &'a wgpu::BindGroup,
-    );
-    fn draw_model_instanced_with_material(
-        &mut self,
-        model: &'a Model,
-        material: &'a Material,
-        instances: Range<u32>,
-        camera: &'a wgpu::BindGroup,
-        light: &'a wgpu::BindGroup,
-    );
 }
 
 impl<'a, 'b> Draw<'b> for wgpu::RenderPass<'a>
 where
     'b: 'a,
 {
-    fn draw_mesh(
-        &mut self,
-        mesh: &'b Mesh,
-        material: &'b Material,
-        camera: &'b wgpu::BindGroup,
-        light: &'b wgpu::BindGroup,
-    ) {
-        self.draw_mesh_instanced(mesh, material, 0..1, camera, light);
-    }
-
     fn draw_mesh_instanced(
         &mut self,
         mesh: &'b Mesh,
@@ -308,41 +917,6 @@ where
         self.set_bind_group(2, light, &[]);
         self.draw_indexed(0..mesh.num_elements, 0, instances);
     }
-
-    fn draw_model(
-        &mut self,
-        model: &'b Model,
-        camera: &'b wgpu::BindGroup,
-        light: &'b wgpu::BindGroup,
-    ) {
-        self.draw_model_instanced(model, 0..1, camera, light);
-    }
-
-    fn draw_model_instanced(
-        &mut self,
-        model: &'b Model,
-        instances: Range<u32>,
-        camera: &'b wgpu::BindGroup,
-        light: &'b wgpu::BindGroup,
-    ) {
-        for mesh in &model.meshes {
-            let material = &model.materials[mesh.material];
-            self.draw_mesh_instanced(mesh, material, instances.clone(), camera, light);
-        }
-    }
-
-    fn draw_model_instanced_with_material(
-        &mut self,
-        model: &'b Model,
-        material: &'b Material,
-        instances: Range<u32>,
-        camera: &'b wgpu::BindGroup,
-        light: &'b wgpu::BindGroup,
-    ) {
-        for mesh in &model.meshes {
-            self.draw_mesh_instanced(mesh, material, instances.clone(), camera, light);
-        }
-    }
 }
 
 pub trait DrawLight<'a> {
@@ -423,44 +997,215 @@ where
     }
 }
 
+// Draws only the geometry, the caller is responsible for the pipeline and bind groups
+pub trait DrawShadow<'a> {
+    fn draw_shadow_mesh_instanced(
+        &mut self,
+        mesh: &'a Mesh,
+        instances: Range<u32>,
+    );
+    fn draw_shadow_model_instanced(
+        &mut self,
+        model: &'a Model,
+        instances: Range<u32>,
+    );
+}
+
+impl<'a, 'b> DrawShadow<'b> for wgpu::RenderPass<'a>
+where
+    'b: 'a,
+{
+    fn draw_shadow_mesh_instanced(
+        &mut self,
+        mesh: &'b Mesh,
+        instances: Range<u32>,
+    ) {
+        self.set_vertex_buffer(0, mesh.vertex_buffer.slice(..));
+        self.set_index_buffer(mesh.index_buffer.slice(..), wgpu::IndexFormat::Uint32);
+        self.draw_indexed(0..mesh.num_elements, 0, instances);
+    }
+
+    fn draw_shadow_model_instanced(
+        &mut self,
+        model: &'b Model,
+        instances: Range<u32>,
+    ) {
+        for mesh in &model.meshes {
+            self.draw_shadow_mesh_instanced(mesh, instances.clone());
+        }
+    }
+}
+
 impl Material {
     pub fn new(
         device: &wgpu::Device,
-        name: &str, 
-        diffuse_texture: crate::texture::Texture, 
-        normal_texture: crate::texture::Texture,
-        layout: &wgpu::BindGroupLayout,
+        assets: &AssetServer,
+        name: &str,
+        textures: MaterialTextures,
+        factors: MaterialFactors,
     ) -> Self {
-        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
-            layout,
-            entries: &[
-                wgpu::BindGroupEntry {
-                    binding: 0,
-                    resource: wgpu::BindingResource::TextureView(&diffuse_texture.view),
-                },
-                wgpu::BindGroupEntry {
-                    binding: 1,
-                    resource: wgpu::BindingResource::Sampler(&diffuse_texture.sampler),
-                },
+        let defaults = assets.default_textures();
+        let base_color_texture = textures.base_color.unwrap_or_else(|| defaults.white.clone());
+        let metallic_roughness_texture = textures.metallic_roughness.unwrap_or_else(|| defaults.white_linear.clone());
+        let occlusion_texture = textures.occlusion.unwrap_or_else(|| defaults.white_linear.clone());
+        let emissive_texture = textures.emissive.unwrap_or_else(|| defaults.white.clone());
+        let normal_texture = textures.normal.unwrap_or_else(|| defaults.normal.clone());
+
+        let factor_buffer = device.create_buffer_init(
+            &wgpu::util::BufferInitDescriptor {
+                label: Some(&format!("{name} Material Buffer")),
+                contents: bytemuck::cast_slice(&[factors.to_raw()]),
+                usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            }
+        );
+
+        let textures = [
+            &base_color_texture,
+            &metallic_roughness_texture,
+            &occlusion_texture,
+            &emissive_texture,
+            &normal_texture,
+        ];
+        let mut entries: Vec<wgpu::BindGroupEntry> = (0u32..).step_by(2).zip(textures)
+            .flat_map(|(binding, texture)| [
                 wgpu::BindGroupEntry {
-                    binding: 2,
-                    resource: wgpu::BindingResource::TextureView(&normal_texture.view),
+                    binding,
+                    resource: wgpu::BindingResource::TextureView(&texture.view),
                 },
                 wgpu::BindGroupEntry {
-                    binding: 3,
-                    resource: wgpu::BindingResource::Sampler(&normal_texture.sampler),
+                    binding: binding + 1,
+                    resource: wgpu::BindingResource::Sampler(&texture.sampler),
                 },
-            ],
+            ])
+            .collect();
+        entries.push(wgpu::BindGroupEntry {
+            binding: MATERIAL_FACTOR_BINDING,
+            resource: factor_buffer.as_entire_binding(),
+        });
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout: assets.material_bind_group_layout(),
+            entries: &entries,
             label: Some(name),
         });
 
-        Self { 
+        Self {
             name: String::from(name),
-            diffuse_texture,
+            base_color_texture,
+            metallic_roughness_texture,
+            occlusion_texture,
+            emissive_texture,
             normal_texture,
+            factors,
+            factor_buffer,
             bind_group,
         }
     }
+
+    // Texture and sampler pairs for base color, metallic-roughness, occlusion, emissive and normal,
+    // followed by the factors
+    pub fn create_bind_group_layout(device: &wgpu::Device) -> wgpu::BindGroupLayout {
+        let mut entries: Vec<wgpu::BindGroupLayoutEntry> = (0..MATERIAL_TEXTURE_COUNT * 2).step_by(2)
+            .flat_map(|binding| [
+                wgpu::BindGroupLayoutEntry {
+                    binding,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Texture {
+                        multisampled: false,
+                        view_dimension: wgpu::TextureViewDimension::D2,
+                        sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: binding + 1,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+                    count: None,
+                },
+            ])
+            .collect();
+        entries.push(wgpu::BindGroupLayoutEntry {
+            binding: MATERIAL_FACTOR_BINDING,
+            visibility: wgpu::ShaderStages::FRAGMENT,
+            ty: wgpu::BindingType::Buffer {
+                ty: wgpu::BufferBindingType::Uniform,
+                has_dynamic_offset: false,
+                min_binding_size: None,
+            },
+            count: None,
+        });
+        device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            entries: &entries,
+            label: Some("material_bind_group_layout"),
+        })
+    }
+}
+
+const MATERIAL_TEXTURE_COUNT: u32 = 5;
+const MATERIAL_FACTOR_BINDING: u32 = MATERIAL_TEXTURE_COUNT * 2;
+
+// Decodes the textures of an OBJ material, reusing the images other materials of the file already loaded
+fn obj_material_data(
+    mat: tobj::Material,
+    containing_folder: &Path,
+    obj_path: &Path,
+    loaded_images: &mut HashMap<(String, bool), Arc<ImageData>>,
+) -> Result<MaterialData> {
+    let mut load_image = |file: &str, is_linear: bool| -> Result<Option<Arc<ImageData>>> {
+        if file.is_empty() {
+            return Ok(None);
+        }
+        if let Some(loaded) = loaded_images.get(&(file.to_string(), is_linear)) {
+            return Ok(Some(loaded.clone()));
+        }
+        let image_path = containing_folder.join(file);
+        let image = image::open(&image_path)
+            .with_context(|| format!(
+                "Failed to load texture {file} of material {} in {}",
+                mat.name,
+                obj_path.display(),
+            ))?;
+        let loaded = Arc::new(ImageData { path: Some(image_path), image, is_linear, sampler: None });
+        loaded_images.insert((file.to_string(), is_linear), loaded.clone());
+        Ok(Some(loaded))
+    };
+    let images = MaterialImages {
+        base_color: load_image(&mat.diffuse_texture, false)?,
+        normal: load_image(&mat.normal_texture, true)?,
+        emissive: load_image(mat.unknown_param.get("map_Ke").map_or("", String::as_str), false)?,
+        ..MaterialImages::default()
+    };
+
+    Ok(MaterialData {
+        factors: obj_material_factors(&mat),
+        name: mat.name,
+        images,
+    })
+}
+
+// MTL files describe Phong materials, so translate them using the PBR extension parameters (Pr, Pm, Ke) where present
+fn obj_material_factors(mat: &tobj::Material) -> MaterialFactors {
+    let param = |key: &str| mat.unknown_param.get(key).and_then(|value| value.trim().parse::<f32>().ok());
+    let vector_param = |key: &str| -> Option<[f32; 3]> {
+        let values: Vec<f32> = mat.unknown_param.get(key)?
+            .split_whitespace()
+            .filter_map(|value| value.parse().ok())
+            .collect();
+        values.try_into().ok()
+    };
+    // The diffuse color only stands in for a missing diffuse texture, Blender exports it alongside textures too
+    let [r, g, b] = if mat.diffuse_texture.is_empty() { mat.diffuse } else { [1.0; 3] };
+    // Common approximation mapping a Phong exponent onto GGX roughness, without a specular color there is no highlight at all
+    let roughness = param("Pr").unwrap_or_else(|| {
+        if mat.specular.iter().all(|&channel| channel <= 0.0) { 1.0 } else { (2.0 / (mat.shininess + 2.0)).sqrt() }
+    });
+    MaterialFactors {
+        base_color: [r, g, b, mat.dissolve],
+        metallic: param("Pm").unwrap_or(0.0),
+        roughness,
+        emissive: vector_param("Ke").unwrap_or([0.0; 3]),
+        ..MaterialFactors::default()
+    }
 }
 
 
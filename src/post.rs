@@ -0,0 +1,347 @@
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+use wgpu::util::DeviceExt;
+
+use crate::graph::{RenderGraph, TextureDesc, TextureId};
+
+// The scene is drawn in this format, so light brighter than the screen can show isn't clipped before tonemapping
+pub const HDR_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rgba16Float;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Tonemapper {
+    // Filmic curve with more contrast, which washes the brightest colors out to white
+    Aces,
+    // color / (1 + color), which keeps the hue of bright colors
+    Reinhard,
+}
+
+// A step of the post-processing chain. The passes run in order on the scene's HDR image before it is shown.
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "pass", rename_all = "snake_case")]
+pub enum PostPass {
+    // Adds a blurred copy of everything brighter than the threshold, so bright lights glow
+    Bloom { threshold: f32, intensity: f32, blur_iterations: u32 },
+    // Scales the colors, usually before tonemapping
+    Exposure { exposure: f32 },
+    // Maps the colors into the range the screen can show. Anything above 1 clips if the chain doesn't have one.
+    Tonemap { tonemapper: Tonemapper },
+}
+
+impl PostPass {
+    pub fn default_chain() -> Vec<Self> {
+        vec![
+            Self::Bloom { threshold: 1.0, intensity: 0.3, blur_iterations: 3 },
+            Self::Exposure { exposure: 1.0 },
+            Self::Tonemap { tonemapper: Tonemapper::Aces },
+        ]
+    }
+}
+
+// The fragment entry points in post.wgsl, each gets its own pipeline
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+enum Effect {
+    BrightPass,
+    Blur,
+    Composite,
+    Exposure,
+    TonemapAces,
+    TonemapReinhard,
+    Blit,
+}
+
+impl Effect {
+    const ALL: [Self; 7] = [
+        Self::BrightPass,
+        Self::Blur,
+        Self::Composite,
+        Self::Exposure,
+        Self::TonemapAces,
+        Self::TonemapReinhard,
+        Self::Blit,
+    ];
+
+    const fn entry_point(self) -> &'static str {
+        match self {
+            Self::BrightPass => "bright_pass",
+            Self::Blur => "blur",
+            Self::Composite => "composite",
+            Self::Exposure => "exposure",
+            Self::TonemapAces => "tonemap_aces",
+            Self::TonemapReinhard => "tonemap_reinhard",
+            Self::Blit => "blit",
+        }
+    }
+}
+
+// The images the steps read and write. Every step writes a new texture, the render graph reuses the ones that
+// are no longer needed.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+enum Slot {
+    Hdr,
+    // Half the size of the frame, which is cheaper to blur and makes the blur reach twice as far
+    Bloom,
+    Frame,
+}
+
+// A single full screen draw
+struct Step {
+    effect: Effect,
+    input: Slot,
+    // Bound next to the input by the effects that combine two images
+    second: Option<Slot>,
+    params: wgpu::Buffer,
+    output: Slot,
+}
+
+// Runs the post-processing passes on the HDR image of the scene and writes the result into the frame
+pub struct PostProcessing {
+    passes: Vec<PostPass>,
+    bind_group_layout: wgpu::BindGroupLayout,
+    sampler: wgpu::Sampler,
+    pipelines: HashMap<Effect, wgpu::RenderPipeline>,
+    width: u32,
+    height: u32,
+    steps: Vec<Step>,
+}
+
+impl PostProcessing {
+    pub fn new(device: &wgpu::Device, frame_format: wgpu::TextureFormat, width: u32, height: u32, passes: Vec<PostPass>) -> Self {
+        let bind_group_layout = create_bind_group_layout(device);
+        let shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some("Post Processing Shader"),
+            source: wgpu::ShaderSource::Wgsl(include_str!("shaders/wgpu_0.13/post.wgsl").into()),
+        });
+        let layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("Post Processing Pipeline Layout"),
+            bind_group_layouts: &[&bind_group_layout],
+            push_constant_ranges: &[],
+        });
+        let pipelines = Effect::ALL
+            .into_iter()
+            .map(|effect| {
+                let format = if effect == Effect::Blit { frame_format } else { HDR_FORMAT };
+                (effect, create_pipeline(device, &layout, &shader, effect, format))
+            })
+            .collect();
+        let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            label: Some("post_processing_sampler"),
+            address_mode_u: wgpu::AddressMode::ClampToEdge,
+            address_mode_v: wgpu::AddressMode::ClampToEdge,
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
+            ..wgpu::SamplerDescriptor::default()
+        });
+
+        let mut post_processing = Self {
+            passes,
+            bind_group_layout,
+            sampler,
+            pipelines,
+            width,
+            height,
+            steps: Vec::new(),
+        };
+        post_processing.steps = post_processing.build_steps(device);
+        post_processing
+    }
+
+    pub fn passes(&self) -> &[PostPass] {
+        &self.passes
+    }
+
+    pub fn set_passes(&mut self, device: &wgpu::Device, passes: Vec<PostPass>) {
+        self.passes = passes;
+        self.steps = self.build_steps(device);
+    }
+
+    // The blur steps are given the size of a texel
+    pub fn resize(&mut self, device: &wgpu::Device, width: u32, height: u32) {
+        self.width = width;
+        self.height = height;
+        self.steps = self.build_steps(device);
+    }
+
+    // Adds a pass per step, reading the scene and ending with the copy into the frame
+    pub fn add_passes<'a>(&'a self, graph: &mut RenderGraph<'a>, scene: TextureId, frame: TextureId) {
+        let mut latest = HashMap::from([(Slot::Hdr, scene), (Slot::Frame, frame)]);
+        for step in &self.steps {
+            let mut pass = graph.add_pass(step.effect.entry_point());
+            let input = pass.read_texture(latest[&step.input]);
+            let second = step.second.map(|second| pass.read_texture(latest[&second]));
+            let output = match step.output {
+                Slot::Frame => pass.write_texture(frame),
+                Slot::Hdr => pass.create_texture("hdr_target", target_desc(self.width, self.height)),
+                Slot::Bloom => pass.create_texture("bloom_target", target_desc(self.width / 2, self.height / 2)),
+            };
+            latest.insert(step.output, output);
+            pass.execute(move |context| {
+                let input = context.texture(input);
+                let second = second.map_or(input, |second| context.texture(second));
+                let bind_group = self.bind_group(context.device, input, second, &step.params);
+                let view = context.texture(output);
+                let mut render_pass = context.encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                    label: Some("Post Processing Pass"),
+                    color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                        view,
+                        resolve_target: None,
+                        ops: wgpu::Operations {
+                            // Every pixel is overwritten
+                            load: wgpu::LoadOp::Clear(wgpu::Color::BLACK),
+                            store: true,
+                        },
+                    })],
+                    depth_stencil_attachment: None,
+                });
+                render_pass.set_pipeline(&self.pipelines[&step.effect]);
+                render_pass.set_bind_group(0, &bind_group, &[]);
+                render_pass.draw(0..3, 0..1);
+            });
+        }
+    }
+
+    // Turns the passes into the draws that implement them, ending with the copy into the frame
+    fn build_steps(&self, device: &wgpu::Device) -> Vec<Step> {
+        let step = |effect, input, second, params: [f32; 4], output| Step {
+            effect,
+            input,
+            second,
+            params: device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                label: Some("Post Processing Params Buffer"),
+                contents: bytemuck::cast_slice(&params),
+                usage: wgpu::BufferUsages::UNIFORM,
+            }),
+            output,
+        };
+        let mut steps = Vec::new();
+        for pass in &self.passes {
+            match *pass {
+                PostPass::Bloom { threshold, intensity, blur_iterations } => {
+                    let texel_width = 1.0 / (self.width / 2).max(1) as f32;
+                    let texel_height = 1.0 / (self.height / 2).max(1) as f32;
+                    steps.push(step(Effect::BrightPass, Slot::Hdr, None, [threshold, 0.0, 0.0, 0.0], Slot::Bloom));
+                    for _ in 0..blur_iterations {
+                        steps.push(step(Effect::Blur, Slot::Bloom, None, [texel_width, 0.0, 0.0, 0.0], Slot::Bloom));
+                        steps.push(step(Effect::Blur, Slot::Bloom, None, [0.0, texel_height, 0.0, 0.0], Slot::Bloom));
+                    }
+                    steps.push(step(Effect::Composite, Slot::Hdr, Some(Slot::Bloom), [intensity, 0.0, 0.0, 0.0], Slot::Hdr));
+                }
+                PostPass::Exposure { exposure } => {
+                    steps.push(step(Effect::Exposure, Slot::Hdr, None, [exposure, 0.0, 0.0, 0.0], Slot::Hdr));
+                }
+                PostPass::Tonemap { tonemapper } => {
+                    let effect = match tonemapper {
+                        Tonemapper::Aces => Effect::TonemapAces,
+                        Tonemapper::Reinhard => Effect::TonemapReinhard,
+                    };
+                    steps.push(step(effect, Slot::Hdr, None, [0.0; 4], Slot::Hdr));
+                }
+            }
+        }
+        steps.push(step(Effect::Blit, Slot::Hdr, None, [0.0; 4], Slot::Frame));
+        steps
+    }
+
+    fn bind_group(&self, device: &wgpu::Device, input: &wgpu::TextureView, second: &wgpu::TextureView, params: &wgpu::Buffer) -> wgpu::BindGroup {
+        device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout: &self.bind_group_layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: wgpu::BindingResource::TextureView(input),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::TextureView(second),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: wgpu::BindingResource::Sampler(&self.sampler),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 3,
+                    resource: params.as_entire_binding(),
+                },
+            ],
+            label: Some("post_processing_bind_group"),
+        })
+    }
+}
+
+const fn target_desc(width: u32, height: u32) -> TextureDesc {
+    TextureDesc {
+        width: if width > 0 { width } else { 1 },
+        height: if height > 0 { height } else { 1 },
+        format: HDR_FORMAT,
+        sample_count: 1,
+        usage: wgpu::TextureUsages::RENDER_ATTACHMENT.union(wgpu::TextureUsages::TEXTURE_BINDING),
+    }
+}
+
+fn create_bind_group_layout(device: &wgpu::Device) -> wgpu::BindGroupLayout {
+    let texture_entry = |binding| wgpu::BindGroupLayoutEntry {
+        binding,
+        visibility: wgpu::ShaderStages::FRAGMENT,
+        ty: wgpu::BindingType::Texture {
+            multisampled: false,
+            view_dimension: wgpu::TextureViewDimension::D2,
+            sample_type: wgpu::TextureSampleType::Float { filterable: true },
+        },
+        count: None,
+    };
+    device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+        entries: &[
+            texture_entry(0),
+            texture_entry(1),
+            wgpu::BindGroupLayoutEntry {
+                binding: 2,
+                visibility: wgpu::ShaderStages::FRAGMENT,
+                ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+                count: None,
+            },
+            wgpu::BindGroupLayoutEntry {
+                binding: 3,
+                visibility: wgpu::ShaderStages::FRAGMENT,
+                ty: wgpu::BindingType::Buffer {
+                    ty: wgpu::BufferBindingType::Uniform,
+                    has_dynamic_offset: false,
+                    min_binding_size: None,
+                },
+                count: None,
+            },
+        ],
+        label: Some("post_processing_bind_group_layout"),
+    })
+}
+
+fn create_pipeline(
+    device: &wgpu::Device,
+    layout: &wgpu::PipelineLayout,
+    shader: &wgpu::ShaderModule,
+    effect: Effect,
+    format: wgpu::TextureFormat,
+) -> wgpu::RenderPipeline {
+    device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+        label: Some(effect.entry_point()),
+        layout: Some(layout),
+        vertex: wgpu::VertexState {
+            module: shader,
+            entry_point: "vs_main",
+            buffers: &[],
+        },
+        fragment: Some(wgpu::FragmentState {
+            module: shader,
+            entry_point: effect.entry_point(),
+            targets: &[Some(wgpu::ColorTargetState {
+                format,
+                blend: None,
+                write_mask: wgpu::ColorWrites::ALL,
+            })],
+        }),
+        primitive: wgpu::PrimitiveState::default(),
+        depth_stencil: None,
+        multisample: wgpu::MultisampleState::default(),
+        multiview: None,
+    })
+}
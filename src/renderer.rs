@@ -1,53 +1,141 @@
-use std::sync::Arc;
-use legion::{World, IntoQuery};
+use std::{cell::RefCell, panic::AssertUnwindSafe, path::{Path, PathBuf}, sync::{atomic::{AtomicBool, Ordering}, Arc}};
+use anyhow::{bail, Result};
+use legion::{Entity, World};
 use wgpu::{SurfaceConfiguration, util::DeviceExt};
 use winit::window::Window;
-use crate::{model::{Vertex, self, Draw, Model, DrawLight}, texture::{self, Texture}, camera::{self, Camera}, transform::{self, Transform}, light::{Light, self}};
+use crate::{asset::{AssetServer, Handle, LoadState}, capture::{self, Capture}, cluster::ClusteredLighting, config::{Backend, PresentMode, RendererConfig, Shading}, culling::CullingStats, deferred::{self, DeferredShading}, graph::{BufferId, RenderGraph, TextureDesc, TextureId, TexturePool}, instance::InstanceBatches, post::{PostPass, PostProcessing, HDR_FORMAT}, shadow::ShadowMaps, skybox::{self, Skybox, SkyboxSource}, model::{Vertex, self, Model, DrawLight}, texture, camera::{self, Camera}, transform, light::{Light, LightList, SceneLighting, self}};
 
 
+// Room for this many lights is allocated up front, the light buffer grows when there are more
+const MIN_LIGHT_CAPACITY: usize = 8;
+// The MSAA sample counts that are offered, if the adapter supports them
+const SAMPLE_COUNTS: [u32; 4] = [1, 2, 4, 8];
+
+// Why a renderer couldn't be created or reconfigured
+#[derive(Debug, thiserror::Error)]
+pub enum RendererError {
+    #[error("Could not create a surface for the window")]
+    CreateSurface(#[from] wgpu::CreateSurfaceError),
+    #[error("No adapter found for the {backend:?} backend, adapters on this machine: {}", describe_adapters(.available))]
+    NoAdapter {
+        backend: Backend,
+        // Every adapter of every backend, so the error shows which backend could be picked instead
+        available: Vec<wgpu::AdapterInfo>,
+    },
+    #[error("{} can't present to the window's surface", describe_adapter(.adapter))]
+    IncompatibleSurface { adapter: wgpu::AdapterInfo },
+    #[error("Could not get a device from {}", describe_adapter(.adapter))]
+    RequestDevice {
+        adapter: wgpu::AdapterInfo,
+        #[source]
+        source: wgpu::RequestDeviceError,
+    },
+    #[error("MSAA with {requested} samples isn't supported by {}, use one of {supported:?}", describe_adapter(.adapter))]
+    UnsupportedSampleCount {
+        adapter: wgpu::AdapterInfo,
+        requested: u32,
+        supported: Vec<u32>,
+    },
+    #[error("Present mode {requested:?} isn't supported by the surface, use one of {supported:?}")]
+    UnsupportedPresentMode {
+        requested: wgpu::PresentMode,
+        supported: Vec<wgpu::PresentMode>,
+    },
+    #[error("Could not load the models the renderer draws itself")]
+    Assets(#[source] Box<dyn std::error::Error + Send + Sync>),
+}
+
+// What the scene was drawn into, for the passes drawing on top of it
+#[derive(Copy, Clone)]
+struct SceneTargets {
+    // The HDR image of the scene
+    color: TextureId,
+    // The samples resolved into color, when drawing with MSAA
+    msaa: Option<TextureId>,
+    depth: TextureId,
+}
+
+// Where the renderer draws its frames
+enum Target {
+    Surface(wgpu::Surface),
+    // Used when rendering headless, e.g. in CI or tests where no window exists
+    Offscreen(texture::Texture),
+}
+
 pub struct Renderer {
-    surface: wgpu::Surface,
+    // Kept to request a new device from when the current one is lost
+    instance: wgpu::Instance,
+    config: RendererConfig,
+    force_fallback_adapter: bool,
+    // Set when wgpu reports that the device is lost, until recover has replaced it
+    device_lost: Arc<AtomicBool>,
+    target: Target,
     pub surface_config: SurfaceConfiguration,
     pub device: wgpu::Device,
     pub queue: wgpu::Queue,
     pub size: winit::dpi::PhysicalSize<u32>,
     clear_color: wgpu::Color,
     render_pipeline:wgpu::RenderPipeline,
-    depth_texture: texture::Texture,
+    adapter_info: wgpu::AdapterInfo,
+    sample_count: u32,
+    supported_sample_counts: Vec<u32>,
+    // The present modes the surface supports, empty when rendering offscreen
+    supported_present_modes: Vec<wgpu::PresentMode>,
+    // Only created when the config asks for deferred shading
+    deferred: Option<DeferredShading>,
+    // Light culling for forward shading
+    clusters: ClusteredLighting,
+    // Turns the HDR image of the scene into the frame
+    post_processing: PostProcessing,
+    // The render graph's transient textures. Drawing only needs a shared borrow of the renderer, so the pool is
+    // borrowed mutably while the graph runs.
+    texture_pool: RefCell<TexturePool>,
     light_render_pipeline: wgpu::RenderPipeline,
-    _debug_light_model: Model,
+    // Drawn behind the scene instead of the clear color, when set
+    skybox: Option<Skybox>,
+    skybox_pipeline: wgpu::RenderPipeline,
+    debug_light_model: Handle<Model>,
     light_buffer: wgpu::Buffer,
     light_bind_group: wgpu::BindGroup,
-    num_lights: usize,
+    // Number of lights the light buffer has room for
+    light_capacity: usize,
+    // What was last written to the light buffer, so unchanged lights aren't uploaded again
+    lights: LightList,
+    // The camera position the shadow maps were last focused on
+    shadow_focus: Option<cgmath::Point3<f32>>,
+    shadow_maps: ShadowMaps,
+    scene_lighting: SceneLighting,
+    scene_lighting_buffer: wgpu::Buffer,
+    capture: Option<Capture>,
+    capture_writer: capture::Writer,
+    pub assets: AssetServer,
+    instance_batches: InstanceBatches,
+    culling_stats: CullingStats,
 }
 
 impl Renderer {
-    pub async fn new(window: &Window, _init_light: &Light) -> Self {
+    pub async fn new(window: &Window, _init_light: &Light, config: &RendererConfig) -> Result<Self, RendererError> {
         let size = window.inner_size();
         let instance = wgpu::Instance::new(wgpu::InstanceDescriptor {
-            backends: wgpu::Backends::VULKAN,
+            backends: config.backend.to_backends(),
             dx12_shader_compiler: Default::default(),
         });
-        let surface = unsafe { instance.create_surface(window) }.unwrap();
+        let surface = unsafe { instance.create_surface(window) }?;
 
         let adapter = instance.request_adapter(
             &wgpu::RequestAdapterOptions {
-                power_preference: wgpu::PowerPreference::default(),
+                power_preference: config.power_preference.to_wgpu(),
                 compatible_surface: Some(&surface),
                 force_fallback_adapter: false,
             },
-        ).await.unwrap();
+        ).await.ok_or_else(|| no_adapter(config.backend))?;
 
-        let (device, queue) = adapter.request_device(
-            &wgpu::DeviceDescriptor {
-                features: wgpu::Features::empty(),
-                limits: wgpu::Limits::default(),
-                label: None,
-            },
-            None,
-        ).await.unwrap();
+        let (device, queue) = request_device(&adapter, config).await?;
 
         let surface_caps = surface.get_capabilities(&adapter);
+        if surface_caps.formats.is_empty() {
+            return Err(RendererError::IncompatibleSurface { adapter: adapter.get_info() });
+        }
         // Shader code in this tutorial assumes an Srgb surface texture. Using a different
         // one will result all the colors comming out darker. If you want to support non
         // Srgb surfaces, you'll need to account for that when drawing to the frame.
@@ -56,98 +144,201 @@ impl Renderer {
             .filter(|f| f.is_srgb())
             .next()
             .unwrap_or(surface_caps.formats[0]);
+        // Frames can only be copied out of the swapchain for screenshots if the surface allows it
+        let usage = if surface_caps.usages.contains(wgpu::TextureUsages::COPY_SRC) {
+            wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::COPY_SRC
+        } else {
+            wgpu::TextureUsages::RENDER_ATTACHMENT
+        };
+        let present_mode = config.present_mode.to_wgpu();
+        check_present_mode(present_mode, &surface_caps.present_modes)?;
         let surface_config = wgpu::SurfaceConfiguration {
-            usage: wgpu::TextureUsages::RENDER_ATTACHMENT,
+            usage,
             format: surface_format,
             width: size.width,
             height: size.height,
-            present_mode: wgpu::PresentMode::Fifo,
+            present_mode,
             alpha_mode: wgpu::CompositeAlphaMode::Auto,
             view_formats: vec![]
         };
         surface.configure(&device, &surface_config);
+
+        let mut renderer = Self::from_device(instance, &adapter, device, queue, Target::Surface(surface), surface_config, config)?;
+        renderer.supported_present_modes = surface_caps.present_modes;
+        Ok(renderer)
+    }
+
+    // Renders into an offscreen texture instead of a window, read frames back with render_to_image.
+    // Setting force_fallback_adapter picks a software adapter, so this also works on machines without a GPU.
+    pub async fn new_headless(width: u32, height: u32, force_fallback_adapter: bool, config: &RendererConfig) -> Result<Self, RendererError> {
+        let instance = wgpu::Instance::new(wgpu::InstanceDescriptor {
+            backends: config.backend.to_backends(),
+            dx12_shader_compiler: wgpu::Dx12Compiler::default(),
+        });
+
+        let adapter = instance.request_adapter(
+            &wgpu::RequestAdapterOptions {
+                power_preference: config.power_preference.to_wgpu(),
+                compatible_surface: None,
+                force_fallback_adapter,
+            },
+        ).await.ok_or_else(|| no_adapter(config.backend))?;
+
+        let (device, queue) = request_device(&adapter, config).await?;
+
+        // There is no surface, but the configuration still describes the target the pipelines draw into
+        let surface_config = wgpu::SurfaceConfiguration {
+            usage: wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::COPY_SRC,
+            format: wgpu::TextureFormat::Rgba8UnormSrgb,
+            width,
+            height,
+            present_mode: config.present_mode.to_wgpu(),
+            alpha_mode: wgpu::CompositeAlphaMode::Auto,
+            view_formats: vec![]
+        };
+        let target = texture::Texture::create_render_target(&device, &surface_config, "offscreen_target");
+
+        let mut renderer = Self::from_device(instance, &adapter, device, queue, Target::Offscreen(target), surface_config, config)?;
+        renderer.force_fallback_adapter = force_fallback_adapter;
+        Ok(renderer)
+    }
+
+    fn from_device(
+        instance: wgpu::Instance,
+        adapter: &wgpu::Adapter,
+        device: wgpu::Device,
+        queue: wgpu::Queue,
+        target: Target,
+        surface_config: SurfaceConfiguration,
+        config: &RendererConfig,
+    ) -> Result<Self, RendererError> {
+        let size = winit::dpi::PhysicalSize::new(surface_config.width, surface_config.height);
+        let device_lost = Arc::new(AtomicBool::new(false));
+        watch_for_device_loss(&device, &device_lost);
+        let adapter_info = adapter.get_info();
+        let supported_sample_counts = supported_sample_counts(adapter, &device, HDR_FORMAT);
+        let sample_count = config.sample_count;
+        if !supported_sample_counts.contains(&sample_count) {
+            return Err(RendererError::UnsupportedSampleCount {
+                adapter: adapter_info,
+                requested: sample_count,
+                supported: supported_sample_counts,
+            });
+        }
         
-        let depth_texture = texture::Texture::create_depth_texture(&device, &surface_config, "depth_texture");
-        let clear_color = wgpu::Color { r: 0.1, g: 0.2, b: 0.3, a: 1.0 };
+        let post_processing = PostProcessing::new(
+            &device,
+            surface_config.format,
+            surface_config.width,
+            surface_config.height,
+            config.post_processing.clone(),
+        );
+        let [r, g, b, a] = config.clear_color;
+        let clear_color = wgpu::Color { r, g, b, a };
 
         let light_bind_group_layout = light::Raw::create_bind_group_layout(&device);
-        let texture_bind_group_layout = Texture::create_bind_group_layout(&device);
+        let mut assets = AssetServer::new(&device, &queue);
         let camera_bind_group_layout = camera::Raw::create_bind_group_layout(&device);
         
 
-        let light_buffer = device.create_buffer(
-            &wgpu::BufferDescriptor {
-                size: 1,
-                label: Some("Light VB"),
-                usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST,
-                mapped_at_creation: false,
-            }
-        );
+        let light_buffer = create_light_buffer(&device, MIN_LIGHT_CAPACITY);
 
-        let light_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
-            layout: &light::Raw::create_bind_group_layout(&device),
-            entries: &[
-                wgpu::BindGroupEntry {
-                    binding: 0,
-                    resource: light_buffer.as_entire_binding(),
-                },
-            ],
-            label: None,
-        });
+        let shadow_maps = ShadowMaps::new(&device);
+        let scene_lighting = SceneLighting::default();
+        let scene_lighting_buffer = create_scene_lighting_buffer(&device, scene_lighting);
+        let light_bind_group = create_light_bind_group(&device, &light_buffer, &shadow_maps, &scene_lighting_buffer);
         
-        let render_pipeline = {
-            let layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
-                label: Some("Render Pipeline Layout"),
-                bind_group_layouts: &[&texture_bind_group_layout, &camera_bind_group_layout, &light_bind_group_layout],
-                push_constant_ranges: &[],
-            });
-            let vertex_shader = wgpu::ShaderModuleDescriptor {
-                label: Some("Normal Shader Vertex"),
-                source: wgpu::ShaderSource::Wgsl(include_str!("shaders/wgpu_0.13/vertex_shader.wgsl").into()),
-            };
-            let fragment_shader = wgpu::ShaderModuleDescriptor {
-                label: Some("Normal Shader Fragment"),
-                source: wgpu::ShaderSource::Wgsl(include_str!("shaders/wgpu_0.13/fragment_shader.wgsl").into()),
-            };
-            create_render_pipeline(
-                &device,
-                &layout,
-                (surface_config.format, Some(texture::Texture::DEPTH_FORMAT)),
-                &[model::ModelVertex::desc(), transform::Raw::desc()],
-                vertex_shader,
-                fragment_shader,
-                "Render Pipeline",
-            )
-        };
-
-        let light_render_pipeline = create_light_render_pipeline(&device, &camera_bind_group_layout, &light_bind_group_layout, &surface_config);
+        let clusters = ClusteredLighting::new(&device);
+        let render_pipeline = create_main_render_pipeline(
+            &device,
+            assets.material_bind_group_layout(),
+            &camera_bind_group_layout,
+            &light_bind_group_layout,
+            &ClusteredLighting::create_bind_group_layout(&device),
+            HDR_FORMAT,
+            sample_count,
+        );
+        let deferred = (config.shading == Shading::Deferred).then(|| {
+            DeferredShading::new(&device, assets.material_bind_group_layout(), &camera_bind_group_layout, &light_bind_group_layout)
+        });
+        let light_render_pipeline = create_light_render_pipeline(
+            &device,
+            &camera_bind_group_layout,
+            &light_bind_group_layout,
+            HDR_FORMAT,
+            scene_sample_count(config.shading, sample_count),
+        );
+        let skybox_pipeline = skybox::create_pipeline(
+            &device,
+            &camera_bind_group_layout,
+            HDR_FORMAT,
+            scene_sample_count(config.shading, sample_count),
+        );
 
 
         let res_dir = std::path::Path::new(env!("OUT_DIR")).join("resources");
 
-        let _debug_light_model = model::Model::load(
-            &device,
-            &queue,
-            &Texture::create_bind_group_layout(&device),
-            res_dir.join("cube.obj"),
-        ).unwrap();
+        let debug_light_model = assets.load_model(&device, &queue, res_dir.join("cube.obj"))
+            .map_err(|e| RendererError::Assets(e.into()))?;
 
 
-        Self {
-            surface,
+        Ok(Self {
+            instance,
+            config: config.clone(),
+            force_fallback_adapter: false,
+            device_lost,
+            target,
             surface_config,
             device,
             queue,
             size,
             clear_color,
             render_pipeline,
-            depth_texture,
+            adapter_info,
+            sample_count,
+            supported_sample_counts,
+            supported_present_modes: Vec::new(),
+            deferred,
+            clusters,
+            post_processing,
+            texture_pool: RefCell::default(),
             light_render_pipeline,
+            skybox: None,
+            skybox_pipeline,
             light_buffer,
             light_bind_group,
-            num_lights: 0,
-            _debug_light_model,
-        }
+            light_capacity: MIN_LIGHT_CAPACITY,
+            lights: LightList::default(),
+            shadow_focus: None,
+            shadow_maps,
+            scene_lighting,
+            scene_lighting_buffer,
+            capture: None,
+            capture_writer: capture::Writer::default(),
+            debug_light_model,
+            assets,
+            instance_batches: InstanceBatches::default(),
+            culling_stats: CullingStats::default(),
+        })
+    }
+
+    // Spawns the node tree of a glTF file into the world, see Model::load_gltf. Blocks until the file is loaded.
+    pub fn load_gltf<P: AsRef<Path>>(&mut self, world: &mut World, path: P) -> Result<Vec<Entity>> {
+        Model::load_gltf(&self.device, &self.queue, &mut self.assets, world, path)
+    }
+
+    // Loads a model on a worker thread, a placeholder cube is drawn in its place until it has been uploaded
+    pub fn load_model_async<P: AsRef<Path>>(&mut self, path: P) -> Handle<Model> {
+        self.assets.load_model_async(path)
+    }
+
+    // Blocks until every model loading in the background is ready to draw
+    pub fn wait_for_loads(&mut self) {
+        self.assets.wait_for_loads(&self.device, &self.queue);
+    }
+
+    pub fn model_load_state(&self, model: Handle<Model>) -> LoadState<'_> {
+        self.assets.load_state(model)
     }
 
     // If the window has been resized, we need to recreate the surface with the new size. 
@@ -156,59 +347,416 @@ impl Renderer {
             self.size = new_size;
             self.surface_config.width = new_size.width;
             self.surface_config.height = new_size.height;
-            self.surface.configure(&self.device, &self.surface_config);
-            self.depth_texture = texture::Texture::create_depth_texture(&self.device, &self.surface_config, "depth_texture");
+            match &mut self.target {
+                Target::Surface(surface) => surface.configure(&self.device, &self.surface_config),
+                Target::Offscreen(target) => {
+                    *target = texture::Texture::create_render_target(&self.device, &self.surface_config, "offscreen_target");
+                }
+            }
+            self.post_processing.resize(&self.device, new_size.width, new_size.height);
         }
     }
 
-    pub fn update_lights(&mut self, lights: &Vec<light::Raw>) {
-        let new_buffer= self.device.create_buffer_init(
-            &wgpu::util::BufferInitDescriptor {
-                label: Some("Light VB"),
-                usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST,
-                contents: bytemuck::cast_slice(lights),
-            }
+    // The pipelines drawing into the frame targets
+    fn create_pipelines(&mut self) {
+        let camera_bind_group_layout = camera::Raw::create_bind_group_layout(&self.device);
+        let light_bind_group_layout = light::Raw::create_bind_group_layout(&self.device);
+        self.render_pipeline = create_main_render_pipeline(
+            &self.device,
+            self.assets.material_bind_group_layout(),
+            &camera_bind_group_layout,
+            &light_bind_group_layout,
+            &ClusteredLighting::create_bind_group_layout(&self.device),
+            HDR_FORMAT,
+            self.sample_count,
         );
-        let new_bind_group =  self.device.create_bind_group(&wgpu::BindGroupDescriptor {
-            layout: &light::Raw::create_bind_group_layout(&self.device),
-            entries: &[
-                wgpu::BindGroupEntry {
-                    binding: 0,
-                    resource: new_buffer.as_entire_binding(),
-                },
-            ],
-            label: None,
+        self.deferred = (self.config.shading == Shading::Deferred).then(|| {
+            DeferredShading::new(&self.device, self.assets.material_bind_group_layout(), &camera_bind_group_layout, &light_bind_group_layout)
         });
-        self.num_lights = lights.len();
-        self.light_buffer = new_buffer;
-        self.light_bind_group = new_bind_group;
+        self.light_render_pipeline = create_light_render_pipeline(
+            &self.device,
+            &camera_bind_group_layout,
+            &light_bind_group_layout,
+            HDR_FORMAT,
+            scene_sample_count(self.config.shading, self.sample_count),
+        );
+        self.skybox_pipeline = skybox::create_pipeline(
+            &self.device,
+            &camera_bind_group_layout,
+            HDR_FORMAT,
+            scene_sample_count(self.config.shading, self.sample_count),
+        );
+    }
 
+    // Whether the GPU device was lost, e.g. because the driver was reset. Nothing is drawn until recover succeeds.
+    pub fn is_device_lost(&self) -> bool {
+        self.device_lost.load(Ordering::Acquire)
     }
 
-    pub fn render(&mut self, world: &World, camera: &Camera) -> Result<(), wgpu::SurfaceError> {
-        let output = self.surface.get_current_texture()?;
-        let view = output.texture.create_view(&wgpu::TextureViewDescriptor::default());
-        
+    // Replaces a lost device with a new one and recreates everything the renderer had on it. Handles stay valid,
+    // models and textures are uploaded again from the decoded copies the asset server keeps. The skybox is loaded
+    // again from its source, cameras made for the old device need Camera::recreate_buffers afterwards.
+    pub async fn recover(&mut self) -> Result<(), RendererError> {
+        let compatible_surface = match &self.target {
+            Target::Surface(surface) => Some(surface),
+            Target::Offscreen(_) => None,
+        };
+        let adapter = self.instance.request_adapter(
+            &wgpu::RequestAdapterOptions {
+                power_preference: self.config.power_preference.to_wgpu(),
+                compatible_surface,
+                force_fallback_adapter: self.force_fallback_adapter,
+            },
+        ).await.ok_or_else(|| no_adapter(self.config.backend))?;
+        // Checked before anything is replaced, so an error leaves the renderer as it was
+        if let Some(surface) = compatible_surface {
+            if !surface.get_capabilities(&adapter).formats.contains(&self.surface_config.format) {
+                return Err(RendererError::IncompatibleSurface { adapter: adapter.get_info() });
+            }
+        }
+        let (device, queue) = request_device(&adapter, &self.config).await?;
+        watch_for_device_loss(&device, &self.device_lost);
+        self.device = device;
+        self.queue = queue;
+        self.adapter_info = adapter.get_info();
+
+        match &mut self.target {
+            Target::Surface(surface) => {
+                let surface_caps = surface.get_capabilities(&adapter);
+                // The new adapter may not support everything the old one did
+                if check_present_mode(self.surface_config.present_mode, &surface_caps.present_modes).is_err() {
+                    self.surface_config.present_mode = wgpu::PresentMode::AutoVsync;
+                }
+                surface.configure(&self.device, &self.surface_config);
+                self.supported_present_modes = surface_caps.present_modes;
+            }
+            Target::Offscreen(target) => {
+                *target = texture::Texture::create_render_target(&self.device, &self.surface_config, "offscreen_target");
+            }
+        }
+        self.supported_sample_counts = supported_sample_counts(&adapter, &self.device, HDR_FORMAT);
+        if !self.supported_sample_counts.contains(&self.sample_count) {
+            self.sample_count = 1;
+        }
+
+        self.assets.recreate(&self.device, &self.queue);
+        if let Some(skybox) = self.skybox.take() {
+            self.skybox = match Skybox::load(&self.device, &self.queue, skybox.source) {
+                Ok(skybox) => Some(skybox),
+                Err(e) => {
+                    eprintln!("Failed to reload the skybox: {e:?}");
+                    None
+                }
+            };
+        }
+        self.texture_pool = RefCell::default();
+        self.clusters = ClusteredLighting::new(&self.device);
+        self.create_pipelines();
+        self.post_processing = PostProcessing::new(
+            &self.device,
+            self.surface_config.format,
+            self.surface_config.width,
+            self.surface_config.height,
+            self.post_processing.passes().to_vec(),
+        );
+        self.light_capacity = MIN_LIGHT_CAPACITY;
+        self.light_buffer = create_light_buffer(&self.device, MIN_LIGHT_CAPACITY);
+        self.shadow_maps = ShadowMaps::new(&self.device);
+        self.scene_lighting_buffer = create_scene_lighting_buffer(&self.device, self.scene_lighting);
+        self.light_bind_group = create_light_bind_group(&self.device, &self.light_buffer, &self.shadow_maps, &self.scene_lighting_buffer);
+        // Forgetting what was uploaded makes the next update upload the lights, shadows and instances again
+        self.lights = LightList::default();
+        self.shadow_focus = None;
+        self.instance_batches = InstanceBatches::default();
+
+        self.device_lost.store(false, Ordering::Release);
+        Ok(())
+    }
+
+    // Whether frames wait for the display's vertical blank, which caps the frame rate at its refresh rate
+    pub const fn vsync(&self) -> bool {
+        matches!(
+            self.surface_config.present_mode,
+            wgpu::PresentMode::AutoVsync | wgpu::PresentMode::Fifo | wgpu::PresentMode::FifoRelaxed
+        )
+    }
+
+    pub fn set_vsync(&mut self, vsync: bool) {
+        let present_mode = if vsync { wgpu::PresentMode::AutoVsync } else { wgpu::PresentMode::AutoNoVsync };
+        self.configure_present_mode(present_mode);
+    }
+
+    pub const fn present_mode(&self) -> wgpu::PresentMode {
+        self.surface_config.present_mode
+    }
+
+    // Fails if the surface doesn't support the mode, the auto modes are always supported
+    pub fn set_present_mode(&mut self, present_mode: PresentMode) -> Result<(), RendererError> {
+        let present_mode = present_mode.to_wgpu();
+        if let Target::Surface(_) = self.target {
+            check_present_mode(present_mode, &self.supported_present_modes)?;
+        }
+        self.configure_present_mode(present_mode);
+        Ok(())
+    }
+
+    fn configure_present_mode(&mut self, present_mode: wgpu::PresentMode) {
+        self.surface_config.present_mode = present_mode;
+        if let Target::Surface(surface) = &self.target {
+            surface.configure(&self.device, &self.surface_config);
+        }
+    }
+
+    pub const fn sample_count(&self) -> u32 {
+        self.sample_count
+    }
+
+    // The MSAA sample counts the adapter can render with, in increasing order and always starting at 1
+    pub fn supported_sample_counts(&self) -> &[u32] {
+        &self.supported_sample_counts
+    }
+
+    // Switches MSAA to the given number of samples per pixel, recreating the pipelines drawing into the MSAA
+    // targets. Deferred shading ignores it.
+    pub fn set_sample_count(&mut self, sample_count: u32) -> Result<(), RendererError> {
+        if !self.supported_sample_counts.contains(&sample_count) {
+            return Err(RendererError::UnsupportedSampleCount {
+                adapter: self.adapter_info.clone(),
+                requested: sample_count,
+                supported: self.supported_sample_counts.clone(),
+            });
+        }
+        if sample_count == self.sample_count {
+            return Ok(());
+        }
+        self.sample_count = sample_count;
+        self.create_pipelines();
+        Ok(())
+    }
+
+    // The post-processing passes, in the order they run
+    pub fn post_passes(&self) -> &[PostPass] {
+        self.post_processing.passes()
+    }
+
+    // Replaces the post-processing chain, an empty one shows the HDR image clipped to the screen's range
+    pub fn set_post_passes(&mut self, passes: Vec<PostPass>) {
+        self.post_processing.set_passes(&self.device, passes);
+    }
+
+    // Shows a cube texture behind the scene instead of the clear color, or removes the skybox with None
+    pub fn set_skybox(&mut self, source: Option<SkyboxSource>) -> Result<()> {
+        self.skybox = source.map(|source| Skybox::load(&self.device, &self.queue, source)).transpose()?;
+        Ok(())
+    }
+
+    // Whether forward shading shows how many lights reach each cluster instead of the lit scene
+    pub const fn light_heatmap(&self) -> bool {
+        self.clusters.show_heatmap()
+    }
+
+    // Deferred shading has no clusters and ignores it
+    pub const fn set_light_heatmap(&mut self, show_heatmap: bool) {
+        self.clusters.set_show_heatmap(show_heatmap);
+    }
+
+    // Writes the lights that were added, changed or moved, returning false if none were and nothing was written
+    #[allow(clippy::cast_possible_truncation)]
+    fn update_lights(&mut self, world: &World) -> bool {
+        let previous_count = self.lights.len();
+        if !self.lights.update(world) {
+            return false;
+        }
+        let count = self.lights.len();
+        let dirty = self.lights.take_dirty();
+        // Grow geometrically, so adding lights one at a time doesn't reallocate the buffer and bind group every time
+        let dirty = if count > self.light_capacity {
+            self.light_capacity = count.next_power_of_two();
+            self.light_buffer = create_light_buffer(&self.device, self.light_capacity);
+            self.light_bind_group = create_light_bind_group(&self.device, &self.light_buffer, &self.shadow_maps, &self.scene_lighting_buffer);
+            Some(0..count)
+        } else {
+            dirty
+        };
+        if let Some(dirty) = dirty {
+            let offset = (std::mem::size_of::<light::Raw>() * dirty.start) as wgpu::BufferAddress;
+            self.queue.write_buffer(&self.light_buffer, offset, bytemuck::cast_slice(&self.lights.lights()[dirty]));
+        }
+        if count != previous_count {
+            self.queue.write_buffer(&self.scene_lighting_buffer, 0, bytemuck::cast_slice(&[self.scene_lighting.to_raw(count as u32)]));
+        }
+        true
+    }
+
+    pub const fn scene_lighting(&self) -> &SceneLighting {
+        &self.scene_lighting
+    }
+
+    #[allow(clippy::cast_possible_truncation)]
+    pub fn set_scene_lighting(&mut self, scene_lighting: SceneLighting) {
+        self.scene_lighting = scene_lighting;
+        let light_count = self.lights.len() as u32;
+        self.queue.write_buffer(&self.scene_lighting_buffer, 0, bytemuck::cast_slice(&[scene_lighting.to_raw(light_count)]));
+    }
+
+    // Does nothing while the device is lost
+    pub fn render(&mut self, camera: &Camera) -> Result<(), wgpu::SurfaceError> {
+        if self.is_device_lost() {
+            return Ok(());
+        }
+        self.render_frame(camera)
+    }
+
+    fn render_frame(&mut self, camera: &Camera) -> Result<(), wgpu::SurfaceError> {
+        let capture = self.capture.take();
+        match &self.target {
+            Target::Surface(surface) => {
+                let output = surface.get_current_texture()?;
+                let view = output.texture.create_view(&wgpu::TextureViewDescriptor::default());
+                self.draw(&view, camera);
+                // The frame has to be read back before it is handed to the presentation engine
+                self.capture = capture.and_then(|capture| self.capture_frame(capture, &output.texture));
+                catch_device_lost(&self.device_lost, || output.present());
+            }
+            Target::Offscreen(target) => {
+                self.draw(&target.view, camera);
+                self.capture = capture.and_then(|capture| self.capture_frame(capture, &target.texture));
+            }
+        }
+        Ok(())
+    }
+
+    // Saves the next rendered frame as a PNG
+    pub fn screenshot<P: Into<PathBuf>>(&mut self, path: P) {
+        self.capture = Some(Capture::Screenshot(path.into()));
+    }
+
+    // Saves the next frame_count rendered frames as numbered PNGs in the given directory
+    pub fn capture_frames<P: Into<PathBuf>>(&mut self, directory: P, frame_count: u32) -> Result<()> {
+        self.capture = Some(Capture::sequence(directory, frame_count)?);
+        Ok(())
+    }
+
+    // Writes the frame in the given texture to disk, returning what is left of the capture
+    fn capture_frame(&self, capture: Capture, frame: &wgpu::Texture) -> Option<Capture> {
+        if !self.surface_config.usage.contains(wgpu::TextureUsages::COPY_SRC) {
+            eprintln!("Frame capture is not supported by this surface");
+            return None;
+        }
+        let (path, rest) = capture.advance();
+        // Reading back submits and waits for the copy, so a lost device shows up here too
+        let image = catch_device_lost(&self.device_lost, || texture::Texture::read_to_image(
+            &self.device,
+            &self.queue,
+            frame,
+            self.surface_config.format,
+            self.surface_config.width,
+            self.surface_config.height,
+        ))?;
+        match image {
+            Ok(image) => self.capture_writer.save(image, path),
+            Err(e) => eprintln!("Failed to read back frame for {}: {e:?}", path.display()),
+        }
+        rest
+    }
+
+    // Renders a frame into the offscreen target and reads it back. Only available on renderers made with new_headless.
+    pub fn render_to_image(&self, camera: &Camera) -> Result<image::RgbaImage> {
+        let Target::Offscreen(target) = &self.target else {
+            bail!("render_to_image requires a headless renderer");
+        };
+        self.draw(&target.view, camera);
+        texture::Texture::read_to_image(
+            &self.device,
+            &self.queue,
+            &target.texture,
+            self.surface_config.format,
+            self.surface_config.width,
+            self.surface_config.height,
+        )
+    }
+
+    // Records and submits the commands drawing what the last update synced from the world into the given view
+    fn draw(&self, view: &wgpu::TextureView, camera: &Camera) {
         //command buffer
         let mut encoder = self.device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
             label: Some("Render Encoder"),
         });
 
-        {
-            let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+        let mut graph = RenderGraph::default();
+        let frame = graph.import_texture(view);
+        let lights = graph.import_buffer(&self.light_buffer);
+        let shadow_map = self.shadow_maps.add_pass(&mut graph, &self.instance_batches, &self.assets);
+        let targets = if let Some(deferred) = &self.deferred {
+            self.add_deferred_passes(&mut graph, deferred, lights, shadow_map, camera)
+        } else {
+            let clusters = self.clusters.add_pass(&mut graph, lights, &self.scene_lighting_buffer);
+            self.add_scene_pass(&mut graph, lights, clusters, shadow_map, camera)
+        };
+        let scene = self.skybox.as_ref().map_or(targets.color, |skybox| self.add_skybox_pass(&mut graph, skybox, targets, camera));
+        self.post_processing.add_passes(&mut graph, scene, frame);
+        graph.execute(&self.device, &mut encoder, &mut self.texture_pool.borrow_mut());
+
+        // Finish giving commands, and submit command buffer to queue.
+        let command_buffer = encoder.finish();
+        catch_device_lost(&self.device_lost, || self.queue.submit(std::iter::once(command_buffer)));
+    }
+
+    // Draws the light boxes and the visible instances into an HDR texture
+    fn add_scene_pass<'a>(
+        &'a self,
+        graph: &mut RenderGraph<'a>,
+        lights: BufferId,
+        clusters: BufferId,
+        shadow_map: TextureId,
+        camera: &'a Camera,
+    ) -> SceneTargets {
+        let (width, height) = (self.surface_config.width, self.surface_config.height);
+        let target_desc = |format, sample_count, usage| TextureDesc { width, height, format, sample_count, usage };
+        let mut pass = graph.add_pass("scene");
+        // Both are read through the light bind group
+        pass.read_buffer(lights);
+        pass.read_texture(shadow_map);
+        // Read through the cluster bind group
+        pass.read_buffer(clusters);
+        let scene = pass.create_texture(
+            "hdr_scene",
+            target_desc(HDR_FORMAT, 1, wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::TEXTURE_BINDING),
+        );
+        // With MSAA the samples are resolved into the HDR texture at the end of the pass, and only kept if the
+        // skybox pass draws more of them
+        let msaa_target = (self.sample_count > 1).then(|| {
+            pass.create_texture("msaa_target", target_desc(HDR_FORMAT, self.sample_count, wgpu::TextureUsages::RENDER_ATTACHMENT))
+        });
+        // The GL backend can't create multisampled textures that can be sampled, and nothing needs to
+        let depth_usage = if self.sample_count == 1 {
+            wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::TEXTURE_BINDING
+        } else {
+            wgpu::TextureUsages::RENDER_ATTACHMENT
+        };
+        let depth = pass.create_texture("depth_texture", target_desc(texture::Texture::DEPTH_FORMAT, self.sample_count, depth_usage));
+
+        let keep_samples = self.skybox.is_some();
+
+        pass.execute(move |context| {
+            let scene_view = context.texture(scene);
+            let (view, resolve_target, store) = msaa_target
+                .map_or((scene_view, None, true), |msaa_target| (context.texture(msaa_target), Some(scene_view), keep_samples));
+            let depth_view = context.texture(depth);
+            let mut render_pass = context.encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
                 label: Some("Render Pass"),
                 color_attachments: &[
                     Some(wgpu::RenderPassColorAttachment {
-                        view: &view,
-                        resolve_target: None,
+                        view,
+                        resolve_target,
                         ops: wgpu::Operations {
                             load: wgpu::LoadOp::Clear(self.clear_color),
-                            store: true,
+                            store,
                         }
                     })
                 ],
                 depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
-                    view: &self.depth_texture.view,
+                    view: depth_view,
                     depth_ops: Some(wgpu::Operations {
                         load: wgpu::LoadOp::Clear(1.0),
                         store: true,
@@ -217,57 +765,439 @@ impl Renderer {
                 }),
             });
 
-            
-            let num_lights = <&Light>::query().iter(world).count() as u32;
-            
+            #[allow(clippy::cast_possible_truncation)]
+            let num_lights = self.lights.len() as u32;
+
             render_pass.set_pipeline(&self.light_render_pipeline);
             render_pass.draw_light_model_instanced(
-                &self._debug_light_model,
+                self.assets.model(self.debug_light_model),
                 0..num_lights,
                 &camera.bind_group,
                 &self.light_bind_group,
             );
-            
-            
-            let mut renderables = <(&Transform, &Arc<Model>)>::query();
+
             render_pass.set_pipeline(&self.render_pipeline);
-            for (transform, model) in renderables.iter(world) {
-                render_pass.set_vertex_buffer(1, transform.buffer.slice(..));
-                render_pass.draw_model(model, &camera.bind_group,  &self.light_bind_group);
+            render_pass.set_bind_group(3, &self.clusters.bind_group, &[]);
+            for (model, batch) in self.instance_batches.iter() {
+                batch.draw_visible(&mut render_pass, self.assets.model(model), &camera.bind_group, &self.light_bind_group);
             }
-        }
+        });
+        SceneTargets { color: scene, msaa: msaa_target, depth }
+    }
 
-        // Finish giving commands, and submit command buffer to queue.
-        let command_buffer = encoder.finish();
-        self.queue.submit(std::iter::once(command_buffer));
-        output.present();
-        Ok(())
+    // Writes the surfaces into a G-buffer, shades it once per light and then draws the light boxes on top
+    fn add_deferred_passes<'a>(
+        &'a self,
+        graph: &mut RenderGraph<'a>,
+        deferred: &'a DeferredShading,
+        lights: BufferId,
+        shadow_map: TextureId,
+        camera: &'a Camera,
+    ) -> SceneTargets {
+        let (width, height) = (self.surface_config.width, self.surface_config.height);
+        let target_desc = |format| TextureDesc {
+            width,
+            height,
+            format,
+            sample_count: 1,
+            usage: wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::TEXTURE_BINDING,
+        };
+
+        let mut pass = graph.add_pass("gbuffer");
+        // The ambient light is read through the light bind group
+        pass.read_buffer(lights);
+        let scene = pass.create_texture("hdr_scene", target_desc(HDR_FORMAT));
+        let albedo = pass.create_texture("gbuffer_albedo", target_desc(deferred::ALBEDO_FORMAT));
+        let normal = pass.create_texture("gbuffer_normal", target_desc(deferred::NORMAL_FORMAT));
+        let material = pass.create_texture("gbuffer_material", target_desc(deferred::MATERIAL_FORMAT));
+        let depth = pass.create_texture("depth_texture", target_desc(texture::Texture::DEPTH_FORMAT));
+        pass.execute(move |context| {
+            let clear = |view, color| Some(wgpu::RenderPassColorAttachment {
+                view,
+                resolve_target: None,
+                ops: wgpu::Operations {
+                    load: wgpu::LoadOp::Clear(color),
+                    store: true,
+                },
+            });
+            let color_attachments = [
+                clear(context.texture(scene), self.clear_color),
+                clear(context.texture(albedo), wgpu::Color::TRANSPARENT),
+                clear(context.texture(normal), wgpu::Color::TRANSPARENT),
+                clear(context.texture(material), wgpu::Color::TRANSPARENT),
+            ];
+            let depth_view = context.texture(depth);
+            let mut render_pass = context.encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                label: Some("GBuffer Pass"),
+                color_attachments: &color_attachments,
+                depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
+                    view: depth_view,
+                    depth_ops: Some(wgpu::Operations {
+                        load: wgpu::LoadOp::Clear(1.0),
+                        store: true,
+                    }),
+                    stencil_ops: None,
+                }),
+            });
+            render_pass.set_pipeline(&deferred.geometry_pipeline);
+            for (model, batch) in self.instance_batches.iter() {
+                batch.draw_visible(&mut render_pass, self.assets.model(model), &camera.bind_group, &self.light_bind_group);
+            }
+        });
+
+        let mut pass = graph.add_pass("deferred_lighting");
+        pass.read_buffer(lights);
+        pass.read_texture(shadow_map);
+        pass.read_texture(albedo);
+        pass.read_texture(normal);
+        pass.read_texture(material);
+        pass.read_texture(depth);
+        let scene = pass.write_texture(scene);
+        pass.execute(move |context| {
+            let bind_group = deferred.bind_group(
+                context.device,
+                context.texture(albedo),
+                context.texture(normal),
+                context.texture(material),
+                context.texture(depth),
+            );
+            let view = context.texture(scene);
+            let mut render_pass = context.encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                label: Some("Deferred Lighting Pass"),
+                color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                    view,
+                    resolve_target: None,
+                    ops: wgpu::Operations {
+                        load: wgpu::LoadOp::Load,
+                        store: true,
+                    },
+                })],
+                depth_stencil_attachment: None,
+            });
+            #[allow(clippy::cast_possible_truncation)]
+            let num_lights = self.lights.len() as u32;
+            render_pass.set_pipeline(&deferred.lighting_pipeline);
+            render_pass.set_bind_group(0, &bind_group, &[]);
+            render_pass.set_bind_group(1, &camera.bind_group, &[]);
+            render_pass.set_bind_group(2, &self.light_bind_group, &[]);
+            render_pass.draw(0..deferred::LIGHT_VOLUME_VERTICES, 0..num_lights);
+        });
+
+        self.add_light_box_pass(graph, lights, scene, depth, camera)
+    }
+
+    // Draws the light boxes on top of a lit scene, depth tested against the depth of the surfaces
+    fn add_light_box_pass<'a>(
+        &'a self,
+        graph: &mut RenderGraph<'a>,
+        lights: BufferId,
+        scene: TextureId,
+        depth: TextureId,
+        camera: &'a Camera,
+    ) -> SceneTargets {
+        let mut pass = graph.add_pass("light_boxes");
+        pass.read_buffer(lights);
+        let scene = pass.write_texture(scene);
+        let depth = pass.write_texture(depth);
+        pass.execute(move |context| {
+            let view = context.texture(scene);
+            let depth_view = context.texture(depth);
+            let mut render_pass = context.encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                label: Some("Light Box Pass"),
+                color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                    view,
+                    resolve_target: None,
+                    ops: wgpu::Operations {
+                        load: wgpu::LoadOp::Load,
+                        store: true,
+                    },
+                })],
+                depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
+                    view: depth_view,
+                    depth_ops: Some(wgpu::Operations {
+                        load: wgpu::LoadOp::Load,
+                        store: true,
+                    }),
+                    stencil_ops: None,
+                }),
+            });
+            #[allow(clippy::cast_possible_truncation)]
+            let num_lights = self.lights.len() as u32;
+            render_pass.set_pipeline(&self.light_render_pipeline);
+            render_pass.draw_light_model_instanced(
+                self.assets.model(self.debug_light_model),
+                0..num_lights,
+                &camera.bind_group,
+                &self.light_bind_group,
+            );
+        });
+        SceneTargets { color: scene, msaa: None, depth }
+    }
+
+    // Draws the skybox wherever the depth buffer is still clear, returns the HDR texture with the sky in it
+    fn add_skybox_pass<'a>(&'a self, graph: &mut RenderGraph<'a>, skybox: &'a Skybox, targets: SceneTargets, camera: &'a Camera) -> TextureId {
+        let mut pass = graph.add_pass("skybox");
+        let depth = pass.read_texture(targets.depth);
+        let scene = pass.write_texture(targets.color);
+        let msaa_target = targets.msaa.map(|msaa_target| pass.write_texture(msaa_target));
+        pass.execute(move |context| {
+            let scene_view = context.texture(scene);
+            let (view, resolve_target) = msaa_target
+                .map_or((scene_view, None), |msaa_target| (context.texture(msaa_target), Some(scene_view)));
+            let depth_view = context.texture(depth);
+            let mut render_pass = context.encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                label: Some("Skybox Pass"),
+                color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                    view,
+                    resolve_target,
+                    ops: wgpu::Operations {
+                        load: wgpu::LoadOp::Load,
+                        store: resolve_target.is_none(),
+                    },
+                })],
+                // Only tested against, so the depth is left untouched
+                depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
+                    view: depth_view,
+                    depth_ops: None,
+                    stencil_ops: None,
+                }),
+            });
+            render_pass.set_pipeline(&self.skybox_pipeline);
+            render_pass.set_bind_group(0, &skybox.bind_group, &[]);
+            render_pass.set_bind_group(1, &camera.bind_group, &[]);
+            render_pass.draw(0..3, 0..1);
+        });
+        scene
     }
 
     pub fn update(&mut self, camera: &Camera, world: &World) {
+        self.assets.poll(&self.device, &self.queue);
+
         let camera_raw = camera.to_raw();
         self.queue.write_buffer(&camera.buffer, 0, bytemuck::cast_slice(&[camera_raw]));
+        self.clusters.update(&self.queue, camera, self.surface_config.width, self.surface_config.height);
+
+        let lights_changed = self.update_lights(world);
+        // Directional shadows follow the camera, the other shadow maps only depend on the lights
+        if lights_changed || self.shadow_focus != Some(camera.position) {
+            self.shadow_maps.update(&self.queue, self.lights.lights(), camera.position);
+            self.shadow_focus = Some(camera.position);
+        }
 
-        let lights_raw: Vec<light::Raw> = <&Light>::query().iter(world).map(|light| {
-            light.to_raw()
-        }).collect();
-        if lights_raw.len() == self.num_lights {
-            self.queue.write_buffer(&self.light_buffer, 0, bytemuck::cast_slice(&lights_raw));
-            
+        self.instance_batches.update(&self.device, &self.queue, world);
+        self.culling_stats = self.instance_batches.cull(&self.device, &self.queue, &camera.frustum(), &self.assets);
+    }
+
+    // Instances drawn and skipped by frustum culling in the last update. Shadows still draw every instance,
+    // since things out of view can cast shadows into it.
+    pub const fn culling_stats(&self) -> CullingStats {
+        self.culling_stats
+    }
+}
+
+fn create_light_buffer(device: &wgpu::Device, capacity: usize) -> wgpu::Buffer {
+    device.create_buffer(&wgpu::BufferDescriptor {
+        label: Some("Light Buffer"),
+        size: (std::mem::size_of::<light::Raw>() * capacity) as wgpu::BufferAddress,
+        usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST,
+        mapped_at_creation: false,
+    })
+}
+
+fn create_scene_lighting_buffer(device: &wgpu::Device, scene_lighting: SceneLighting) -> wgpu::Buffer {
+    device.create_buffer_init(
+        &wgpu::util::BufferInitDescriptor {
+            label: Some("Scene Lighting Buffer"),
+            contents: bytemuck::cast_slice(&[scene_lighting.to_raw(0)]),
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+        }
+    )
+}
+
+fn create_light_bind_group(device: &wgpu::Device, light_buffer: &wgpu::Buffer, shadow_maps: &ShadowMaps, scene_lighting_buffer: &wgpu::Buffer) -> wgpu::BindGroup {
+    device.create_bind_group(&wgpu::BindGroupDescriptor {
+        layout: &light::Raw::create_bind_group_layout(device),
+        entries: &[
+            wgpu::BindGroupEntry {
+                binding: 0,
+                resource: light_buffer.as_entire_binding(),
+            },
+            wgpu::BindGroupEntry {
+                binding: 1,
+                resource: shadow_maps.matrix_buffer.as_entire_binding(),
+            },
+            wgpu::BindGroupEntry {
+                binding: 2,
+                resource: wgpu::BindingResource::TextureView(&shadow_maps.texture.view),
+            },
+            wgpu::BindGroupEntry {
+                binding: 3,
+                resource: wgpu::BindingResource::Sampler(&shadow_maps.texture.sampler),
+            },
+            wgpu::BindGroupEntry {
+                binding: 4,
+                resource: scene_lighting_buffer.as_entire_binding(),
+            },
+        ],
+        label: None,
+    })
+}
+
+async fn request_device(adapter: &wgpu::Adapter, config: &RendererConfig) -> Result<(wgpu::Device, wgpu::Queue), RendererError> {
+    // Without this only the sample counts every adapter supports can be used
+    let features = adapter.features() & wgpu::Features::TEXTURE_ADAPTER_SPECIFIC_FORMAT_FEATURES;
+    adapter.request_device(
+        &wgpu::DeviceDescriptor {
+            features,
+            limits: config.limits.to_wgpu(adapter),
+            label: None,
+        },
+        None,
+    ).await.map_err(|source| RendererError::RequestDevice { adapter: adapter.get_info(), source })
+}
+
+fn check_present_mode(present_mode: wgpu::PresentMode, supported: &[wgpu::PresentMode]) -> Result<(), RendererError> {
+    let is_auto = matches!(present_mode, wgpu::PresentMode::AutoVsync | wgpu::PresentMode::AutoNoVsync);
+    if !is_auto && !supported.contains(&present_mode) {
+        return Err(RendererError::UnsupportedPresentMode { requested: present_mode, supported: supported.to_vec() });
+    }
+    Ok(())
+}
+
+// wgpu 0.17 has no callback for a lost device, it only shows up in the errors of whatever uses the device next
+fn watch_for_device_loss(device: &wgpu::Device, device_lost: &Arc<AtomicBool>) {
+    let device_lost = device_lost.clone();
+    device.on_uncaptured_error(Box::new(move |error| {
+        if is_device_lost_error(&error) {
+            eprintln!("The GPU device was lost: {error}");
+            device_lost.store(true, Ordering::Release);
         } else {
-            self.update_lights(&lights_raw);
+            // Panicking here would unwind through wgpu, so other errors are reported and the frame goes on
+            eprintln!("wgpu error: {error}");
         }
-        
+    }));
+}
 
-        let mut transforms = <&Transform>::query();
-        for transform in transforms.iter(world) {
-            self.queue.write_buffer(&transform.buffer, 0, bytemuck::cast_slice(&[transform.to_raw()]));
+// wgpu wraps the error of the device in context, the device's own error is further down the chain
+fn is_device_lost_error(error: &wgpu::Error) -> bool {
+    let mut source: Option<&(dyn std::error::Error + 'static)> = Some(error);
+    while let Some(error) = source {
+        if matches!(error.downcast_ref::<wgpu_core::device::DeviceError>(), Some(wgpu_core::device::DeviceError::Lost)) {
+            return true;
         }
+        source = error.source();
+    }
+    false
+}
+
+// Workaround for wgpu 0.17, which panics instead of returning an error when a submit, present or poll finds the
+// device lost. Only wrap those calls. The panic only carries wgpu's message, so it is compared with how
+// DeviceError::Lost describes itself. A device lost panic sets device_lost and returns None, any other panic is
+// passed on. The caught panic has already gone through the panic hook, which prints it like any other.
+fn catch_device_lost<T>(device_lost: &AtomicBool, f: impl FnOnce() -> T) -> Option<T> {
+    match std::panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(value) => Some(value),
+        Err(payload) => {
+            let message = payload.downcast_ref::<String>().map_or("", String::as_str);
+            if !message.contains(&wgpu_core::device::DeviceError::Lost.to_string()) {
+                std::panic::resume_unwind(payload);
+            }
+            device_lost.store(true, Ordering::Release);
+            None
+        }
+    }
+}
+
+fn no_adapter(backend: Backend) -> RendererError {
+    // A fresh instance, the one that found no adapter may have been limited to the requested backend
+    let instance = wgpu::Instance::new(wgpu::InstanceDescriptor::default());
+    let available = instance.enumerate_adapters(wgpu::Backends::all()).map(|adapter| adapter.get_info()).collect();
+    RendererError::NoAdapter { backend, available }
+}
 
+fn describe_adapter(adapter: &wgpu::AdapterInfo) -> String {
+    format!("{} ({:?}, {:?})", adapter.name, adapter.backend, adapter.device_type)
+}
+
+fn describe_adapters(adapters: &[wgpu::AdapterInfo]) -> String {
+    if adapters.is_empty() {
+        return String::from("none");
+    }
+    adapters.iter().map(describe_adapter).collect::<Vec<_>>().join(", ")
+}
+
+// Deferred shading draws the G-buffer with a single sample, so MSAA only applies to forward shading
+const fn scene_sample_count(shading: Shading, sample_count: u32) -> u32 {
+    match shading {
+        Shading::Forward => sample_count,
+        Shading::Deferred => 1,
     }
 }
 
-fn create_light_render_pipeline(device: &wgpu::Device, camera_bind_group_layout: &wgpu::BindGroupLayout, light_bind_group_layout: &wgpu::BindGroupLayout, surface_config: &SurfaceConfiguration) -> wgpu::RenderPipeline {
+// The MSAA sample counts that both the color and the depth target can be created with on this device
+fn supported_sample_counts(adapter: &wgpu::Adapter, device: &wgpu::Device, color_format: wgpu::TextureFormat) -> Vec<u32> {
+    let format_flags = |format: wgpu::TextureFormat| {
+        if device.features().contains(wgpu::Features::TEXTURE_ADAPTER_SPECIFIC_FORMAT_FEATURES) {
+            adapter.get_texture_format_features(format).flags
+        } else {
+            format.guaranteed_format_features(device.features()).flags
+        }
+    };
+    let color_flags = format_flags(color_format);
+    let depth_flags = format_flags(texture::Texture::DEPTH_FORMAT);
+    let can_resolve = color_flags.contains(wgpu::TextureFormatFeatureFlags::MULTISAMPLE_RESOLVE);
+    SAMPLE_COUNTS
+        .into_iter()
+        .filter(|&count| count == 1 || (can_resolve && color_flags.sample_count_supported(count) && depth_flags.sample_count_supported(count)))
+        .collect()
+}
+
+fn create_main_render_pipeline(
+    device: &wgpu::Device,
+    material_bind_group_layout: &wgpu::BindGroupLayout,
+    camera_bind_group_layout: &wgpu::BindGroupLayout,
+    light_bind_group_layout: &wgpu::BindGroupLayout,
+    cluster_bind_group_layout: &wgpu::BindGroupLayout,
+    color_format: wgpu::TextureFormat,
+    sample_count: u32,
+) -> wgpu::RenderPipeline {
+    let layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+        label: Some("Render Pipeline Layout"),
+        bind_group_layouts: &[material_bind_group_layout, camera_bind_group_layout, light_bind_group_layout, cluster_bind_group_layout],
+        push_constant_ranges: &[],
+    });
+    let vertex_shader = wgpu::ShaderModuleDescriptor {
+        label: Some("Normal Shader Vertex"),
+        source: wgpu::ShaderSource::Wgsl(include_str!("shaders/wgpu_0.13/vertex_shader.wgsl").into()),
+    };
+    let fragment_shader = wgpu::ShaderModuleDescriptor {
+        label: Some("Normal Shader Fragment"),
+        source: wgpu::ShaderSource::Wgsl(concat!(
+            include_str!("shaders/wgpu_0.13/material.wgsl"),
+            include_str!("shaders/wgpu_0.13/lighting.wgsl"),
+            include_str!("shaders/wgpu_0.13/clusters.wgsl"),
+            include_str!("shaders/wgpu_0.13/fragment_shader.wgsl"),
+        ).into()),
+    };
+    create_render_pipeline(
+        device,
+        &layout,
+        (color_format, Some(texture::Texture::DEPTH_FORMAT)),
+        &[model::ModelVertex::desc(), transform::Raw::desc()],
+        vertex_shader,
+        fragment_shader,
+        sample_count,
+        "Render Pipeline",
+    )
+}
+
+fn create_light_render_pipeline(
+    device: &wgpu::Device,
+    camera_bind_group_layout: &wgpu::BindGroupLayout,
+    light_bind_group_layout: &wgpu::BindGroupLayout,
+    color_format: wgpu::TextureFormat,
+    sample_count: u32,
+) -> wgpu::RenderPipeline {
     let light_render_pipeline = {
         let layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
             label: Some("Light Pipeline Layout"),
@@ -285,10 +1215,11 @@ fn create_light_render_pipeline(device: &wgpu::Device, camera_bind_group_layout:
         create_render_pipeline(
             device,
             &layout,
-            (surface_config.format, Some(texture::Texture::DEPTH_FORMAT)),
+            (color_format, Some(texture::Texture::DEPTH_FORMAT)),
             &[model::ModelVertex::desc()],
             shader_vertex,
             shader_fragment,
+            sample_count,
             "Light Pipeline"
         )
     };
@@ -296,6 +1227,7 @@ fn create_light_render_pipeline(device: &wgpu::Device, camera_bind_group_layout:
 }
 
 
+#[allow(clippy::too_many_arguments)]
 pub fn create_render_pipeline(
     device: &wgpu::Device,
     layout: &wgpu::PipelineLayout,
@@ -303,6 +1235,7 @@ pub fn create_render_pipeline(
     vertex_layouts: &[wgpu::VertexBufferLayout],
     vertex_shader: wgpu::ShaderModuleDescriptor,
     fragment_shader: wgpu::ShaderModuleDescriptor,
+    sample_count: u32,
     label: &str,
 ) -> wgpu::RenderPipeline {
     let vertex_shader = device.create_shader_module(vertex_shader);
@@ -348,7 +1281,7 @@ pub fn create_render_pipeline(
             bias: wgpu::DepthBiasState::default(),
         }),
         multisample: wgpu::MultisampleState {
-            count: 1,
+            count: sample_count,
             mask: !0,
             alpha_to_coverage_enabled: false,
         },
@@ -360,3 +1293,88 @@ pub fn create_render_pipeline(
 
 
 
+
+#[cfg(test)]
+mod tests {
+    use cgmath::{Rotation3, Zero};
+
+    use super::*;
+    use crate::{hierarchy, transform::LocalTransform};
+
+    // Largest difference per color channel to the reference, software and hardware rasterizers round differently
+    const CHANNEL_TOLERANCE: u8 = 8;
+    // Share of pixels allowed past the tolerance, for the ones along the edges of the cube
+    const MAX_MISMATCHED_SHARE: f64 = 0.01;
+
+    // Panics instead of passing when there is no adapter, these tests are ignored by default for that reason
+    fn headless_renderer(width: u32, height: u32) -> Renderer {
+        pollster::block_on(Renderer::new_headless(width, height, true, &RendererConfig::default()))
+            .unwrap_or_else(|e| panic!("Could not create a headless renderer: {e}"))
+    }
+
+    // What the default post-processing chain turns a linear clear color into: no bloom below the threshold, an
+    // exposure of 1, the ACES curve and then the sRGB encoding of the frame
+    #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+    fn post_processed(x: f64) -> u8 {
+        let mapped = ((x * 2.51f64.mul_add(x, 0.03)) / x.mul_add(2.43f64.mul_add(x, 0.59), 0.14)).clamp(0.0, 1.0);
+        let encoded = if mapped <= 0.003_130_8 { mapped * 12.92 } else { 1.055f64.mul_add(mapped.powf(1.0 / 2.4), -0.055) };
+        (encoded * 255.0).round() as u8
+    }
+
+    #[test]
+    #[ignore = "needs an adapter, run with cargo test -- --ignored"]
+    fn headless_renders_an_empty_world() {
+        let renderer = headless_renderer(64, 48);
+        let camera = Camera::new((0.0, 0.0, 5.0), cgmath::Deg(-90.0), cgmath::Deg(0.0), cgmath::Deg(45.0), 0.1, 100.0, &renderer);
+        let image = renderer.render_to_image(&camera).unwrap();
+        assert_eq!(image.dimensions(), (64, 48));
+        // Nothing to draw, so every pixel is the clear color
+        let [r, g, b, _] = RendererConfig::default().clear_color;
+        let expected = [post_processed(r), post_processed(g), post_processed(b), 255];
+        for pixel in image.pixels() {
+            assert!(
+                pixel.0.iter().zip(expected).all(|(&actual, expected)| actual.abs_diff(expected) <= 2),
+                "{pixel:?} is not the clear color {expected:?}",
+            );
+        }
+    }
+
+    // Compares a lit, rotated cube against tests/golden/cube_scene.png. Set UPDATE_GOLDEN to write the reference anew.
+    #[test]
+    #[ignore = "needs an adapter, run with cargo test -- --ignored"]
+    fn headless_cube_scene_matches_the_golden_image() {
+        let mut renderer = headless_renderer(128, 96);
+        let res_dir = Path::new(env!("OUT_DIR")).join("resources");
+        let cube_model = renderer.load_model_async(res_dir.join("cube.obj"));
+        renderer.wait_for_loads();
+        assert!(matches!(renderer.model_load_state(cube_model), LoadState::Loaded));
+
+        let mut world = World::default();
+        let rotation = cgmath::Quaternion::from_axis_angle(cgmath::Vector3::unit_y(), cgmath::Deg(30.0));
+        world.push((LocalTransform::new(cgmath::Vector3::zero(), rotation), cube_model));
+        world.push((Light::point(cgmath::Vector3::new(2.0, 3.0, 3.0), [1.0, 0.9, 0.8], 20.0), ()));
+        world.push((Light::directional(cgmath::Vector3::new(-1.0, -1.0, -0.5), [0.5, 0.5, 0.6]), ()));
+        hierarchy::propagate_transforms(&mut world);
+
+        let camera = Camera::new((0.0, 2.0, 5.0), cgmath::Deg(-90.0), cgmath::Deg(-20.0), cgmath::Deg(45.0), 0.1, 100.0, &renderer);
+        renderer.update(&camera, &world);
+        let image = renderer.render_to_image(&camera).unwrap();
+
+        let golden_path = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/golden/cube_scene.png");
+        if std::env::var_os("UPDATE_GOLDEN").is_some() {
+            image.save(&golden_path).unwrap();
+            return;
+        }
+        let golden = image::open(&golden_path).unwrap().to_rgba8();
+        assert_eq!(image.dimensions(), golden.dimensions());
+        let mismatched = image.pixels().zip(golden.pixels())
+            .filter(|(actual, expected)| actual.0.iter().zip(expected.0).any(|(&a, e)| a.abs_diff(e) > CHANNEL_TOLERANCE))
+            .count();
+        let mismatched_share = mismatched as f64 / f64::from(image.width() * image.height());
+        if mismatched_share > MAX_MISMATCHED_SHARE {
+            let actual_path = std::env::temp_dir().join("cube_scene.actual.png");
+            image.save(&actual_path).unwrap();
+            panic!("{mismatched} pixels differ from {}, the frame was saved to {}", golden_path.display(), actual_path.display());
+        }
+    }
+}
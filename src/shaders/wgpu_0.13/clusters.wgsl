@@ -0,0 +1,50 @@
+// The clusters the view frustum is split into for light culling, shared by the culling compute shader and the
+// forward shader. The file is put in front of the shaders that use it, see cluster.rs.
+
+// Must match the constants in cluster.rs
+const CLUSTERS_X: u32 = 16u;
+const CLUSTERS_Y: u32 = 9u;
+const CLUSTERS_Z: u32 = 24u;
+const MAX_LIGHTS_PER_CLUSTER: u32 = 32u;
+
+// Must match cluster::Raw
+struct ClusterParams {
+    view: mat4x4<f32>,
+    // The projection's x and y scale, a view space position at depth d lands on x * scale / d in NDC
+    projection_scale: vec2<f32>,
+    z_near: f32,
+    z_far: f32,
+    screen_size: vec2<f32>,
+    show_heatmap: u32,
+};
+
+// The indices of the lights reaching a cluster, into the light buffer. count is the number of lights reaching it,
+// only the first MAX_LIGHTS_PER_CLUSTER of those are listed.
+struct ClusterLights {
+    count: u32,
+    indices: array<u32, MAX_LIGHTS_PER_CLUSTER>,
+};
+
+// Must match the cluster buffer in cluster.rs
+struct ClusterData {
+    // Directional lights reach every cluster, so they are listed once instead of taking room in every cluster
+    directional: ClusterLights,
+    clusters: array<ClusterLights>,
+};
+
+// Distance from the camera to the near side of a depth slice. Slices get deeper further away, so clusters stay
+// roughly as deep as they are wide.
+fn slice_depth(params: ClusterParams, slice: u32) -> f32 {
+    return params.z_near * pow(params.z_far / params.z_near, f32(slice) / f32(CLUSTERS_Z));
+}
+
+// The inverse of slice_depth, for a distance in front of the camera
+fn depth_slice(params: ClusterParams, depth: f32) -> u32 {
+    let slice = log(depth / params.z_near) / log(params.z_far / params.z_near) * f32(CLUSTERS_Z);
+    return min(u32(max(slice, 0.0)), CLUSTERS_Z - 1u);
+}
+
+// Clusters are numbered along x first, then y from the top of the screen, then depth
+fn cluster_index(x: u32, y: u32, z: u32) -> u32 {
+    return x + y * CLUSTERS_X + z * CLUSTERS_X * CLUSTERS_Y;
+}
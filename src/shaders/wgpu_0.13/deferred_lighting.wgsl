@@ -0,0 +1,75 @@
+// Shades the G-buffer one light at a time, put behind lighting.wgsl. Every light draws a box around its range,
+// so it only shades the pixels it can reach. Directional lights reach everything and cover the screen instead.
+
+struct Uniforms {
+    view_pos: vec4<f32>,
+    view_proj: mat4x4<f32>,
+    inv_view_proj: mat4x4<f32>,
+};
+@group(1) @binding(0)
+var<uniform> uniforms: Uniforms;
+
+// Must match DeferredShading::create_bind_group_layout in deferred.rs
+@group(0) @binding(0)
+var t_albedo: texture_2d<f32>;
+@group(0) @binding(1)
+var t_normal: texture_2d<f32>;
+@group(0) @binding(2)
+var t_material: texture_2d<f32>;
+@group(0) @binding(3)
+var t_depth: texture_2d<f32>;
+
+struct VolumeOutput {
+    @builtin(position) clip_position: vec4<f32>,
+    @location(0) @interpolate(flat) light_index: u32,
+};
+
+// Drawn with 36 vertices per light and one instance per light
+@vertex
+fn vs_main(@builtin(vertex_index) vertex_index: u32, @builtin(instance_index) light_index: u32) -> VolumeOutput {
+    let light = lights.lights[light_index];
+    var out: VolumeOutput;
+    out.light_index = light_index;
+
+    if light.kind == LIGHT_DIRECTIONAL {
+        // A single triangle covering the screen, the other vertices collapse into a point
+        let uv = vec2<f32>(f32((vertex_index << 1u) & 2u), f32(vertex_index & 2u));
+        let position = vec4<f32>(uv.x * 2.0 - 1.0, 1.0 - uv.y * 2.0, 0.0, 1.0);
+        out.clip_position = select(vec4<f32>(0.0, 0.0, 0.0, 1.0), position, vertex_index < 3u);
+        return out;
+    }
+
+    // The corners of the box's triangles, wound counter clockwise seen from outside. Corner i has its x, y and z
+    // at the far side of the box when bit 0, 1 and 2 are set.
+    var corners = array<u32, 36>(
+        0u, 6u, 2u, 0u, 4u, 6u, 1u, 3u, 7u, 1u, 7u, 5u, 0u, 1u, 5u, 0u, 5u, 4u,
+        2u, 7u, 3u, 2u, 6u, 7u, 0u, 3u, 1u, 0u, 2u, 3u, 4u, 5u, 7u, 4u, 7u, 6u,
+    );
+    let corner = corners[vertex_index];
+    let offset = vec3<f32>(f32(corner & 1u), f32((corner >> 1u) & 1u), f32((corner >> 2u) & 1u)) * 2.0 - 1.0;
+    let clip = uniforms.view_proj * vec4<f32>(light.position + offset * light.range, 1.0);
+    // Only the back faces are drawn, so the box still covers the pixels when the camera is inside of it.
+    // Pulling them in to the far plane keeps boxes reaching past it from being clipped.
+    out.clip_position = vec4<f32>(clip.xy, min(clip.z, clip.w), clip.w);
+    return out;
+}
+
+@fragment
+fn fs_main(in: VolumeOutput) -> @location(0) vec4<f32> {
+    let coords = vec2<i32>(in.clip_position.xy);
+    let depth = textureLoad(t_depth, coords, 0).r;
+    let albedo = textureLoad(t_albedo, coords, 0);
+    let normal = textureLoad(t_normal, coords, 0).xyz;
+    let material = textureLoad(t_material, coords, 0);
+
+    // Back from the pixel and its depth to world space, texture coordinates have y pointing down
+    let uv = in.clip_position.xy / vec2<f32>(textureDimensions(t_depth));
+    let ndc = vec4<f32>(uv.x * 2.0 - 1.0, 1.0 - uv.y * 2.0, depth, 1.0);
+    let world = uniforms.inv_view_proj * ndc;
+    let world_position = world.xyz / world.w;
+    let view_dir = normalize(uniforms.view_pos.xyz - world_position);
+
+    let color = light_contribution(in.light_index, world_position, normal, view_dir, albedo.rgb, material.r, material.g);
+    // Nothing was drawn where the depth is still at the far plane
+    return vec4<f32>(select(color, vec3<f32>(0.0), depth >= 1.0), 0.0);
+}
@@ -1,80 +1,77 @@
+// Forward shading, put behind material.wgsl, lighting.wgsl and clusters.wgsl
 
-
-struct VertexOutput {
-    @builtin(position) clip_position: vec4<f32>,
-    @location(0) tex_coords: vec2<f32>,
-    @location(1) tangent_position: vec3<f32>,
-    @location(2) tangent_view_position: vec3<f32>,
-    @location(3) tangent_matrix_1: vec3<f32>,
-    @location(4) tangent_matrix_2: vec3<f32>,
-    @location(5) tangent_matrix_3: vec3<f32>,
-    
-};
-
-struct Light {
-    position: vec3<f32>,
-    color: vec3<f32>,
+struct Uniforms {
+    view_pos: vec4<f32>,
+    view_proj: mat4x4<f32>,
 };
-struct Lights {
-    lights: array<Light>
+@group(1) @binding(0)
+var<uniform> uniforms: Uniforms;
+
+// Must match ClusteredLighting::create_bind_group_layout in cluster.rs
+@group(3) @binding(0)
+var<uniform> cluster_params: ClusterParams;
+@group(3) @binding(1)
+var<storage, read> cluster_data: ClusterData;
+
+// Runs from dark blue for no lights over green to red for a full cluster. Clusters with more lights than they can
+// list are magenta, the lights that didn't fit are left out of their lighting.
+fn heatmap(light_count: u32) -> vec3<f32> {
+    if light_count > MAX_LIGHTS_PER_CLUSTER {
+        return vec3<f32>(1.0, 0.0, 1.0);
+    }
+    let t = f32(light_count) / f32(MAX_LIGHTS_PER_CLUSTER) * 4.0;
+    return clamp(vec3<f32>(1.5 - abs(t - 3.0), 1.5 - abs(t - 2.0), 1.5 - abs(t - 1.0)), vec3<f32>(0.0), vec3<f32>(1.0));
 }
-@group(2) @binding(0)
-var<storage, read> lights: Lights;
-
-
-
-// Fragment shader
-@group(0) @binding(0)
-var t_diffuse: texture_2d<f32>;
-@group(0) @binding(1)
-var s_diffuse: sampler;
-@group(0) @binding(2)
-var t_normal: texture_2d<f32>;
-@group(0) @binding(3)
-var s_normal: sampler;
 
 @fragment
 fn main(in: VertexOutput) -> @location(0) vec4<f32> {
-
-    let tangent_matrix = mat3x3<f32>(
-        in.tangent_matrix_1,
-        in.tangent_matrix_2,
-        in.tangent_matrix_3,
-    ); 
-
-    let object_color: vec4<f32> = textureSample(t_diffuse, s_diffuse, in.tex_coords);
-    let object_normal: vec4<f32> = textureSample(t_normal, s_normal, in.tex_coords);
-
-    //Calculate ambient light as average color of all lights
-    let num_lights = arrayLength(&lights.lights);
-    
-    var combined_light_color = vec3(0.0, 0.0, 0.0);
-
-    for (var i = 0; i < i32(num_lights); i=i+1) {
-        let tangent_light_position = tangent_matrix * lights.lights[i].position;
-        let ambient_strength = 0.1;
-        let ambient_color = lights.lights[i].color * ambient_strength;
-
-        let tangent_normal = object_normal.xyz * 2.0 - 1.0;
-        let light_dir = normalize(tangent_light_position - in.tangent_position);
-        let view_dir = normalize(in.tangent_view_position - in.tangent_position);
-
-        // Diffuse
-        let diffuse_strength = max(dot(tangent_normal, light_dir), 0.0);
-        let diffuse_color = lights.lights[i].color * diffuse_strength;
-
-        // Specular
-        let half_dir = normalize(view_dir + light_dir);
-        let specular_strength = pow(max(dot(tangent_normal, half_dir), 0.0), 32.0);
-        let specular_color = specular_strength * lights.lights[i].color;
-
-        combined_light_color += ambient_color + diffuse_color + specular_color;
+    let surface = sample_surface(in);
+    let view_dir = normalize(uniforms.view_pos.xyz - in.world_position);
+
+    // In the fragment stage the position builtin holds the pixel coordinates
+    let tile = vec2<u32>(in.clip_position.xy / cluster_params.screen_size * vec2<f32>(f32(CLUSTERS_X), f32(CLUSTERS_Y)));
+    let depth = -(cluster_params.view * vec4<f32>(in.world_position, 1.0)).z;
+    let cluster = cluster_index(
+        min(tile.x, CLUSTERS_X - 1u),
+        min(tile.y, CLUSTERS_Y - 1u),
+        depth_slice(cluster_params, depth),
+    );
+    let num_lights = cluster_data.clusters[cluster].count;
+
+    if cluster_params.show_heatmap != 0u {
+        // Shaded a little, so the shapes stay recognizable
+        let facing = max(dot(surface.normal, view_dir), 0.0);
+        return vec4<f32>(heatmap(num_lights) * (0.5 + 0.5 * facing), 1.0);
     }
 
-    combined_light_color = combined_light_color / f32(num_lights);
-    let result = combined_light_color * object_color.xyz;
-    
-
+    // Lights add up, on top of the ambient light the whole scene gets
+    var combined_light_color = scene_lighting.ambient * surface.base_color.rgb * surface.occlusion + surface.emissive;
+
+    let num_directional = min(cluster_data.directional.count, MAX_LIGHTS_PER_CLUSTER);
+    for (var i = 0u; i < num_directional; i = i + 1u) {
+        combined_light_color += light_contribution(
+            cluster_data.directional.indices[i],
+            in.world_position,
+            surface.normal,
+            view_dir,
+            surface.base_color.rgb,
+            surface.metallic,
+            surface.roughness,
+        );
+    }
+    // Only the lights that reach the cluster, the others would add nothing
+    for (var i = 0u; i < min(num_lights, MAX_LIGHTS_PER_CLUSTER); i = i + 1u) {
+        combined_light_color += light_contribution(
+            cluster_data.clusters[cluster].indices[i],
+            in.world_position,
+            surface.normal,
+            view_dir,
+            surface.base_color.rgb,
+            surface.metallic,
+            surface.roughness,
+        );
+    }
 
-    return vec4<f32>(result, object_color.a);
+    // Left in HDR, exposure and tonemapping are post-processing passes
+    return vec4<f32>(combined_light_color, surface.base_color.a);
 }
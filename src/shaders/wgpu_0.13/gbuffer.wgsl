@@ -0,0 +1,30 @@
+// Writes the surfaces into the G-buffer for deferred shading, put behind material.wgsl and lighting.wgsl
+
+struct Uniforms {
+    view_pos: vec4<f32>,
+    view_proj: mat4x4<f32>,
+};
+@group(1) @binding(0)
+var<uniform> uniforms: Uniforms;
+
+// Must match the G-buffer formats in deferred.rs
+struct GBufferOutput {
+    // The light that doesn't come from the lights, the lighting pass adds theirs on top
+    @location(0) scene: vec4<f32>,
+    // Base color, with the ambient occlusion in alpha
+    @location(1) albedo: vec4<f32>,
+    @location(2) normal: vec4<f32>,
+    // Metallic in red and roughness in green
+    @location(3) material: vec4<f32>,
+};
+
+@fragment
+fn main(in: VertexOutput) -> GBufferOutput {
+    let surface = sample_surface(in);
+    var out: GBufferOutput;
+    out.scene = vec4<f32>(scene_lighting.ambient * surface.base_color.rgb * surface.occlusion + surface.emissive, 1.0);
+    out.albedo = vec4<f32>(surface.base_color.rgb, surface.occlusion);
+    out.normal = vec4<f32>(surface.normal, 0.0);
+    out.material = vec4<f32>(surface.metallic, surface.roughness, 0.0, 0.0);
+    return out;
+}
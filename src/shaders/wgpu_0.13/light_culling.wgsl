@@ -0,0 +1,94 @@
+// Finds the lights reaching each cluster, put behind clusters.wgsl. Runs once per cluster every frame, before the
+// forward pass reads the result.
+
+// Must match light::Raw
+struct Light {
+    position: vec3<f32>,
+    kind: u32,
+    color: vec3<f32>,
+    intensity: f32,
+    direction: vec3<f32>,
+    range: f32,
+    inner_cos: f32,
+    outer_cos: f32,
+};
+struct Lights {
+    lights: array<Light>
+}
+
+struct SceneLighting {
+    ambient: vec3<f32>,
+    light_count: u32,
+};
+
+// Must match create_culling_bind_group_layout in cluster.rs
+@group(0) @binding(0)
+var<uniform> params: ClusterParams;
+@group(0) @binding(1)
+var<storage, read> lights: Lights;
+@group(0) @binding(2)
+var<uniform> scene_lighting: SceneLighting;
+@group(0) @binding(3)
+var<storage, read_write> cluster_data: ClusterData;
+
+// Must match KIND_DIRECTIONAL in light.rs
+const LIGHT_DIRECTIONAL: u32 = 0u;
+
+// Must match WORKGROUP_SIZE in cluster.rs
+@compute @workgroup_size(64)
+fn main(@builtin(global_invocation_id) id: vec3<u32>) {
+    let index = id.x;
+    if index >= CLUSTERS_X * CLUSTERS_Y * CLUSTERS_Z {
+        return;
+    }
+    let x = index % CLUSTERS_X;
+    let y = index / CLUSTERS_X % CLUSTERS_Y;
+    let z = index / (CLUSTERS_X * CLUSTERS_Y);
+
+    // The box around the cluster in view space. The sides of the tile spread out with depth, so the box has to
+    // span both the near and the far end of it.
+    let near = slice_depth(params, z);
+    let far = slice_depth(params, z + 1u);
+    let tiles = vec2<f32>(f32(CLUSTERS_X), f32(CLUSTERS_Y));
+    // y counts tiles from the top, NDC from the bottom
+    let ndc_min = vec2<f32>(f32(x) / tiles.x, 1.0 - f32(y + 1u) / tiles.y) * 2.0 - 1.0;
+    let ndc_max = vec2<f32>(f32(x + 1u) / tiles.x, 1.0 - f32(y) / tiles.y) * 2.0 - 1.0;
+    let side_min = min(ndc_min * near, ndc_min * far) / params.projection_scale;
+    let side_max = max(ndc_max * near, ndc_max * far) / params.projection_scale;
+    // The camera looks down -z
+    let box_min = vec3<f32>(side_min, -far);
+    let box_max = vec3<f32>(side_max, -near);
+
+    // Spot lights are treated like point lights, their cone could be tested too but the sphere is enough to
+    // keep the lists short. Lights past the maximum are counted but not listed, the heat map shows where.
+    var count = 0u;
+    for (var i = 0u; i < scene_lighting.light_count; i = i + 1u) {
+        let light = lights.lights[i];
+        if light.kind == LIGHT_DIRECTIONAL {
+            continue;
+        }
+        let center = (params.view * vec4<f32>(light.position, 1.0)).xyz;
+        let offset = center - clamp(center, box_min, box_max);
+        if dot(offset, offset) <= light.range * light.range {
+            if count < MAX_LIGHTS_PER_CLUSTER {
+                cluster_data.clusters[index].indices[count] = i;
+            }
+            count = count + 1u;
+        }
+    }
+    cluster_data.clusters[index].count = count;
+
+    // The directional lights are only listed once, by the first invocation
+    if index == 0u {
+        var directional = 0u;
+        for (var i = 0u; i < scene_lighting.light_count; i = i + 1u) {
+            if lights.lights[i].kind == LIGHT_DIRECTIONAL {
+                if directional < MAX_LIGHTS_PER_CLUSTER {
+                    cluster_data.directional.indices[directional] = i;
+                }
+                directional = directional + 1u;
+            }
+        }
+        cluster_data.directional.count = directional;
+    }
+}
@@ -0,0 +1,160 @@
+// The lights and how they shade a surface, shared by the forward shader and the deferred lighting pass. The file
+// is put in front of the shaders that use it, see renderer.rs and deferred.rs.
+
+struct Light {
+    position: vec3<f32>,
+    kind: u32,
+    color: vec3<f32>,
+    intensity: f32,
+    direction: vec3<f32>,
+    range: f32,
+    inner_cos: f32,
+    outer_cos: f32,
+};
+struct Lights {
+    lights: array<Light>
+}
+@group(2) @binding(0)
+var<storage, read> lights: Lights;
+
+// Light view projection of every shadow map layer, six cube faces per light
+@group(2) @binding(1)
+var<storage, read> shadow_matrices: array<mat4x4<f32>>;
+@group(2) @binding(2)
+var t_shadow: texture_depth_2d_array;
+@group(2) @binding(3)
+var s_shadow: sampler_comparison;
+
+struct SceneLighting {
+    ambient: vec3<f32>,
+    // The light buffer may have room for more lights than it holds
+    light_count: u32,
+};
+@group(2) @binding(4)
+var<uniform> scene_lighting: SceneLighting;
+
+// Must match the KIND_* constants in light.rs
+const LIGHT_DIRECTIONAL: u32 = 0u;
+const LIGHT_POINT: u32 = 1u;
+const LIGHT_SPOT: u32 = 2u;
+
+// Must match the constants in shadow.rs
+const MAX_SHADOWED_LIGHTS: u32 = 4u;
+const SHADOW_MAP_SIZE: f32 = 1024.0;
+
+// Index of the cube face the direction points through, in the order +X, -X, +Y, -Y, +Z, -Z
+fn cube_face(direction: vec3<f32>) -> u32 {
+    let a = abs(direction);
+    if a.x >= a.y && a.x >= a.z {
+        return select(1u, 0u, direction.x > 0.0);
+    }
+    if a.y >= a.z {
+        return select(3u, 2u, direction.y > 0.0);
+    }
+    return select(5u, 4u, direction.z > 0.0);
+}
+
+// Fraction of the light reaching the position, filtered over a 3x3 texel neighbourhood (PCF).
+// The level 0 comparison works outside of uniform control flow, like the forward shader's loop over the lights of
+// a cluster.
+fn shadow_factor(light_index: u32, world_position: vec3<f32>) -> f32 {
+    let shadowed_index = min(light_index, MAX_SHADOWED_LIGHTS - 1u);
+    let light = lights.lights[shadowed_index];
+    // Only point lights use all six layers
+    let face = select(0u, cube_face(world_position - light.position), light.kind == LIGHT_POINT);
+    let layer = shadowed_index * 6u + face;
+    let light_space = shadow_matrices[layer] * vec4<f32>(world_position, 1.0);
+    let ndc = light_space.xyz / light_space.w;
+    // Texture coordinates have y pointing down
+    let uv = ndc.xy * vec2<f32>(0.5, -0.5) + 0.5;
+    let texel_size = 1.0 / SHADOW_MAP_SIZE;
+
+    var visibility = 0.0;
+    for (var x = -1; x <= 1; x = x + 1) {
+        for (var y = -1; y <= 1; y = y + 1) {
+            let offset = vec2<f32>(f32(x), f32(y)) * texel_size;
+            visibility += textureSampleCompareLevel(t_shadow, s_shadow, uv + offset, i32(layer), ndc.z);
+        }
+    }
+    // Directional shadow maps only cover the area around the camera, everything outside is lit
+    let inside = all(uv >= vec2<f32>(0.0)) && all(uv <= vec2<f32>(1.0)) && ndc.z <= 1.0;
+    let has_shadow_map = light_index < MAX_SHADOWED_LIGHTS && light_space.w > 0.0 && inside;
+    return select(1.0, visibility / 9.0, has_shadow_map);
+}
+
+// Smoothly fades a light out to zero at its range, instead of cutting it off
+fn range_attenuation(distance: f32, range: f32) -> f32 {
+    let ratio = distance / range;
+    let window = clamp(1.0 - ratio * ratio * ratio * ratio, 0.0, 1.0);
+    return window * window / (distance * distance + 1.0);
+}
+
+const PI: f32 = 3.14159265;
+
+// GGX / Trowbridge-Reitz normal distribution, how many microfacets face along the half vector
+fn distribution_ggx(n_dot_h: f32, roughness: f32) -> f32 {
+    let a = roughness * roughness;
+    let a2 = a * a;
+    let d = n_dot_h * n_dot_h * (a2 - 1.0) + 1.0;
+    return a2 / (PI * d * d);
+}
+
+// Smith's method with Schlick-GGX, how many microfacets are neither shadowed nor hidden from the viewer
+fn geometry_smith(n_dot_v: f32, n_dot_l: f32, roughness: f32) -> f32 {
+    let r = roughness + 1.0;
+    let k = r * r / 8.0;
+    let g_v = n_dot_v / (n_dot_v * (1.0 - k) + k);
+    let g_l = n_dot_l / (n_dot_l * (1.0 - k) + k);
+    return g_v * g_l;
+}
+
+// Schlick's approximation of how much light is reflected rather than refracted
+fn fresnel_schlick(cos_theta: f32, f0: vec3<f32>) -> vec3<f32> {
+    return f0 + (1.0 - f0) * pow(clamp(1.0 - cos_theta, 0.0, 1.0), 5.0);
+}
+
+// The light with the given index reflects towards the viewer from a surface
+fn light_contribution(
+    index: u32,
+    world_position: vec3<f32>,
+    normal: vec3<f32>,
+    view_dir: vec3<f32>,
+    base_color: vec3<f32>,
+    metallic: f32,
+    roughness: f32,
+) -> vec3<f32> {
+    let light = lights.lights[index];
+    let n_dot_v = max(dot(normal, view_dir), 0.0001);
+    // Dielectrics reflect about 4% of the light head on, metals reflect their own color
+    let f0 = mix(vec3<f32>(0.04), base_color, metallic);
+
+    // Directional lights shine from the same direction everywhere, the others from their position
+    var light_dir = normalize(light.position - world_position);
+    var attenuation = 1.0;
+    if light.kind == LIGHT_DIRECTIONAL {
+        light_dir = -light.direction;
+    } else {
+        let to_fragment = world_position - light.position;
+        attenuation = range_attenuation(length(to_fragment), light.range);
+        if light.kind == LIGHT_SPOT {
+            let cos_angle = dot(normalize(to_fragment), light.direction);
+            attenuation *= smoothstep(light.outer_cos, light.inner_cos, cos_angle);
+        }
+    }
+    let radiance = light.color * light.intensity * attenuation;
+
+    // Cook-Torrance specular
+    let half_dir = normalize(view_dir + light_dir);
+    let n_dot_l = max(dot(normal, light_dir), 0.0);
+    let n_dot_h = max(dot(normal, half_dir), 0.0);
+    let fresnel = fresnel_schlick(max(dot(half_dir, view_dir), 0.0), f0);
+    let specular = distribution_ggx(n_dot_h, roughness) * geometry_smith(n_dot_v, n_dot_l, roughness) * fresnel
+        / (4.0 * n_dot_v * n_dot_l + 0.0001);
+
+    // Whatever isn't reflected is diffused, except by metals which absorb it
+    let diffuse = (1.0 - fresnel) * (1.0 - metallic) * base_color / PI;
+
+    let shadow = shadow_factor(index, world_position);
+
+    return (diffuse + specular) * radiance * n_dot_l * shadow;
+}
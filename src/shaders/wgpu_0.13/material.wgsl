@@ -0,0 +1,78 @@
+// The material of a mesh and how it is sampled, shared by the forward shader and the G-buffer pass. The file is
+// put in front of the shaders that use it, see renderer.rs and deferred.rs.
+
+
+
+struct VertexOutput {
+    @builtin(position) clip_position: vec4<f32>,
+    @location(0) tex_coords: vec2<f32>,
+    @location(1) world_position: vec3<f32>,
+    @location(2) world_normal: vec3<f32>,
+    @location(3) world_tangent: vec3<f32>,
+    @location(4) world_bitangent: vec3<f32>,
+};
+
+// Must match Material::create_bind_group_layout in model.rs
+@group(0) @binding(0)
+var t_base_color: texture_2d<f32>;
+@group(0) @binding(1)
+var s_base_color: sampler;
+@group(0) @binding(2)
+var t_metallic_roughness: texture_2d<f32>;
+@group(0) @binding(3)
+var s_metallic_roughness: sampler;
+@group(0) @binding(4)
+var t_occlusion: texture_2d<f32>;
+@group(0) @binding(5)
+var s_occlusion: sampler;
+@group(0) @binding(6)
+var t_emissive: texture_2d<f32>;
+@group(0) @binding(7)
+var s_emissive: sampler;
+@group(0) @binding(8)
+var t_normal: texture_2d<f32>;
+@group(0) @binding(9)
+var s_normal: sampler;
+
+struct MaterialFactors {
+    base_color: vec4<f32>,
+    emissive: vec3<f32>,
+    metallic: f32,
+    roughness: f32,
+    occlusion_strength: f32,
+    normal_scale: f32,
+};
+@group(0) @binding(10)
+var<uniform> material: MaterialFactors;
+
+// What the material looks like at a fragment
+struct Surface {
+    base_color: vec4<f32>,
+    metallic: f32,
+    roughness: f32,
+    occlusion: f32,
+    emissive: vec3<f32>,
+    // In world space, with the normal map applied
+    normal: vec3<f32>,
+};
+
+fn sample_surface(in: VertexOutput) -> Surface {
+    var surface: Surface;
+    surface.base_color = textureSample(t_base_color, s_base_color, in.tex_coords) * material.base_color;
+    let metallic_roughness = textureSample(t_metallic_roughness, s_metallic_roughness, in.tex_coords);
+    surface.metallic = metallic_roughness.b * material.metallic;
+    // Perfectly smooth surfaces turn lights into invisible points
+    surface.roughness = clamp(metallic_roughness.g * material.roughness, 0.04, 1.0);
+    surface.occlusion = mix(1.0, textureSample(t_occlusion, s_occlusion, in.tex_coords).r, material.occlusion_strength);
+    surface.emissive = textureSample(t_emissive, s_emissive, in.tex_coords).rgb * material.emissive;
+
+    let tangent_matrix = mat3x3<f32>(
+        normalize(in.world_tangent),
+        normalize(in.world_bitangent),
+        normalize(in.world_normal),
+    );
+    let tangent_normal = (textureSample(t_normal, s_normal, in.tex_coords).xyz * 2.0 - 1.0)
+        * vec3<f32>(material.normal_scale, material.normal_scale, 1.0);
+    surface.normal = normalize(tangent_matrix * tangent_normal);
+    return surface;
+}
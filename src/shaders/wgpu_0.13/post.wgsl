@@ -0,0 +1,84 @@
+// The full screen passes of the post-processing chain in post.rs, one fragment entry point per effect
+
+struct VertexOutput {
+    @builtin(position) clip_position: vec4<f32>,
+    @location(0) tex_coords: vec2<f32>,
+};
+
+@group(0) @binding(0)
+var t_input: texture_2d<f32>;
+// Only read by the passes that combine two images, the others get the input again
+@group(0) @binding(1)
+var t_second: texture_2d<f32>;
+@group(0) @binding(2)
+var s_input: sampler;
+// What the values mean depends on the effect, see PostProcessing::build_steps
+@group(0) @binding(3)
+var<uniform> params: vec4<f32>;
+
+// A single triangle that covers the whole screen, so no vertex buffer is needed
+@vertex
+fn vs_main(@builtin(vertex_index) index: u32) -> VertexOutput {
+    let uv = vec2<f32>(f32((index << 1u) & 2u), f32(index & 2u));
+    var out: VertexOutput;
+    out.clip_position = vec4<f32>(uv.x * 2.0 - 1.0, 1.0 - uv.y * 2.0, 0.0, 1.0);
+    out.tex_coords = uv;
+    return out;
+}
+
+// Keeps only the light above the threshold in params.x
+@fragment
+fn bright_pass(in: VertexOutput) -> @location(0) vec4<f32> {
+    let color = textureSample(t_input, s_input, in.tex_coords).rgb;
+    let brightness = max(color.r, max(color.g, color.b));
+    // Scaling instead of cutting off keeps colors that are just above the threshold from popping in
+    let contribution = max(brightness - params.x, 0.0) / max(brightness, 0.0001);
+    return vec4<f32>(color * contribution, 1.0);
+}
+
+// One direction of a separable 9 tap gaussian blur, params.xy is the distance between two texels along it
+@fragment
+fn blur(in: VertexOutput) -> @location(0) vec4<f32> {
+    var weights = array<f32, 5>(0.227027, 0.1945946, 0.1216216, 0.054054, 0.016216);
+    var result = textureSample(t_input, s_input, in.tex_coords).rgb * weights[0];
+    for (var i = 1; i < 5; i = i + 1) {
+        let offset = params.xy * f32(i);
+        result += textureSample(t_input, s_input, in.tex_coords + offset).rgb * weights[i];
+        result += textureSample(t_input, s_input, in.tex_coords - offset).rgb * weights[i];
+    }
+    return vec4<f32>(result, 1.0);
+}
+
+// Adds the blurred bright parts on top of the image, scaled by params.x
+@fragment
+fn composite(in: VertexOutput) -> @location(0) vec4<f32> {
+    let color = textureSample(t_input, s_input, in.tex_coords).rgb;
+    let bloom = textureSample(t_second, s_input, in.tex_coords).rgb;
+    return vec4<f32>(color + bloom * params.x, 1.0);
+}
+
+@fragment
+fn exposure(in: VertexOutput) -> @location(0) vec4<f32> {
+    let color = textureSample(t_input, s_input, in.tex_coords).rgb;
+    return vec4<f32>(color * params.x, 1.0);
+}
+
+// Krzysztof Narkowicz's fit of the ACES filmic curve
+@fragment
+fn tonemap_aces(in: VertexOutput) -> @location(0) vec4<f32> {
+    let x = textureSample(t_input, s_input, in.tex_coords).rgb;
+    let mapped = (x * (2.51 * x + 0.03)) / (x * (2.43 * x + 0.59) + 0.14);
+    return vec4<f32>(clamp(mapped, vec3<f32>(0.0), vec3<f32>(1.0)), 1.0);
+}
+
+@fragment
+fn tonemap_reinhard(in: VertexOutput) -> @location(0) vec4<f32> {
+    let x = textureSample(t_input, s_input, in.tex_coords).rgb;
+    return vec4<f32>(x / (1.0 + x), 1.0);
+}
+
+// Copies the result into the frame, the sRGB frame format takes care of the encoding
+@fragment
+fn blit(in: VertexOutput) -> @location(0) vec4<f32> {
+    return vec4<f32>(textureSample(t_input, s_input, in.tex_coords).rgb, 1.0);
+}
@@ -0,0 +1,40 @@
+// The skybox, drawn as a single triangle covering the screen at the far plane. Only the pixels no geometry was
+// drawn to pass the depth test.
+
+struct Camera {
+    view_pos: vec4<f32>,
+    view_proj: mat4x4<f32>,
+    inv_view_proj: mat4x4<f32>,
+};
+@group(1) @binding(0)
+var<uniform> camera: Camera;
+
+// Must match Skybox::create_bind_group_layout in skybox.rs
+@group(0) @binding(0)
+var t_sky: texture_cube<f32>;
+@group(0) @binding(1)
+var s_sky: sampler;
+
+struct VertexOutput {
+    @builtin(position) clip_position: vec4<f32>,
+    @location(0) ndc: vec2<f32>,
+};
+
+@vertex
+fn vs_main(@builtin(vertex_index) index: u32) -> VertexOutput {
+    let uv = vec2<f32>(f32((index << 1u) & 2u), f32(index & 2u));
+    let ndc = vec2<f32>(uv.x * 2.0 - 1.0, 1.0 - uv.y * 2.0);
+    var out: VertexOutput;
+    // A depth of 1 is as far away as the depth buffer goes
+    out.clip_position = vec4<f32>(ndc, 1.0, 1.0);
+    out.ndc = ndc;
+    return out;
+}
+
+@fragment
+fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
+    // The direction the camera looks in through this pixel
+    let far = camera.inv_view_proj * vec4<f32>(in.ndc, 1.0, 1.0);
+    let direction = far.xyz / far.w - camera.view_pos.xyz;
+    return vec4<f32>(textureSample(t_sky, s_sky, direction).rgb, 1.0);
+}
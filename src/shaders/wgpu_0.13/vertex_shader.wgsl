@@ -22,7 +22,13 @@ var<uniform> uniforms: Uniforms;
 
 struct Light {
     position: vec3<f32>,
+    kind: u32,
     color: vec3<f32>,
+    intensity: f32,
+    direction: vec3<f32>,
+    range: f32,
+    inner_cos: f32,
+    outer_cos: f32,
 };
 struct Lights {
     lights: array<Light>
@@ -43,12 +49,10 @@ struct VertexInput {
 struct VertexOutput {
     @builtin(position) clip_position: vec4<f32>,
     @location(0) tex_coords: vec2<f32>,
-    @location(1) tangent_position: vec3<f32>,
-    @location(2) tangent_view_position: vec3<f32>,
-    @location(3) tangent_matrix_1: vec3<f32>,
-    @location(4) tangent_matrix_2: vec3<f32>,
-    @location(5) tangent_matrix_3: vec3<f32>,
-    
+    @location(1) world_position: vec3<f32>,
+    @location(2) world_normal: vec3<f32>,
+    @location(3) world_tangent: vec3<f32>,
+    @location(4) world_bitangent: vec3<f32>,
 };
 
 @vertex
@@ -68,26 +72,18 @@ fn main(
         instance.normal_matrix_2,
     );
 
-    let world_normal = normalize(normal_matrix * model.normal);
-    let world_tangent = normalize(normal_matrix * model.tangent);
-    let world_bitangent = normalize(normal_matrix * model.bitangent);
-    let tangent_matrix = transpose(mat3x3<f32>(
-        world_tangent,
-        world_bitangent,
-        world_normal,
-    ));
-
     let world_position = model_matrix * vec4<f32>(model.position, 1.0);
 
+    // Shading happens in world space, the fragment shader builds the tangent frame from these
     var out: VertexOutput;
     out.clip_position = uniforms.view_proj * world_position;
     out.tex_coords = model.tex_coords;
-    out.tangent_position = tangent_matrix * world_position.xyz;
-    out.tangent_view_position = tangent_matrix * uniforms.view_pos.xyz;
-    //out.tangent_light_position = tangent_matrix * lights.lights[0].position;
-    out.tangent_matrix_1 = tangent_matrix[0];
-    out.tangent_matrix_1 = tangent_matrix[1];
-    out.tangent_matrix_1 = tangent_matrix[2];
+    out.world_position = world_position.xyz;
+    // Tangents lie in the surface, so they follow the model matrix. Normals need the inverse-transpose
+    // to stay perpendicular to the surface under non-uniform scale.
+    let surface_matrix = mat3x3<f32>(model_matrix[0].xyz, model_matrix[1].xyz, model_matrix[2].xyz);
+    out.world_normal = normalize(normal_matrix * model.normal);
+    out.world_tangent = normalize(surface_matrix * model.tangent);
+    out.world_bitangent = normalize(surface_matrix * model.bitangent);
     return out;
 }
-
@@ -10,7 +10,13 @@ var<uniform> camera: Camera;
 
 struct Light {
     position: vec3<f32>,
+    kind: u32,
     color: vec3<f32>,
+    intensity: f32,
+    direction: vec3<f32>,
+    range: f32,
+    inner_cos: f32,
+    outer_cos: f32,
 };
 struct Lights {
     lights: array<Light>
@@ -0,0 +1,261 @@
+use cgmath::{Matrix4, Point3, Vector3};
+
+use crate::{asset::AssetServer, camera::OPENGL_TO_WGPU_MATRIX, graph::{RenderGraph, TextureId}, instance::InstanceBatches, light, model::{self, DrawShadow, Vertex}, texture::Texture, transform};
+
+// Only the first lights in the light buffer cast shadows, must match MAX_SHADOWED_LIGHTS in fragment_shader.wgsl
+pub const MAX_SHADOWED_LIGHTS: u32 = 4;
+// Must match SHADOW_MAP_SIZE in fragment_shader.wgsl
+pub const SHADOW_MAP_SIZE: u32 = 1024;
+// Every shadowed light gets six layers, a point light renders one shadow map for each face of a cube around it
+// while directional and spot lights only use the first
+const LAYERS_PER_LIGHT: u32 = 6;
+const SHADOW_LAYERS: u32 = MAX_SHADOWED_LIGHTS * LAYERS_PER_LIGHT;
+#[allow(clippy::cast_possible_truncation)]
+const MATRIX_SIZE: u32 = std::mem::size_of::<[[f32; 4]; 4]>() as u32;
+const SHADOW_NEAR: f32 = 0.1;
+const SHADOW_FAR: f32 = 100.0;
+// The field of view a spot light's shadow map is kept within
+const MIN_SPOT_FOV: f32 = 0.01;
+const MAX_SPOT_FOV: f32 = std::f32::consts::PI - 0.01;
+// Half the width of the area around the camera covered by a directional light's shadow map
+const DIRECTIONAL_SHADOW_EXTENT: f32 = 30.0;
+
+// Look direction and up vector of the cube faces, in the order +X, -X, +Y, -Y, +Z, -Z which the shader relies on
+const CUBE_FACES: [([f32; 3], [f32; 3]); LAYERS_PER_LIGHT as usize] = [
+    ([1.0, 0.0, 0.0], [0.0, -1.0, 0.0]),
+    ([-1.0, 0.0, 0.0], [0.0, -1.0, 0.0]),
+    ([0.0, 1.0, 0.0], [0.0, 0.0, 1.0]),
+    ([0.0, -1.0, 0.0], [0.0, 0.0, -1.0]),
+    ([0.0, 0.0, 1.0], [0.0, -1.0, 0.0]),
+    ([0.0, 0.0, -1.0], [0.0, -1.0, 0.0]),
+];
+
+// Depth maps rendered from the point of view of each shadow casting light
+pub struct ShadowMaps {
+    pub texture: Texture,
+    layer_views: Vec<wgpu::TextureView>,
+    // The light view projection of every layer, read by the fragment shader
+    pub matrix_buffer: wgpu::Buffer,
+    // The same matrices, each padded to the uniform offset alignment so the shadow pass can bind one at a time
+    pass_buffer: wgpu::Buffer,
+    pass_bind_group: wgpu::BindGroup,
+    pass_stride: u32,
+    pipeline: wgpu::RenderPipeline,
+    // Layers that were given a view projection in the last update and need to be rendered
+    active_layers: Vec<u32>,
+}
+
+impl ShadowMaps {
+    pub fn new(device: &wgpu::Device) -> Self {
+        let texture = Texture::create_shadow_texture(device, SHADOW_MAP_SIZE, SHADOW_LAYERS, "shadow_texture");
+        let layer_views = (0..SHADOW_LAYERS)
+            .map(|layer| {
+                texture.texture.create_view(&wgpu::TextureViewDescriptor {
+                    label: Some("shadow_layer_view"),
+                    dimension: Some(wgpu::TextureViewDimension::D2),
+                    base_array_layer: layer,
+                    array_layer_count: Some(1),
+                    ..wgpu::TextureViewDescriptor::default()
+                })
+            })
+            .collect();
+
+        let matrix_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Shadow Matrix Buffer"),
+            size: u64::from(MATRIX_SIZE * SHADOW_LAYERS),
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+
+        let alignment = device.limits().min_uniform_buffer_offset_alignment;
+        let pass_stride = wgpu::util::align_to(MATRIX_SIZE, alignment);
+        let pass_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Shadow Pass Buffer"),
+            size: u64::from(pass_stride) * u64::from(SHADOW_LAYERS),
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        let pass_bind_group_layout = create_pass_bind_group_layout(device);
+        let pass_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout: &pass_bind_group_layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: wgpu::BindingResource::Buffer(wgpu::BufferBinding {
+                        buffer: &pass_buffer,
+                        offset: 0,
+                        size: wgpu::BufferSize::new(u64::from(MATRIX_SIZE)),
+                    }),
+                },
+            ],
+            label: Some("shadow_pass_bind_group"),
+        });
+
+        let pipeline = create_shadow_pipeline(device, &pass_bind_group_layout);
+
+        Self {
+            texture,
+            layer_views,
+            matrix_buffer,
+            pass_buffer,
+            pass_bind_group,
+            pass_stride,
+            pipeline,
+            active_layers: Vec::new(),
+        }
+    }
+
+    // Recomputes the light view projections, call whenever the lights or the camera have moved.
+    // Directional shadows cover the area around the focus point, which should be the camera position.
+    pub fn update(&mut self, queue: &wgpu::Queue, lights: &[light::Raw], focus: Point3<f32>) {
+        self.active_layers.clear();
+        for (light_index, light) in (0u32..).zip(lights.iter().take(MAX_SHADOWED_LIGHTS as usize)) {
+            let base_layer = light_index * LAYERS_PER_LIGHT;
+            let matrices = match light.kind {
+                light::KIND_POINT => cube_view_projections(light.position.into(), light.range).to_vec(),
+                light::KIND_SPOT => vec![spot_view_projection(light)],
+                _ => vec![directional_view_projection(light.direction.into(), focus)],
+            };
+            for (layer, matrix) in (base_layer..).zip(&matrices) {
+                queue.write_buffer(&self.matrix_buffer, u64::from(MATRIX_SIZE * layer), bytemuck::cast_slice(&[*matrix]));
+                queue.write_buffer(&self.pass_buffer, u64::from(self.pass_stride * layer), bytemuck::cast_slice(&[*matrix]));
+                self.active_layers.push(layer);
+            }
+        }
+    }
+
+    // Adds the pass rendering the shadow maps, returns the shadow texture the passes sampling it have to read
+    pub fn add_pass<'a>(&'a self, graph: &mut RenderGraph<'a>, instance_batches: &'a InstanceBatches, assets: &'a AssetServer) -> TextureId {
+        let texture = graph.import_texture(&self.texture.view);
+        let mut pass = graph.add_pass("shadows");
+        let texture = pass.write_texture(texture);
+        pass.execute(move |context| self.render(context.encoder, instance_batches, assets));
+        texture
+    }
+
+    // Renders the depth of every renderable into the layers of the shadow casting lights
+    fn render(&self, encoder: &mut wgpu::CommandEncoder, instance_batches: &InstanceBatches, assets: &AssetServer) {
+        for &layer in &self.active_layers {
+            let view = &self.layer_views[layer as usize];
+            let mut shadow_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                label: Some("Shadow Pass"),
+                color_attachments: &[],
+                depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
+                    view,
+                    depth_ops: Some(wgpu::Operations {
+                        load: wgpu::LoadOp::Clear(1.0),
+                        store: true,
+                    }),
+                    stencil_ops: None,
+                }),
+            });
+            shadow_pass.set_pipeline(&self.pipeline);
+            shadow_pass.set_bind_group(0, &self.pass_bind_group, &[self.pass_stride * layer]);
+
+            for (model, batch) in instance_batches.iter() {
+                shadow_pass.set_vertex_buffer(1, batch.buffer.slice(..));
+                shadow_pass.draw_shadow_model_instanced(assets.model(model), 0..batch.count);
+            }
+        }
+    }
+}
+
+fn cube_view_projections(position: Point3<f32>, range: f32) -> [[[f32; 4]; 4]; LAYERS_PER_LIGHT as usize] {
+    let projection = OPENGL_TO_WGPU_MATRIX * cgmath::perspective(cgmath::Deg(90.0), 1.0, SHADOW_NEAR, range.clamp(1.0, SHADOW_FAR));
+    CUBE_FACES.map(|(direction, up)| {
+        let view = Matrix4::look_to_rh(position, Vector3::from(direction), Vector3::from(up));
+        (projection * view).into()
+    })
+}
+
+fn spot_view_projection(light: &light::Raw) -> [[f32; 4]; 4] {
+    // perspective needs an angle strictly between 0 and 180°, a cone of zero width still gets a sliver of shadow map
+    let fovy = cgmath::Rad((2.0 * light.outer_cos.clamp(-1.0, 1.0).acos()).clamp(MIN_SPOT_FOV, MAX_SPOT_FOV));
+    let projection = OPENGL_TO_WGPU_MATRIX * cgmath::perspective(fovy, 1.0, SHADOW_NEAR, light.range.clamp(1.0, SHADOW_FAR));
+    let direction = Vector3::from(light.direction);
+    let view = Matrix4::look_to_rh(light.position.into(), direction, up_vector(direction));
+    (projection * view).into()
+}
+
+fn directional_view_projection(direction: Vector3<f32>, focus: Point3<f32>) -> [[f32; 4]; 4] {
+    let extent = DIRECTIONAL_SHADOW_EXTENT;
+    let projection = OPENGL_TO_WGPU_MATRIX * cgmath::ortho(-extent, extent, -extent, extent, SHADOW_NEAR, SHADOW_FAR);
+    // Back the light up so everything within the extent of the focus point is in front of it
+    let eye = focus - direction * (SHADOW_FAR / 2.0);
+    let view = Matrix4::look_to_rh(eye, direction, up_vector(direction));
+    (projection * view).into()
+}
+
+// Any up vector works for a light, as long as it isn't parallel to the direction it is looking in
+fn up_vector(direction: Vector3<f32>) -> Vector3<f32> {
+    if direction.y.abs() > 0.99 {
+        Vector3::unit_z()
+    } else {
+        Vector3::unit_y()
+    }
+}
+
+fn create_pass_bind_group_layout(device: &wgpu::Device) -> wgpu::BindGroupLayout {
+    device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+        entries: &[
+            wgpu::BindGroupLayoutEntry {
+                binding: 0,
+                visibility: wgpu::ShaderStages::VERTEX,
+                ty: wgpu::BindingType::Buffer {
+                    ty: wgpu::BufferBindingType::Uniform,
+                    has_dynamic_offset: true,
+                    min_binding_size: None,
+                },
+                count: None,
+            },
+        ],
+        label: Some("shadow_pass_bind_group_layout"),
+    })
+}
+
+fn create_shadow_pipeline(device: &wgpu::Device, pass_bind_group_layout: &wgpu::BindGroupLayout) -> wgpu::RenderPipeline {
+    let layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+        label: Some("Shadow Pipeline Layout"),
+        bind_group_layouts: &[pass_bind_group_layout],
+        push_constant_ranges: &[],
+    });
+    let shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+        label: Some("Shadow Shader Vertex"),
+        source: wgpu::ShaderSource::Wgsl(include_str!("shaders/wgpu_0.13/vertex_shader_shadow.wgsl").into()),
+    });
+
+    // Depth only, so there is no fragment stage
+    device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+        label: Some("Shadow Pipeline"),
+        layout: Some(&layout),
+        vertex: wgpu::VertexState {
+            module: &shader,
+            entry_point: "main",
+            buffers: &[model::ModelVertex::desc(), transform::Raw::desc()],
+        },
+        fragment: None,
+        primitive: wgpu::PrimitiveState {
+            topology: wgpu::PrimitiveTopology::TriangleList,
+            strip_index_format: None,
+            front_face: wgpu::FrontFace::Ccw,
+            cull_mode: Some(wgpu::Face::Back),
+            polygon_mode: wgpu::PolygonMode::Fill,
+            conservative: false,
+            unclipped_depth: false,
+        },
+        depth_stencil: Some(wgpu::DepthStencilState {
+            format: Texture::DEPTH_FORMAT,
+            depth_write_enabled: true,
+            depth_compare: wgpu::CompareFunction::LessEqual,
+            stencil: wgpu::StencilState::default(),
+            // Pushes the stored depth away from the light to avoid shadow acne
+            bias: wgpu::DepthBiasState {
+                constant: 2,
+                slope_scale: 2.0,
+                clamp: 0.0,
+            },
+        }),
+        multisample: wgpu::MultisampleState::default(),
+        multiview: None,
+    })
+}
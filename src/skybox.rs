@@ -0,0 +1,126 @@
+use anyhow::Result;
+use std::path::PathBuf;
+
+use crate::texture::Texture;
+
+// Where the cube texture of a skybox comes from, kept to load it again on a new device
+#[derive(Debug, Clone)]
+pub enum SkyboxSource {
+    // Six square images, see Texture::load_cube
+    Faces([PathBuf; 6]),
+    // A Radiance HDR image, see Texture::load_equirectangular
+    Equirectangular(PathBuf),
+}
+
+impl SkyboxSource {
+    fn load(&self, device: &wgpu::Device, queue: &wgpu::Queue) -> Result<Texture> {
+        match self {
+            Self::Faces(faces) => Texture::load_cube(device, queue, faces, "skybox"),
+            Self::Equirectangular(path) => Texture::load_equirectangular(device, queue, path, "skybox"),
+        }
+    }
+}
+
+// A cube texture shown wherever the scene has no geometry, in place of the clear color
+pub struct Skybox {
+    // Kept alive for the bind group
+    _texture: Texture,
+    pub source: SkyboxSource,
+    pub bind_group: wgpu::BindGroup,
+}
+
+impl Skybox {
+    pub fn load(device: &wgpu::Device, queue: &wgpu::Queue, source: SkyboxSource) -> Result<Self> {
+        let texture = source.load(device, queue)?;
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout: &Self::create_bind_group_layout(device),
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: wgpu::BindingResource::TextureView(&texture.view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::Sampler(&texture.sampler),
+                },
+            ],
+            label: Some("skybox_bind_group"),
+        });
+        Ok(Self { _texture: texture, source, bind_group })
+    }
+
+    pub fn create_bind_group_layout(device: &wgpu::Device) -> wgpu::BindGroupLayout {
+        device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            entries: &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Texture {
+                        multisampled: false,
+                        view_dimension: wgpu::TextureViewDimension::Cube,
+                        sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+                    count: None,
+                },
+            ],
+            label: Some("skybox_bind_group_layout"),
+        })
+    }
+}
+
+// Draws the skybox behind everything already in the depth buffer, without writing to it
+pub fn create_pipeline(
+    device: &wgpu::Device,
+    camera_bind_group_layout: &wgpu::BindGroupLayout,
+    color_format: wgpu::TextureFormat,
+    sample_count: u32,
+) -> wgpu::RenderPipeline {
+    let layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+        label: Some("Skybox Pipeline Layout"),
+        bind_group_layouts: &[&Skybox::create_bind_group_layout(device), camera_bind_group_layout],
+        push_constant_ranges: &[],
+    });
+    let shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+        label: Some("Skybox Shader"),
+        source: wgpu::ShaderSource::Wgsl(include_str!("shaders/wgpu_0.13/skybox.wgsl").into()),
+    });
+
+    device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+        label: Some("Skybox Pipeline"),
+        layout: Some(&layout),
+        vertex: wgpu::VertexState {
+            module: &shader,
+            entry_point: "vs_main",
+            buffers: &[],
+        },
+        fragment: Some(wgpu::FragmentState {
+            module: &shader,
+            entry_point: "fs_main",
+            targets: &[Some(wgpu::ColorTargetState {
+                format: color_format,
+                blend: None,
+                write_mask: wgpu::ColorWrites::ALL,
+            })],
+        }),
+        primitive: wgpu::PrimitiveState::default(),
+        // The skybox lies on the far plane, which only the cleared depth is not in front of
+        depth_stencil: Some(wgpu::DepthStencilState {
+            format: Texture::DEPTH_FORMAT,
+            depth_write_enabled: false,
+            depth_compare: wgpu::CompareFunction::LessEqual,
+            stencil: wgpu::StencilState::default(),
+            bias: wgpu::DepthBiasState::default(),
+        }),
+        multisample: wgpu::MultisampleState {
+            count: sample_count,
+            ..wgpu::MultisampleState::default()
+        },
+        multiview: None,
+    })
+}
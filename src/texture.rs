@@ -1,7 +1,7 @@
-use image::GenericImageView;
-use anyhow::Result;
-use wgpu::Device;
-use std::path::Path;
+use cgmath::{InnerSpace, Vector3};
+use image::{codecs::hdr::HdrDecoder, GenericImageView, Rgb};
+use anyhow::{anyhow, bail, Result};
+use std::{f32::consts::PI, fs::File, io::BufReader, path::Path};
 #[derive(Debug)]
 pub struct Texture {
     pub texture: wgpu::Texture,
@@ -12,29 +12,15 @@ pub struct Texture {
 impl Texture {
     pub const DEPTH_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Depth32Float;
 
-    pub fn load<P: AsRef<Path>>(
-        device: &wgpu::Device,
-        queue: &wgpu::Queue,
-        path: P,
-        is_normal_map: bool,
-    ) -> Result<Self> {
-        // Needed to appease the borrow checker
-        let path_copy = path.as_ref().to_path_buf();
-        let label = path_copy.to_str();
-        
-        let img = image::open(path)?;
-        Ok(Self::from_image(device, queue, &img, label, is_normal_map))
-    }
-
     pub fn from_bytes(
         device: &wgpu::Device,
         queue: &wgpu::Queue,
         bytes: &[u8], 
         label: &str,
-        is_normal_map: bool,
+        is_linear: bool,
     ) -> Result<Self> {
         let img = image::load_from_memory(bytes)?;
-        Ok(Self::from_image(device, queue, &img, Some(label), is_normal_map))
+        Ok(Self::from_image(device, queue, &img, Some(label), is_linear))
     }
 
     pub fn from_image(
@@ -42,7 +28,7 @@ impl Texture {
         queue: &wgpu::Queue,
         img: &image::DynamicImage,
         label: Option<&str>,
-        is_normal_map: bool,
+        is_linear: bool,
     ) -> Self {
         let rgba = img.to_rgba8(); 
         let dimensions = img.dimensions();
@@ -59,12 +45,15 @@ impl Texture {
                 mip_level_count: 1,
                 sample_count: 1,
                 dimension: wgpu::TextureDimension::D2,
-                format: if is_normal_map {
+                // Normal maps and other non-color data must not be gamma corrected
+                format: if is_linear {
                     wgpu::TextureFormat::Rgba8Unorm
                 } else {
                     wgpu::TextureFormat::Rgba8UnormSrgb
                 },
-                view_formats: &[wgpu::TextureFormat::Rgba8Unorm, wgpu::TextureFormat::Rgba8UnormSrgb],
+                // Only ever viewed in its own format. Listing others needs VIEW_FORMATS, which the GL and fallback
+                // adapters headless rendering runs on don't support
+                view_formats: &[],
                 usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
             }
         );
@@ -101,87 +90,384 @@ impl Texture {
         Self { texture, view, sampler }
     }
 
-    pub fn create_depth_texture(device: &wgpu::Device, sc_desc: &wgpu::SurfaceConfiguration, label: &str) -> Self {
-        let size = wgpu::Extent3d { // 2.
+    // 1x1 texture of a single color, used in place of textures a material doesn't have
+    pub fn from_color(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        color: [u8; 4],
+        label: &str,
+        is_linear: bool,
+    ) -> Self {
+        let img = image::DynamicImage::ImageRgba8(image::RgbaImage::from_pixel(1, 1, image::Rgba(color)));
+        Self::from_image(device, queue, &img, Some(label), is_linear)
+    }
+
+    // Cube texture from six square images of the same size, in the order +X, -X, +Y, -Y, +Z, -Z
+    pub fn load_cube<P: AsRef<Path>>(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        faces: &[P; 6],
+        label: &str,
+    ) -> Result<Self> {
+        let mut size = None;
+        let mut data = Vec::new();
+        for face in faces {
+            let img = image::open(face)?;
+            let dimensions = img.dimensions();
+            if dimensions.0 != dimensions.1 || size.is_some_and(|size| size != dimensions.0) {
+                bail!("Cube face {} is {}x{}, the faces have to be square and all the same size", face.as_ref().display(), dimensions.0, dimensions.1);
+            }
+            size = Some(dimensions.0);
+            data.extend_from_slice(&img.to_rgba8());
+        }
+        let size = size.unwrap_or_default();
+        Ok(Self::from_cube_faces(device, queue, size, wgpu::TextureFormat::Rgba8UnormSrgb, &data, label))
+    }
+
+    // Cube texture from an equirectangular (latitude-longitude) Radiance HDR image. Keeps the full range of the
+    // image, the faces are half as wide as the image is high.
+    #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss, clippy::cast_precision_loss)]
+    pub fn load_equirectangular<P: AsRef<Path>>(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        path: P,
+        label: &str,
+    ) -> Result<Self> {
+        let decoder = HdrDecoder::new(BufReader::new(File::open(path)?))?;
+        let (width, height) = (decoder.metadata().width, decoder.metadata().height);
+        let pixels = decoder.read_image_hdr()?;
+        let size = (height / 2).max(1);
+
+        let mut data: Vec<u16> = Vec::with_capacity((size * size * 6 * 4) as usize);
+        for face in 0..6 {
+            for face_row in 0..size {
+                for face_column in 0..size {
+                    // The centre of the texel, from -1 to 1 across the face
+                    let across = ((face_column as f32 + 0.5) / size as f32).mul_add(2.0, -1.0);
+                    let down = ((face_row as f32 + 0.5) / size as f32).mul_add(2.0, -1.0);
+                    let (column, row) = equirectangular_texel(cube_direction(face, across, down), width, height);
+                    let Rgb([red, green, blue]) = pixels[(row * width + column) as usize];
+                    data.extend([red, green, blue, 1.0].map(f16_bits));
+                }
+            }
+        }
+        Ok(Self::from_cube_faces(device, queue, size, wgpu::TextureFormat::Rgba16Float, bytemuck::cast_slice(&data), label))
+    }
+
+    // The six faces follow each other in data, tightly packed
+    fn from_cube_faces(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        size: u32,
+        format: wgpu::TextureFormat,
+        data: &[u8],
+        label: &str,
+    ) -> Self {
+        let extent = wgpu::Extent3d {
+            width: size,
+            height: size,
+            depth_or_array_layers: 6,
+        };
+        let texture = device.create_texture(&wgpu::TextureDescriptor {
+            label: Some(label),
+            size: extent,
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format,
+            view_formats: &[],
+            usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
+        });
+        let bytes_per_texel = format.block_size(None).unwrap_or(4);
+        queue.write_texture(
+            wgpu::ImageCopyTexture {
+                texture: &texture,
+                mip_level: 0,
+                origin: wgpu::Origin3d::ZERO,
+                aspect: wgpu::TextureAspect::All,
+            },
+            data,
+            wgpu::ImageDataLayout {
+                offset: 0,
+                bytes_per_row: Some(bytes_per_texel * size),
+                rows_per_image: Some(size),
+            },
+            extent,
+        );
+
+        let view = texture.create_view(&wgpu::TextureViewDescriptor {
+            label: Some(label),
+            dimension: Some(wgpu::TextureViewDimension::Cube),
+            ..wgpu::TextureViewDescriptor::default()
+        });
+        let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            address_mode_u: wgpu::AddressMode::ClampToEdge,
+            address_mode_v: wgpu::AddressMode::ClampToEdge,
+            address_mode_w: wgpu::AddressMode::ClampToEdge,
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
+            mipmap_filter: wgpu::FilterMode::Nearest,
+            ..wgpu::SamplerDescriptor::default()
+        });
+
+        Self { texture, view, sampler }
+    }
+
+    // Array of square depth textures, one layer per shadow map. The view covers all layers.
+    pub fn create_shadow_texture(device: &wgpu::Device, size: u32, layers: u32, label: &str) -> Self {
+        // The GL backend turns square textures with a multiple of six layers into cube maps,
+        // which can't be sampled as a 2D array, so allocate a spare layer in that case
+        let layers = if layers.is_multiple_of(6) { layers + 1 } else { layers };
+        let texture = device.create_texture(&wgpu::TextureDescriptor {
+            label: Some(label),
+            size: wgpu::Extent3d {
+                width: size,
+                height: size,
+                depth_or_array_layers: layers,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: Self::DEPTH_FORMAT,
+            view_formats: &[],
+            usage: wgpu::TextureUsages::RENDER_ATTACHMENT
+                | wgpu::TextureUsages::TEXTURE_BINDING,
+        });
+
+        let view = texture.create_view(&wgpu::TextureViewDescriptor {
+            label: Some(label),
+            dimension: Some(wgpu::TextureViewDimension::D2Array),
+            ..wgpu::TextureViewDescriptor::default()
+        });
+        let sampler = create_comparison_sampler(device);
+
+        Self { texture, view, sampler }
+    }
+
+    // Color texture that can be rendered into and copied out of, used instead of a swapchain image when rendering offscreen
+    pub fn create_render_target(device: &wgpu::Device, sc_desc: &wgpu::SurfaceConfiguration, label: &str) -> Self {
+        let size = wgpu::Extent3d {
             width: sc_desc.width,
             height: sc_desc.height,
             depth_or_array_layers: 1,
         };
-        let desc = wgpu::TextureDescriptor {
+        let texture = device.create_texture(&wgpu::TextureDescriptor {
             label: Some(label),
             size,
             mip_level_count: 1,
             sample_count: 1,
             dimension: wgpu::TextureDimension::D2,
-            format: Self::DEPTH_FORMAT,
-            view_formats: &[Self::DEPTH_FORMAT],
-            usage: wgpu::TextureUsages::RENDER_ATTACHMENT // 3.
+            format: sc_desc.format,
+            view_formats: &[],
+            usage: wgpu::TextureUsages::RENDER_ATTACHMENT
+                | wgpu::TextureUsages::COPY_SRC
                 | wgpu::TextureUsages::TEXTURE_BINDING,
-        };
-        let texture = device.create_texture(&desc);
+        });
 
         let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
-        let sampler = device.create_sampler(
-            &wgpu::SamplerDescriptor { // 4.
-                address_mode_u: wgpu::AddressMode::ClampToEdge,
-                address_mode_v: wgpu::AddressMode::ClampToEdge,
-                address_mode_w: wgpu::AddressMode::ClampToEdge,
-                mag_filter: wgpu::FilterMode::Linear,
-                min_filter: wgpu::FilterMode::Linear,
-                mipmap_filter: wgpu::FilterMode::Nearest,
-                lod_min_clamp: 0.0, // 5.
-                lod_max_clamp: 100.0,
-                compare: Some(wgpu::CompareFunction::LessEqual),
-                ..wgpu::SamplerDescriptor::default()
-            }
-        );
+        let sampler = device.create_sampler(&wgpu::SamplerDescriptor::default());
 
         Self { texture, view, sampler }
     }
 
-    pub fn create_bind_group_layout(device: &Device) -> wgpu::BindGroupLayout{
-        device.create_bind_group_layout(
-            &wgpu::BindGroupLayoutDescriptor {
-                entries: &[
-                    // diffuse texture
-                    wgpu::BindGroupLayoutEntry {
-                        binding: 0,
-                        visibility: wgpu::ShaderStages::FRAGMENT,
-                        ty: wgpu::BindingType::Texture {
-                            multisampled: false,
-                            view_dimension: wgpu::TextureViewDimension::D2,
-                            sample_type: wgpu::TextureSampleType::Float { filterable: true },
-                        },
-                        count: None,
-                    },
-                    // diffuse sampler
-                    wgpu::BindGroupLayoutEntry {
-                        binding: 1,
-                        visibility: wgpu::ShaderStages::FRAGMENT,
-                        ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
-                        count: None,
-                    },
-                    // normal map texture
-                    wgpu::BindGroupLayoutEntry {
-                        binding: 2,
-                        visibility: wgpu::ShaderStages::FRAGMENT,
-                        ty: wgpu::BindingType::Texture {
-                            multisampled: false,
-                            sample_type: wgpu::TextureSampleType::Float { filterable: true },
-                            view_dimension: wgpu::TextureViewDimension::D2,
-                        },
-                        count: None,
-                    },
-                    // normal map sampler
-                    wgpu::BindGroupLayoutEntry {
-                        binding: 3,
-                        visibility: wgpu::ShaderStages::FRAGMENT,
-                        ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
-                        count: None,
-                    },
-                ],
-                label: Some("texture_bind_group_layout"),
+    // Copies a 4 byte per pixel color texture back to the CPU. Blocks until the GPU has finished the copy.
+    pub fn read_to_image(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        texture: &wgpu::Texture,
+        format: wgpu::TextureFormat,
+        width: u32,
+        height: u32,
+    ) -> Result<image::RgbaImage> {
+        let unpadded_bytes_per_row = 4 * width;
+        // Rows in a texture to buffer copy must be aligned to COPY_BYTES_PER_ROW_ALIGNMENT
+        let padded_bytes_per_row = padded_bytes_per_row(width);
+
+        let buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Texture Readback Buffer"),
+            size: u64::from(padded_bytes_per_row) * u64::from(height),
+            usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::MAP_READ,
+            mapped_at_creation: false,
+        });
+
+        let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("Texture Readback Encoder"),
+        });
+        encoder.copy_texture_to_buffer(
+            wgpu::ImageCopyTexture {
+                texture,
+                mip_level: 0,
+                origin: wgpu::Origin3d::ZERO,
+                aspect: wgpu::TextureAspect::All,
+            },
+            wgpu::ImageCopyBuffer {
+                buffer: &buffer,
+                layout: wgpu::ImageDataLayout {
+                    offset: 0,
+                    bytes_per_row: Some(padded_bytes_per_row),
+                    rows_per_image: Some(height),
+                },
+            },
+            wgpu::Extent3d {
+                width,
+                height,
+                depth_or_array_layers: 1,
+            },
+        );
+        queue.submit(std::iter::once(encoder.finish()));
+
+        let slice = buffer.slice(..);
+        let (sender, receiver) = std::sync::mpsc::channel();
+        slice.map_async(wgpu::MapMode::Read, move |result| {
+            // The receiver only goes away if we have already given up on the readback
+            let _ = sender.send(result);
+        });
+        device.poll(wgpu::Maintain::Wait);
+        receiver.recv()??;
+
+        let mut pixels = Vec::with_capacity((unpadded_bytes_per_row * height) as usize);
+        {
+            let data = slice.get_mapped_range();
+            for row in data.chunks(padded_bytes_per_row as usize) {
+                pixels.extend_from_slice(&row[..unpadded_bytes_per_row as usize]);
             }
-        )
+        }
+        buffer.unmap();
+
+        // Swapchains often prefer BGRA, so swizzle those back into RGBA
+        if matches!(format, wgpu::TextureFormat::Bgra8Unorm | wgpu::TextureFormat::Bgra8UnormSrgb) {
+            for pixel in pixels.chunks_mut(4) {
+                pixel.swap(0, 2);
+            }
+        }
+
+        image::RgbaImage::from_raw(width, height, pixels)
+            .ok_or_else(|| anyhow!("Readback of {width}x{height} texture returned too few bytes"))
+    }
+}
+
+fn create_comparison_sampler(device: &wgpu::Device) -> wgpu::Sampler {
+    device.create_sampler(
+        &wgpu::SamplerDescriptor { // 4.
+            address_mode_u: wgpu::AddressMode::ClampToEdge,
+            address_mode_v: wgpu::AddressMode::ClampToEdge,
+            address_mode_w: wgpu::AddressMode::ClampToEdge,
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
+            mipmap_filter: wgpu::FilterMode::Nearest,
+            lod_min_clamp: 0.0, // 5.
+            lod_max_clamp: 100.0,
+            compare: Some(wgpu::CompareFunction::LessEqual),
+            ..wgpu::SamplerDescriptor::default()
+        }
+    )
+}
+
+// Direction from the centre of a cube through a point on one of its faces, with across and down running from -1
+// to 1 over the face. The faces are in the order +X, -X, +Y, -Y, +Z, -Z, as the GPU samples them.
+fn cube_direction(face: u32, across: f32, down: f32) -> Vector3<f32> {
+    match face {
+        0 => Vector3::new(1.0, -down, -across),
+        1 => Vector3::new(-1.0, -down, across),
+        2 => Vector3::new(across, 1.0, down),
+        3 => Vector3::new(across, -1.0, -down),
+        4 => Vector3::new(across, -down, 1.0),
+        _ => Vector3::new(-across, -down, -1.0),
+    }
+}
+
+// The column and row of the equirectangular image texel seen in a direction. Longitude runs along the width of the
+// image, latitude down its height starting straight up.
+#[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss, clippy::cast_precision_loss)]
+fn equirectangular_texel(direction: Vector3<f32>, width: u32, height: u32) -> (u32, u32) {
+    let direction = direction.normalize();
+    let longitude = 0.5 + direction.z.atan2(direction.x) / (2.0 * PI);
+    let latitude = direction.y.clamp(-1.0, 1.0).acos() / PI;
+    let column = ((longitude * width as f32) as u32).min(width - 1);
+    let row = ((latitude * height as f32) as u32).min(height - 1);
+    (column, row)
+}
+
+// The bits of the nearest half precision float, rounding the mantissa down. Too small values become zero and too
+// large ones infinity, which is all colors need.
+#[allow(clippy::cast_possible_truncation, clippy::cast_possible_wrap, clippy::cast_sign_loss)]
+const fn f16_bits(value: f32) -> u16 {
+    let bits = value.to_bits();
+    let sign = ((bits >> 16) & 0x8000) as u16;
+    let exponent = ((bits >> 23) & 0xff) as i32 - 127 + 15;
+    let mantissa = ((bits >> 13) & 0x3ff) as u16;
+    if exponent <= 0 {
+        sign
+    } else if exponent >= 31 {
+        sign | 0x7c00
+    } else {
+        sign | ((exponent as u16) << 10) | mantissa
+    }
+}
+
+// Bytes per row of a 4 byte per pixel image, padded up to what wgpu requires for buffer copies
+pub const fn padded_bytes_per_row(width: u32) -> u32 {
+    let align = wgpu::COPY_BYTES_PER_ROW_ALIGNMENT;
+    (4 * width).div_ceil(align) * align
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EDGE_POINTS: [f32; 5] = [-1.0, -0.5, 0.0, 0.5, 1.0];
+
+    fn edges(face: u32) -> impl Iterator<Item = Vector3<f32>> {
+        EDGE_POINTS.into_iter().flat_map(move |t| {
+            [(-1.0, t), (1.0, t), (t, -1.0), (t, 1.0)].map(|(across, down)| cube_direction(face, across, down))
+        })
+    }
+
+    #[test]
+    fn cube_faces_are_centred_on_the_axes() {
+        let axes = [Vector3::unit_x(), -Vector3::unit_x(), Vector3::unit_y(), -Vector3::unit_y(), Vector3::unit_z(), -Vector3::unit_z()];
+        for (face, axis) in (0..6).zip(axes) {
+            assert_eq!(cube_direction(face, 0.0, 0.0), axis);
+        }
+    }
+
+    #[test]
+    fn cube_faces_meet_at_their_edges() {
+        for face in 0..6 {
+            for direction in edges(face) {
+                assert!(
+                    (0..6).filter(|&other| other != face).any(|other| edges(other).any(|edge| edge == direction)),
+                    "{direction:?} on the edge of face {face} is on no other face",
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn equirectangular_rows_run_from_straight_up_to_straight_down() {
+        assert_eq!(equirectangular_texel(Vector3::unit_y(), 64, 32).1, 0);
+        assert_eq!(equirectangular_texel(-Vector3::unit_y(), 64, 32).1, 31);
+        assert_eq!(equirectangular_texel(Vector3::unit_x(), 64, 32).1, 16);
+    }
+
+    #[test]
+    fn equirectangular_columns_run_around_the_horizon() {
+        // The seam of the image lies behind -X
+        assert_eq!(equirectangular_texel(Vector3::new(-1.0, 0.0, -0.01), 64, 32).0, 0);
+        assert_eq!(equirectangular_texel(Vector3::new(-1.0, 0.0, 0.01), 64, 32).0, 63);
+        assert_eq!(equirectangular_texel(-Vector3::unit_z(), 64, 32).0, 16);
+        assert_eq!(equirectangular_texel(Vector3::unit_x(), 64, 32).0, 32);
+        assert_eq!(equirectangular_texel(Vector3::unit_z(), 64, 32).0, 48);
+    }
+
+    #[test]
+    fn half_floats_round_down_and_saturate() {
+        assert_eq!(f16_bits(1.0), 0x3c00);
+        assert_eq!(f16_bits(0.5), 0x3800);
+        assert_eq!(f16_bits(-2.0), 0xc000);
+        assert_eq!(f16_bits(0.0), 0);
+        assert_eq!(f16_bits(65504.0), 0x7bff);
+        assert_eq!(f16_bits(1.0e6), 0x7c00);
+        assert_eq!(f16_bits(1.0e-10), 0);
     }
 }
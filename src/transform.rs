@@ -1,39 +1,103 @@
-use wgpu::util::DeviceExt;
+use cgmath::{EuclideanSpace, InnerSpace, Matrix, One, SquareMatrix, Zero};
+use serde::{Deserialize, Serialize};
 
-use crate::{model, renderer::Renderer};
+use crate::model;
 
-pub struct Transform {
-    position: cgmath::Vector3<f32>,
-    rotation: cgmath::Quaternion<f32>,
-    pub buffer: wgpu::Buffer,
+// Where an entity is placed relative to its parent, or to the world if it has none. Plain data, the renderer
+// keeps the GPU copies of every transform in sync with the World itself, so transforms can be created and
+// changed without a device.
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LocalTransform {
+    pub position: cgmath::Vector3<f32>,
+    pub rotation: cgmath::Quaternion<f32>,
+    // Per axis, applied before the rotation
+    pub scale: cgmath::Vector3<f32>,
 }
 
-impl Transform {
-    pub fn to_raw(&self) -> Raw {
-        compute_raw(self.position, self.rotation)
+impl Default for LocalTransform {
+    fn default() -> Self {
+        Self {
+            position: cgmath::Vector3::zero(),
+            rotation: cgmath::Quaternion::one(),
+            scale: cgmath::Vector3::new(1.0, 1.0, 1.0),
+        }
     }
+}
 
-    pub fn new(position: cgmath::Vector3<f32>, rotation: cgmath::Quaternion<f32>, renderer: &Renderer) ->  Self {
-
-        let raw = compute_raw(position, rotation);
-        let buffer = renderer.device.create_buffer_init(
-            &wgpu::util::BufferInitDescriptor {
-                label: Some("Transform Buffer"),
-                contents: bytemuck::cast_slice(&[raw]),
-                usage: wgpu::BufferUsages::VERTEX | wgpu::BufferUsages::COPY_DST,
-            }
-        );
+impl LocalTransform {
+    pub fn matrix(self) -> cgmath::Matrix4<f32> {
+        cgmath::Matrix4::from_translation(self.position)
+            * cgmath::Matrix4::from(self.rotation)
+            * cgmath::Matrix4::from_nonuniform_scale(self.scale.x, self.scale.y, self.scale.z)
+    }
 
+    pub const fn new(position: cgmath::Vector3<f32>, rotation: cgmath::Quaternion<f32>) -> Self {
         Self {
             position,
             rotation,
-            buffer,
+            scale: cgmath::Vector3::new(1.0, 1.0, 1.0),
         }
     }
 
+    pub const fn with_scale(mut self, scale: cgmath::Vector3<f32>) -> Self {
+        self.scale = scale;
+        self
+    }
+
+    pub const fn set_scale(&mut self, scale: cgmath::Vector3<f32>) {
+        self.scale = scale;
+    }
+
+    pub fn translate_by(&mut self, offset: cgmath::Vector3<f32>) {
+        self.position += offset;
+    }
+
     pub fn rotate_by(&mut self, rotation: cgmath::Quaternion<f32>) {
         self.rotation = rotation * self.rotation;
     }
+
+    // Turns the entity so its local -Z axis points at the target, with its local +Y axis as close to up as possible.
+    // Does nothing if the target is at the entity's position.
+    pub fn look_at(&mut self, target: cgmath::Point3<f32>, up: cgmath::Vector3<f32>) {
+        let forward = target - cgmath::Point3::from_vec(self.position);
+        if forward.magnitude2() <= f32::EPSILON {
+            return;
+        }
+        let forward = forward.normalize();
+        // Any other up vector will do when looking straight along it
+        let up = if forward.cross(up).magnitude2() <= f32::EPSILON {
+            if forward.y.abs() > 0.99 { cgmath::Vector3::unit_z() } else { cgmath::Vector3::unit_y() }
+        } else {
+            up
+        };
+        let right = forward.cross(up).normalize();
+        let up = right.cross(forward);
+        self.rotation = cgmath::Matrix3::from_cols(right, up, -forward).into();
+    }
+}
+
+// Where an entity ends up in the world once the transforms of all its ancestors are applied.
+// Written by hierarchy::propagate_transforms, and what the renderer draws with.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct GlobalTransform(pub cgmath::Matrix4<f32>);
+
+impl Default for GlobalTransform {
+    fn default() -> Self {
+        Self(cgmath::Matrix4::identity())
+    }
+}
+
+impl GlobalTransform {
+    pub fn to_raw(self) -> Raw {
+        let model = self.0;
+        let linear = cgmath::Matrix3::from_cols(model.x.truncate(), model.y.truncate(), model.z.truncate());
+        // A zero scale flattens the mesh and has no inverse, the shader renormalizes whatever it gets then
+        let normal = linear.invert().map_or(linear, |inverse| inverse.transpose());
+        Raw {
+            model: model.into(),
+            normal: normal.into(),
+        }
+    }
 }
 
 #[repr(C)]
@@ -44,15 +108,6 @@ pub struct Raw {
     pub normal: [[f32; 3]; 3],
 }
 
-fn compute_raw(position: cgmath::Vector3<f32>, rotation: cgmath::Quaternion<f32>) -> Raw {
-    let model =
-        cgmath::Matrix4::from_translation(position) * cgmath::Matrix4::from(rotation);
-    Raw {
-        model: model.into(),
-        normal: cgmath::Matrix3::from(rotation).into(),
-    }
-}
-
 impl model::Vertex for Raw {
     fn desc<'a>() -> wgpu::VertexBufferLayout<'a> {
         use std::mem;
@@ -100,4 +155,51 @@ impl model::Vertex for Raw {
             ],
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(a: cgmath::Vector3<f32>, b: cgmath::Vector3<f32>) {
+        assert!((a - b).magnitude() < 1e-5, "{a:?} is not {b:?}");
+    }
+
+    #[test]
+    fn look_at_points_forward_at_the_target() {
+        let mut transform = LocalTransform::new(cgmath::Vector3::new(1.0, 2.0, 3.0), cgmath::Quaternion::one());
+        let target = cgmath::Point3::new(4.0, -1.0, 7.0);
+        transform.look_at(target, cgmath::Vector3::unit_y());
+
+        let forward = transform.rotation * -cgmath::Vector3::unit_z();
+        assert_close(forward, (target - cgmath::Point3::from_vec(transform.position)).normalize());
+        // Up stays in the plane of forward and the world's up, so the entity doesn't roll
+        let right = transform.rotation * cgmath::Vector3::unit_x();
+        assert!(right.y.abs() < 1e-5);
+    }
+
+    #[test]
+    fn look_at_straight_up_still_points_at_the_target() {
+        let mut transform = LocalTransform::default();
+        transform.look_at(cgmath::Point3::new(0.0, 5.0, 0.0), cgmath::Vector3::unit_y());
+        assert_close(transform.rotation * -cgmath::Vector3::unit_z(), cgmath::Vector3::unit_y());
+    }
+
+    #[test]
+    fn normals_stay_perpendicular_under_non_uniform_scale() {
+        let rotation = cgmath::Quaternion::from(cgmath::Euler::new(cgmath::Deg(30.0), cgmath::Deg(-45.0), cgmath::Deg(10.0)));
+        let transform = LocalTransform::new(cgmath::Vector3::new(1.0, 2.0, 3.0), rotation)
+            .with_scale(cgmath::Vector3::new(3.0, 0.5, 1.5));
+        let model = transform.matrix();
+        let raw = GlobalTransform(model).to_raw();
+        let normal_matrix = cgmath::Matrix3::from(raw.normal);
+
+        // A slanted surface, given by two directions along it
+        let along = [cgmath::Vector3::new(1.0, 1.0, 0.0), cgmath::Vector3::new(0.0, 1.0, -2.0)];
+        let normal = normal_matrix * along[0].cross(along[1]);
+        for direction in along {
+            let direction = (model * direction.extend(0.0)).truncate();
+            assert!(normal.normalize().dot(direction.normalize()).abs() < 1e-5);
+        }
+    }
+}